@@ -0,0 +1,22 @@
+//! Records the commit the binaries are built from, for `--version`. Builds
+//! outside of a git checkout, or without git, simply go without it.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    // Packagers can set it themselves
+    if env::var_os("HIDPIPE_GIT_HASH").is_some() {
+        return;
+    }
+    let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+    else {
+        return;
+    };
+    let hash = String::from_utf8_lossy(&output.stdout);
+    if output.status.success() && !hash.trim().is_empty() {
+        println!("cargo:rustc-env=HIDPIPE_GIT_HASH={}", hash.trim());
+    }
+}
@@ -0,0 +1,382 @@
+use hidpipe::control::{self, Command};
+use hidpipe::message::{self, Message as Received};
+use hidpipe::{
+    empty_input_event, struct_from_socket, struct_to_socket, ClientHello, Error, InputEvent,
+    Result, ServerHello, Wire, PROTOCOL_VERSION,
+};
+use input_linux::{AbsoluteAxis, EventKind, Key, UInputHandle};
+use input_linux_sys::{input_absinfo, input_id, uinput_abs_setup, uinput_setup, BUS_USB};
+use libc::c_char;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const BENCH_NAME: &[u8] = b"hidpipe latency benchmark";
+const DEFAULT_ITERATIONS: usize = 1000;
+
+const SOAK_NAME: &[u8] = b"hidpipe soak test";
+const SOAK_DEVICES: usize = 4;
+const SOAK_CLIENTS: usize = 4;
+/// How long the server gets to catch up before its state is checked
+const SOAK_SETTLE: Duration = Duration::from_millis(500);
+
+enum Message {
+    AddDevice(u64, [u8; 80]),
+    UpdateDevice(u64),
+    RemoveDevice(u64),
+    /// A single event or a frame of them
+    InputEvents(Vec<InputEvent>),
+}
+
+fn create_source(device_name: &[u8]) -> UInputHandle<File> {
+    let uinput = UInputHandle::new(
+        File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/uinput")
+            .unwrap(),
+    );
+    uinput.set_evbit(EventKind::Key).unwrap();
+    uinput.set_keybit(Key::ButtonSouth).unwrap();
+    uinput.set_evbit(EventKind::Absolute).unwrap();
+    for axis in [AbsoluteAxis::X, AbsoluteAxis::Y] {
+        uinput.set_absbit(axis).unwrap();
+        uinput
+            .abs_setup(&uinput_abs_setup {
+                code: axis as u16,
+                absinfo: input_absinfo {
+                    value: 0,
+                    minimum: -32768,
+                    maximum: 32767,
+                    fuzz: 0,
+                    flat: 0,
+                    resolution: 0,
+                },
+            })
+            .unwrap();
+    }
+    let mut name = [0 as c_char; 80];
+    for (dst, src) in name.iter_mut().zip(device_name) {
+        *dst = *src as c_char;
+    }
+    uinput
+        .dev_setup(&uinput_setup {
+            id: input_id {
+                bustype: BUS_USB,
+                vendor: 0x1209,
+                product: 0x0001,
+                version: 1,
+            },
+            name,
+            ff_effects_max: 0,
+        })
+        .unwrap();
+    uinput.dev_create().unwrap();
+    uinput
+}
+
+fn read_message(sock: &mut UnixStream) -> Result<Message> {
+    Ok(match message::read_message(sock)? {
+        Received::AddDevice(add_dev, _) => Message::AddDevice(add_dev.id, add_dev.name),
+        Received::UpdateDevice(add_dev, _) => Message::UpdateDevice(add_dev.id),
+        Received::RemoveDevice(id) => Message::RemoveDevice(id),
+        Received::InputEvent(ev) => Message::InputEvents(vec![ev]),
+        Received::InputFrame(_, events) => Message::InputEvents(events),
+        // The benchmark device is not created like a client one, so it has
+        // no tag, and joystick maps and axis limits don't matter here
+        Received::InstanceTag(_) | Received::JoystickMap(_) | Received::UpdateAbsInfo(_) => {
+            return read_message(sock)
+        }
+        m => panic!("Unexpected message {:?}", m),
+    })
+}
+
+fn is_named(name: &[u8; 80], expected: &[u8]) -> bool {
+    name.starts_with(expected) && name[expected.len()] == 0
+}
+
+/// Where the server listens, resolved like the server does without `--socket`.
+fn socket_path() -> String {
+    env::var("HIDPIPE_SOCKET")
+        .unwrap_or_else(|_| format!("{}/hidpipe", env::var("XDG_RUNTIME_DIR").unwrap()))
+}
+
+fn connect(sock_path: &str) -> UnixStream {
+    let mut sock = UnixStream::connect(sock_path).unwrap();
+    struct_to_socket(
+        &mut sock,
+        &ClientHello {
+            version: PROTOCOL_VERSION,
+        }
+        .into_wire(),
+    )
+    .unwrap();
+    // SAFETY: ServerHello is a plain integer.
+    unsafe { struct_from_socket::<ServerHello>(&mut sock) }.unwrap();
+    sock
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+/// Follows what a soak client is told until its connection is closed, and
+/// returns how many soak devices it still believes in. `soak` has that count
+/// as it goes.
+fn follow(mut sock: UnixStream, soak: Arc<AtomicUsize>) -> std::result::Result<usize, String> {
+    let mut live = HashMap::new();
+    loop {
+        match read_message(&mut sock) {
+            Ok(Message::AddDevice(id, name)) => {
+                if live.insert(id, is_named(&name, SOAK_NAME)).is_some() {
+                    return Err(format!("device {} added twice", id));
+                }
+            }
+            Ok(Message::UpdateDevice(id)) if !live.contains_key(&id) => {
+                return Err(format!("unknown device {} updated", id))
+            }
+            Ok(Message::RemoveDevice(id)) => {
+                if live.remove(&id).is_none() {
+                    return Err(format!("unknown device {} removed", id));
+                }
+            }
+            Ok(Message::InputEvents(events)) => {
+                if let Some(ev) = events.iter().find(|ev| !live.contains_key(&ev.id)) {
+                    return Err(format!("event for unknown device {}", ev.id));
+                }
+            }
+            Ok(_) => {}
+            Err(Error::Disconnected) => return Ok(soak.load(Ordering::Relaxed)),
+            Err(e) => return Err(e.to_string()),
+        }
+        soak.store(
+            live.values().filter(|soak| **soak).count(),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+fn control_lines(cmd: &Command) -> Vec<String> {
+    let mut reply = Vec::new();
+    control::send(cmd, &mut reply).unwrap();
+    String::from_utf8_lossy(&reply)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Plugs and unplugs joysticks, connects and disconnects clients and moves the
+/// sticks for `duration`, then checks the server is back where it started.
+fn soak(duration: Duration) {
+    let sock_path = socket_path();
+    let server = getsockopt(&connect(&sock_path), PeerCredentials)
+        .unwrap()
+        .pid();
+    let server_fds = || {
+        fs::read_dir(format!("/proc/{}/fd", server))
+            .unwrap()
+            .count()
+    };
+    let is_soak_line = |line: &String| line.ends_with(std::str::from_utf8(SOAK_NAME).unwrap());
+    thread::sleep(SOAK_SETTLE);
+    let fds = server_fds();
+    let clients = control_lines(&Command::Clients).len();
+    if control_lines(&Command::List).iter().any(is_soak_line) {
+        eprintln!("Soak devices are left over from an earlier run, restart the server");
+        process::exit(1);
+    }
+
+    let mut seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+        | 1;
+    println!("seed {}", seed);
+    let mut random = |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % n as u64) as usize
+    };
+    let mut failures = Vec::new();
+    let mut devices: Vec<UInputHandle<File>> = Vec::new();
+    let mut followers: Vec<(UnixStream, Arc<AtomicUsize>, JoinHandle<_>)> = Vec::new();
+    let (mut plugged, mut connected, mut events) = (0, 0, 0);
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        match random(8) {
+            0 if devices.len() < SOAK_DEVICES => {
+                devices.push(create_source(SOAK_NAME));
+                plugged += 1;
+            }
+            0 | 1 if !devices.is_empty() => {
+                let device = devices.swap_remove(random(devices.len()));
+                device.dev_destroy().unwrap();
+            }
+            2 if followers.len() < SOAK_CLIENTS => {
+                let sock = connect(&sock_path);
+                let follower = sock.try_clone().unwrap();
+                let soak = Arc::new(AtomicUsize::new(0));
+                let seen = soak.clone();
+                followers.push((sock, soak, thread::spawn(move || follow(follower, seen))));
+                connected += 1;
+            }
+            2 | 3 if !followers.is_empty() => {
+                let (sock, _, follower) = followers.swap_remove(random(followers.len()));
+                _ = sock.shutdown(Shutdown::Both);
+                if let Err(e) = follower.join().unwrap() {
+                    failures.push(format!("a client got confused: {}", e));
+                }
+            }
+            _ => {
+                for device in &devices {
+                    let mut abs = empty_input_event();
+                    abs.type_ = EventKind::Absolute as u16;
+                    abs.code = AbsoluteAxis::X as u16;
+                    abs.value = random(65536) as i32 - 32768;
+                    let mut syn = empty_input_event();
+                    syn.type_ = EventKind::Synchronize as u16;
+                    device.write(&[abs, syn]).unwrap();
+                    events += 1;
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // Every client still connected must have every soak device, then see
+    // them all go
+    thread::sleep(SOAK_SETTLE);
+    for (_, soak, _) in &followers {
+        let soak = soak.load(Ordering::Relaxed);
+        if soak != devices.len() {
+            failures.push(format!(
+                "a client has {} of the {} soak devices",
+                soak,
+                devices.len()
+            ));
+        }
+    }
+    for device in devices {
+        device.dev_destroy().unwrap();
+    }
+    thread::sleep(SOAK_SETTLE);
+    for (sock, _, follower) in followers {
+        _ = sock.shutdown(Shutdown::Both);
+        match follower.join().unwrap() {
+            Ok(0) => {}
+            Ok(left) => failures.push(format!("a client still has {} soak devices", left)),
+            Err(e) => failures.push(format!("a client got confused: {}", e)),
+        }
+    }
+    thread::sleep(SOAK_SETTLE);
+    if server_fds() != fds {
+        failures.push(format!("the server had {} fds, now {}", fds, server_fds()));
+    }
+    let stuck = control_lines(&Command::List)
+        .into_iter()
+        .filter(is_soak_line)
+        .count();
+    if stuck != 0 {
+        failures.push(format!("the server still lists {} soak devices", stuck));
+    }
+    if control_lines(&Command::Clients).len() != clients {
+        failures.push("the server still lists disconnected clients".to_string());
+    }
+
+    println!(
+        "{} devices plugged, {} clients connected, {} events",
+        plugged, connected, events
+    );
+    for failure in &failures {
+        println!("FAIL: {}", failure);
+    }
+    if !failures.is_empty() {
+        process::exit(1);
+    }
+    println!("ok");
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    if args.next_if_eq("--soak").is_some() {
+        let seconds = args.next().map(|x| x.parse::<u64>().unwrap()).unwrap_or(60);
+        soak(Duration::from_secs(seconds));
+        return;
+    }
+    let iterations = args
+        .next()
+        .map(|x| x.parse::<usize>().unwrap())
+        .unwrap_or(DEFAULT_ITERATIONS)
+        .max(1);
+    let sock_path = socket_path();
+    let source = create_source(BENCH_NAME);
+    let mut sock = connect(&sock_path);
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let id = loop {
+        match read_message(&mut sock) {
+            Ok(Message::AddDevice(id, name)) if is_named(&name, BENCH_NAME) => break id,
+            Ok(_) => {}
+            Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                eprintln!("The server did not forward the benchmark device, make sure it can open new devices in /dev/input");
+                process::exit(1);
+            }
+            Err(e) => panic!("Lost connection to the server: {}", e),
+        }
+    };
+
+    let mut samples = Vec::with_capacity(iterations);
+    for i in 0..iterations {
+        let value = (i % 32767) as i32 + 1;
+        let mut abs = empty_input_event();
+        abs.type_ = EventKind::Absolute as u16;
+        abs.code = AbsoluteAxis::X as u16;
+        abs.value = value;
+        let mut syn = empty_input_event();
+        syn.type_ = EventKind::Synchronize as u16;
+        let start = Instant::now();
+        source.write(&[abs, syn]).unwrap();
+        loop {
+            match read_message(&mut sock).unwrap() {
+                Message::InputEvents(events)
+                    if events.iter().any(|ev| {
+                        ev.id == id
+                            && ev.ty == abs.type_
+                            && ev.code == abs.code
+                            && ev.value == value
+                    }) =>
+                {
+                    break
+                }
+                Message::RemoveDevice(removed) if removed == id => {
+                    panic!("The benchmark device was removed by the server")
+                }
+                _ => {}
+            }
+        }
+        samples.push(start.elapsed());
+        // Give the server time to go back to sleep, we want to measure wakeups, not throughput
+        thread::sleep(Duration::from_millis(1));
+    }
+    source.dev_destroy().unwrap();
+
+    samples.sort();
+    let total: Duration = samples.iter().sum();
+    println!("{} samples", samples.len());
+    println!("min:  {:?}", samples[0]);
+    println!("mean: {:?}", total / samples.len() as u32);
+    println!("p50:  {:?}", percentile(&samples, 0.5));
+    println!("p90:  {:?}", percentile(&samples, 0.9));
+    println!("p99:  {:?}", percentile(&samples, 0.99));
+    println!("max:  {:?}", samples[samples.len() - 1]);
+}
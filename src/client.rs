@@ -1,126 +1,389 @@
+use hidpipe::device::{self, Overrides, Spoof};
+use hidpipe::message::{self, write_message, Message};
 use hidpipe::{
-    empty_input_event, struct_to_socket, AddDevice, ClientHello, FFErase, FFUpload, InputEvent,
-    MessageType, RemoveDevice, ServerHello,
+    check_len, decode_snapshot, empty_input_event, instance_tag_phys, message_header, parse_header,
+    protocol_major, struct_as_bytes, AddDevice, ClientHello, Error, FFErase, FFUpload, InputEvent,
+    JoystickMap, MessageType, Padded, Ping, Result, ServerHello, Subscribe, Transport,
+    UpdateAbsInfo, Wire, HEADER_SIZE, HELLO_INSTANCE_TAG, HELLO_PING, HELLO_VERSION_MASK,
+    MAX_SUBSCRIPTIONS, PING_INTERVAL, PING_TIMEOUT, PROTOCOL_VERSION, SUBSCRIBE_VERSION,
 };
-use input_linux::bitmask::BitmaskTrait;
+use hidpipe::{debug, error, info, joydev, signal, trace, warn};
 use input_linux::{
-    AbsoluteAxis, AbsoluteInfo, Bitmask, EventKind, ForceFeedbackKind, InputProperty, Key, LedKind,
-    MiscKind, RelativeAxis, SoundKind, SwitchKind, UInputHandle, UInputKind,
+    AbsoluteAxis, AbsoluteInfo, EvdevHandle, EventKind, InputId, UInputHandle, UInputKind,
 };
 use input_linux_sys::{
-    ff_effect, ff_replay, ff_trigger, input_absinfo, input_id, uinput_abs_setup, uinput_ff_erase,
-    uinput_ff_upload, uinput_setup,
+    ff_effect, ff_replay, ff_trigger, uinput_ff_erase, uinput_ff_upload, BUS_BLUETOOTH, BUS_USB,
 };
-use libc::{c_char, O_NONBLOCK};
 use nix::errno::Errno;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
-use nix::sys::socket::{connect, socket, AddressFamily, SockFlag, SockType, VsockAddr};
+use nix::sys::socket::{
+    connect, recv, socket, AddressFamily, MsgFlags, SockFlag, SockType, VsockAddr,
+};
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
 use std::os::fd::AsRawFd;
-use std::os::unix::fs::{chown, OpenOptionsExt};
+use std::os::unix::fs::chown;
 use std::os::unix::net::UnixStream;
-use std::{mem, slice};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{mem, process, slice};
+/// Where the server is reached by default, the host on the port libkrun uses.
+const DEFAULT_CID: u32 = libc::VMADDR_CID_HOST;
+const DEFAULT_PORT: u32 = 3334;
+
+const CONNECT_RETRY_MIN: Duration = Duration::from_millis(100);
+const CONNECT_RETRY_MAX: Duration = Duration::from_secs(5);
+
+/// Exit code used when the server can't be reached, as opposed to 101 for panics.
+const EXIT_DISCONNECTED: i32 = 2;
+/// Exit code used when the server speaks an incompatible protocol version.
+const EXIT_VERSION: i32 = 3;
+/// Exit code used when devices can't be created at all.
+const EXIT_UINPUT: i32 = 4;
+
+struct Options {
+    user_id: u32,
+    cid: u32,
+    port: u32,
+    /// Connects to this socket instead of through vsock
+    unix: Option<String>,
+    /// Connects to this TCP address instead of through vsock
+    tcp: Option<String>,
+    bustype: Option<u16>,
+    status_file: Option<String>,
+    connect_timeout: Option<Duration>,
+    gamepad_only: bool,
+    spoofs: Vec<Spoof>,
+    /// The models to create devices for, all of them when empty
+    subscription: Subscribe,
+    /// Not an option, sent by the server once connected
+    instance_tag: Option<u64>,
+}
+
+const USAGE: &str = "\
+Usage: hidpipe-client [--bustype=usb|bluetooth] [--status-file=<path>]
+                      [--cid=<cid>] [--port=<port> | --unix=<path>
+                      | --tcp=<address>:<port>]
+                      [--connect-timeout=<seconds>] [--guest-hint=gamepad]
+                      [--spoof=<vendor>:<product>=<vendor>:<product>[:<version>]]...
+                      [--subscribe=<vendor>:<product>]...
+                      <uid>
+       hidpipe-client --help | --version";
 
-const ADD_DEVICE: u32 = MessageType::AddDevice as u32;
-const REMOVE_DEVICE: u32 = MessageType::RemoveDevice as u32;
-const INPUT_EVENT: u32 = MessageType::InputEvent as u32;
-const FF_UPLOAD: u32 = MessageType::FFUpload as u32;
-const FF_ERASE: u32 = MessageType::FFErase as u32;
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
 
-fn bitmask_from_slice<T, A>(s: &T::Array) -> Bitmask<T>
-where
-    A: AsRef<[u8]>,
-    T: BitmaskTrait<Array = A>,
-{
-    let mut bm = Bitmask::<T>::default();
-    bm.copy_from_slice(s.as_ref());
-    bm
+fn parse_number(flag: &str, value: &str) -> u32 {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("{} expects a number, not {:?}", flag, value);
+        usage()
+    })
 }
 
-fn init_uinput(sock: &mut UnixStream, user_id: u32) -> (u64, UInputHandle<File>) {
-    let mut add_dev_data = [0u8; mem::size_of::<AddDevice>()];
-    sock.read_exact(&mut add_dev_data).unwrap();
-    let add_dev = unsafe {
-        (add_dev_data.as_ptr() as *const AddDevice)
-            .as_ref()
-            .unwrap()
+fn parse_args() -> Options {
+    let mut user_id = None;
+    let mut cid = DEFAULT_CID;
+    let mut port = DEFAULT_PORT;
+    let mut unix = None;
+    let mut tcp = None;
+    let mut bustype = None;
+    let mut status_file = None;
+    let mut connect_timeout = None;
+    let mut gamepad_only = false;
+    let mut spoofs = Vec::new();
+    let mut subscription = Subscribe {
+        count: 0,
+        ids: [[0; 2]; MAX_SUBSCRIPTIONS],
     };
-    let uinput = UInputHandle::new(
-        File::options()
-            .read(true)
-            .write(true)
-            .custom_flags(O_NONBLOCK)
-            .open("/dev/uinput")
-            .unwrap(),
-    );
-    for evbit in bitmask_from_slice::<EventKind, _>(&add_dev.evbits).iter() {
-        uinput.set_evbit(evbit).unwrap();
+    for arg in env::args().skip(1) {
+        hidpipe::common_flag(&arg, "hidpipe-client", USAGE);
+        if let Some(bus) = arg.strip_prefix("--bustype=") {
+            bustype = Some(match bus {
+                "usb" => BUS_USB,
+                "bluetooth" => BUS_BLUETOOTH,
+                _ => usage(),
+            });
+        } else if let Some(value) = arg.strip_prefix("--cid=") {
+            cid = parse_number("--cid", value);
+        } else if let Some(value) = arg.strip_prefix("--port=") {
+            port = parse_number("--port", value);
+        } else if let Some(path) = arg.strip_prefix("--unix=") {
+            unix = Some(path.to_string());
+        } else if let Some(addr) = arg.strip_prefix("--tcp=") {
+            tcp = Some(addr.to_string());
+        } else if let Some(path) = arg.strip_prefix("--status-file=") {
+            status_file = Some(path.to_string());
+        } else if let Some(secs) = arg.strip_prefix("--connect-timeout=") {
+            let secs = secs.parse::<u64>().unwrap_or_else(|_| usage());
+            connect_timeout = Some(Duration::from_secs(secs));
+        } else if let Some(hint) = arg.strip_prefix("--guest-hint=") {
+            match hint {
+                "gamepad" => gamepad_only = true,
+                _ => usage(),
+            }
+        } else if let Some(spoof) = arg.strip_prefix("--spoof=") {
+            spoofs.push(Spoof::parse(spoof).unwrap_or_else(|| usage()));
+        } else if let Some(model) = arg.strip_prefix("--subscribe=") {
+            let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+            let (vendor, product) = model.split_once(':').unwrap_or_else(|| usage());
+            let (Some(vendor), Some(product)) = (hex(vendor), hex(product)) else {
+                usage();
+            };
+            let Some(id) = subscription.ids.get_mut(subscription.count as usize) else {
+                eprintln!("--subscribe takes up to {} models", MAX_SUBSCRIPTIONS);
+                usage();
+            };
+            *id = [vendor, product];
+            subscription.count += 1;
+        } else if user_id.is_none() {
+            user_id = Some(arg.parse::<u32>().unwrap_or_else(|_| usage()));
+        } else {
+            usage();
+        }
     }
-    for keybit in bitmask_from_slice::<Key, _>(&add_dev.keybits).iter() {
-        uinput.set_keybit(keybit).unwrap();
+    Options {
+        user_id: user_id.unwrap_or_else(|| usage()),
+        cid,
+        port,
+        unix,
+        tcp,
+        bustype,
+        status_file,
+        connect_timeout,
+        gamepad_only,
+        spoofs,
+        subscription,
+        instance_tag: None,
     }
-    for relbit in bitmask_from_slice::<RelativeAxis, _>(&add_dev.relbits).iter() {
-        uinput.set_relbit(relbit).unwrap();
+}
+
+/// Creates the device of a record. A device that can't be created or isn't
+/// subscribed to is left out, its events are then ignored like those of any
+/// unknown device, the others keep working.
+fn init_uinput(
+    add_dev: &AddDevice,
+    abs: &[AbsoluteInfo],
+    options: &Options,
+) -> Option<UInputHandle<File>> {
+    // Servers that don't take subscriptions send every device
+    if !options.subscription.matches(&add_dev.input_id) {
+        debug!(
+            "Leaving out device {} ({:04x}:{:04x}), it isn't subscribed to",
+            add_dev.id, add_dev.input_id.vendor, add_dev.input_id.product
+        );
+        return None;
     }
-    for absbit in bitmask_from_slice::<AbsoluteAxis, _>(&add_dev.absbits).iter() {
-        uinput.set_absbit(absbit).unwrap();
-        let mut absinfo_data = [0u8; mem::size_of::<AbsoluteInfo>()];
-        sock.read_exact(&mut absinfo_data).unwrap();
-        let abs_info = unsafe {
-            (absinfo_data.as_ptr() as *const AbsoluteInfo)
-                .as_ref()
-                .unwrap()
-        };
-        uinput
-            .abs_setup(&uinput_abs_setup {
-                code: absbit as u16,
-                absinfo: input_absinfo {
-                    value: abs_info.value,
-                    minimum: abs_info.minimum,
-                    maximum: abs_info.maximum,
-                    fuzz: abs_info.fuzz,
-                    flat: abs_info.flat,
-                    resolution: abs_info.resolution,
-                },
-            })
-            .unwrap();
+    let overrides = Overrides {
+        bustype: options.bustype,
+        phys: options.instance_tag.map(instance_tag_phys),
+        gamepad_only: options.gamepad_only,
+        spoofs: &options.spoofs,
+    };
+    let uinput = match device::create_uinput(add_dev, abs, &overrides) {
+        Ok(uinput) => uinput,
+        Err(e) => {
+            warn!(
+                "Failed to create device {} ({}), skipping it, error: {:?}",
+                add_dev.id,
+                String::from_utf8_lossy(add_dev.name.split(|c| *c == 0).next().unwrap()),
+                e
+            );
+            return None;
+        }
+    };
+    let id = overrides.input_id(add_dev.input_id);
+    if (id.vendor, id.product) != (add_dev.input_id.vendor, add_dev.input_id.product) {
+        info!(
+            "Device {} ({:04x}:{:04x}) is passed off as {:04x}:{:04x}",
+            add_dev.id, add_dev.input_id.vendor, add_dev.input_id.product, id.vendor, id.product
+        );
+    }
+    match uinput.evdev_path() {
+        Ok(evdev_path) => {
+            if let Err(e) = check_created(add_dev, id, &evdev_path, options) {
+                warn!(
+                    "Failed to check how device {} was created, error: {:?}",
+                    add_dev.id, e
+                );
+            }
+            // The device works without it, only not for the user
+            if let Err(e) = chown(&evdev_path, Some(options.user_id), Some(0)) {
+                warn!(
+                    "Failed to give {} to user {}, error: {:?}",
+                    evdev_path.display(),
+                    options.user_id,
+                    e
+                );
+            }
+            match joystick_path(&uinput) {
+                Some(js_path) => debug!(
+                    "Device {} is {} and {}",
+                    add_dev.id,
+                    evdev_path.display(),
+                    js_path.display()
+                ),
+                None => debug!("Device {} is {}", add_dev.id, evdev_path.display()),
+            }
+        }
+        Err(e) => warn!(
+            "Failed to find the event node of device {}, error: {:?}",
+            add_dev.id, e
+        ),
     }
-    for mscbit in bitmask_from_slice::<MiscKind, _>(&add_dev.mscbits).iter() {
-        uinput.set_mscbit(mscbit).unwrap();
+    // uinput has no way to give a device a unique id, so it can only be reported
+    let uniq = add_dev.uniq.split(|c| *c == 0).next().unwrap();
+    if !uniq.is_empty() {
+        debug!(
+            "Device {} has the unique id {} on the host",
+            add_dev.id,
+            String::from_utf8_lossy(uniq)
+        );
     }
-    for ledbit in bitmask_from_slice::<LedKind, _>(&add_dev.ledbits).iter() {
-        uinput.set_ledbit(ledbit).unwrap();
+    Some(uinput)
+}
+
+/// Checks that the device came out the way the host has it, uinput quietly
+/// drops what it doesn't support.
+fn check_created(
+    add_dev: &AddDevice,
+    id: InputId,
+    evdev_path: &Path,
+    options: &Options,
+) -> io::Result<()> {
+    // Games match controllers by bus, vendor and product, make sure they survived the trip
+    let created = EvdevHandle::new(File::open(evdev_path)?);
+    let created_id = created.device_id()?;
+    let ids = |id: InputId| (id.bustype, id.vendor, id.product, id.version);
+    if ids(created_id) != ids(id) {
+        warn!(
+            "Device {} was created as {:04x}:{:04x} version {:#x} on bus {:#x} \
+             instead of {:04x}:{:04x} version {:#x} on bus {:#x}",
+            add_dev.id,
+            created_id.vendor,
+            created_id.product,
+            created_id.version,
+            created_id.bustype,
+            id.vendor,
+            id.product,
+            id.version,
+            id.bustype
+        );
     }
-    for sndbit in bitmask_from_slice::<SoundKind, _>(&add_dev.sndbits).iter() {
-        uinput.set_sndbit(sndbit).unwrap();
+    // Properties decide how userspace treats the device, a missing one turns a
+    // touchpad into a touchscreen or a controller into a tablet
+    let created_props = created.device_properties()?;
+    if &created_props[..] != add_dev.propbits.as_ref() {
+        let host: Vec<_> = device::set_bits(add_dev.propbits.as_ref()).collect();
+        let guest: Vec<_> = device::set_bits(&created_props[..]).collect();
+        warn!(
+            "Device {} was created with properties {:?} instead of {:?}",
+            add_dev.id, guest, host
+        );
     }
-    for swbit in bitmask_from_slice::<SwitchKind, _>(&add_dev.swbits).iter() {
-        uinput.set_swbit(swbit).unwrap();
+    if options.gamepad_only
+        && (created.event_bits()?.get(EventKind::Relative)
+            || created.key_mask()?.iter().any(device::is_desktop_key))
+    {
+        warn!(
+            "Device {} still looks like a keyboard or a mouse to the guest",
+            add_dev.id
+        );
     }
-    for propbit in bitmask_from_slice::<InputProperty, _>(&add_dev.propbits).iter() {
-        uinput.set_propbit(propbit).unwrap();
+    Ok(())
+}
+
+/// The joydev node of a device, if the kernel considers it a joystick.
+fn joystick_path(uinput: &UInputHandle<File>) -> Option<PathBuf> {
+    joydev::find_node(&uinput.sys_path().ok()?)
+}
+
+/// A uinput device can't be set up again once created, its event node can.
+fn apply_abs_info(uinput: &UInputHandle<File>, update: &UpdateAbsInfo) -> io::Result<()> {
+    let Ok(axis) = AbsoluteAxis::from_code(update.axis as u16) else {
+        return Ok(());
+    };
+    let evdev = EvdevHandle::new(
+        File::options()
+            .read(true)
+            .write(true)
+            .open(uinput.evdev_path()?)?,
+    );
+    evdev.set_absolute_info(axis, &update.info)
+}
+
+fn apply_joystick_map(uinput: &UInputHandle<File>, map: &JoystickMap) -> io::Result<()> {
+    let Some(path) = joystick_path(uinput) else {
+        warn!(
+            "Device {} has a joystick mapping but no joystick node",
+            map.id
+        );
+        return Ok(());
+    };
+    let js = File::options().read(true).write(true).open(&path)?;
+    let applied = joydev::write_map(&js, map)?;
+    if applied.axes != map.axes
+        || applied.buttons != map.buttons
+        || applied.axmap != map.axmap
+        || applied.btnmap != map.btnmap
+    {
+        warn!(
+            "{} does not match the joystick node of device {} on the host ({} axes and {} buttons instead of {} and {})",
+            path.display(),
+            map.id,
+            applied.axes,
+            applied.buttons,
+            map.axes,
+            map.buttons
+        );
     }
-    for ffbit in bitmask_from_slice::<ForceFeedbackKind, _>(&add_dev.ffbits).iter() {
-        uinput.set_ffbit(ffbit).unwrap();
+    Ok(())
+}
+
+/// Lists the nodes of every device, one `<id> <event node> <joystick node or ->`
+/// line each, for scripts that want to make stable links to them.
+fn write_status(path: &str, inputs_by_id: &HashMap<u64, UInputHandle<File>>) -> io::Result<()> {
+    let mut status = String::new();
+    let mut ids: Vec<_> = inputs_by_id.keys().collect();
+    ids.sort();
+    for id in ids {
+        let uinput = &inputs_by_id[id];
+        let js_path = joystick_path(uinput);
+        status.push_str(&format!(
+            "{} {} {}\n",
+            id,
+            uinput.evdev_path()?.display(),
+            js_path.as_deref().unwrap_or(Path::new("-")).display()
+        ));
+    }
+    // Readers never see a half written file
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, status)?;
+    fs::rename(tmp_path, path)
+}
+
+/// Logs every device the client created, asked for with SIGUSR2.
+fn log_devices(inputs_by_id: &HashMap<u64, UInputHandle<File>>) {
+    let mut ids: Vec<_> = inputs_by_id.keys().collect();
+    ids.sort();
+    info!("Created {} devices", ids.len());
+    for id in ids {
+        let uinput = &inputs_by_id[id];
+        let path = match uinput.evdev_path() {
+            Ok(path) => path.display().to_string(),
+            Err(e) => format!("unknown node ({})", e),
+        };
+        info!(
+            "Device {}: fd {}, {}",
+            id,
+            uinput.as_inner().as_raw_fd(),
+            path
+        );
     }
-    uinput
-        .dev_setup(&uinput_setup {
-            id: input_id {
-                bustype: add_dev.input_id.bustype,
-                vendor: add_dev.input_id.vendor,
-                product: add_dev.input_id.product,
-                version: add_dev.input_id.version,
-            },
-            name: add_dev.name.map(|c| c as c_char),
-            ff_effects_max: add_dev.ff_effects,
-        })
-        .unwrap();
-    uinput.dev_create().unwrap();
-    chown(uinput.evdev_path().unwrap(), Some(user_id), Some(0)).unwrap();
-    (add_dev.id, uinput)
 }
 
 fn ff_effect_empty() -> ff_effect {
@@ -140,27 +403,429 @@ fn ff_effect_empty() -> ff_effect {
     }
 }
 
-fn main() {
-    let user_id = env::args().nth(1).unwrap().parse::<u32>().unwrap();
+/// Removes the devices of a connection that was lost, the server sends them
+/// all again when we reconnect.
+fn teardown(options: &Options, inputs_by_id: &HashMap<u64, UInputHandle<File>>) {
+    for uinput in inputs_by_id.values() {
+        if let Err(e) = uinput.dev_destroy() {
+            warn!("Failed to destroy a uinput device, error: {:?}", e);
+        }
+    }
+    if let Some(path) = &options.status_file {
+        if let Err(e) = write_status(path, &HashMap::new()) {
+            warn!("Failed to write the status file, error: {:?}", e);
+        }
+    }
+}
+
+fn register_uinput(
+    epoll: &Epoll,
+    inputs_by_id: &mut HashMap<u64, UInputHandle<File>>,
+    fd_to_id: &mut HashMap<u64, u64>,
+    id: u64,
+    uinput: UInputHandle<File>,
+) {
+    let raw = uinput.as_inner().as_raw_fd() as u64;
+    epoll
+        .add(uinput.as_inner(), EpollEvent::new(EpollFlags::EPOLLIN, raw))
+        .unwrap();
+    inputs_by_id.insert(id, uinput);
+    fd_to_id.insert(raw, id);
+}
+
+fn unregister_uinput(
+    epoll: &Epoll,
+    inputs_by_id: &mut HashMap<u64, UInputHandle<File>>,
+    fd_to_id: &mut HashMap<u64, u64>,
+    id: u64,
+) {
+    if let Some(uinput) = inputs_by_id.remove(&id) {
+        debug!("Device {} was removed", id);
+        let raw = uinput.as_inner().as_raw_fd() as u64;
+        fd_to_id.remove(&raw);
+        epoll.delete(uinput.as_inner()).unwrap();
+        if let Err(e) = uinput.dev_destroy() {
+            warn!("Failed to destroy device {}, error: {:?}", id, e);
+        }
+    }
+}
+
+/// What the server sent so far. Messages are only handled once they arrived
+/// whole, so the loop never waits in the middle of one while the devices
+/// have feedback to send.
+#[derive(Default)]
+struct Inbox {
+    buf: Vec<u8>,
+    /// Pings to answer
+    pings: Vec<u64>,
+    /// Whether the server answered our ping
+    ponged: bool,
+}
+
+impl Inbox {
+    /// Reads whatever the socket has, without waiting.
+    fn fill(&mut self, sock: &dyn Transport) -> Result<()> {
+        let mut chunk = [0u8; 16 << 10];
+        loop {
+            match recv(sock.as_raw_fd(), &mut chunk, MsgFlags::MSG_DONTWAIT) {
+                Ok(0) => return Err(Error::Disconnected),
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(Errno::EAGAIN) => return Ok(()),
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(io::Error::from(e).into()),
+            }
+        }
+    }
+    /// Takes out the first message if it is complete.
+    fn next(&mut self) -> Result<Option<Message>> {
+        let Some(header) = self.buf.get(..HEADER_SIZE) else {
+            return Ok(None);
+        };
+        let (msg, len) = parse_header(header.try_into().unwrap());
+        message::check_header(msg, len)?;
+        let Some(payload) = self.buf.get(HEADER_SIZE..HEADER_SIZE + len) else {
+            return Ok(None);
+        };
+        trace!("Server sent message {} of {} bytes", msg, len);
+        let message = Message::decode(msg, payload)?;
+        self.buf.drain(..HEADER_SIZE + len);
+        Ok(Some(message))
+    }
+}
+
+fn handle_server_messages(
+    inbox: &mut Inbox,
+    options: &mut Options,
+    epoll: &Epoll,
+    inputs_by_id: &mut HashMap<u64, UInputHandle<File>>,
+    fd_to_id: &mut HashMap<u64, u64>,
+    ff_uploads: &mut HashMap<u32, uinput_ff_upload>,
+    ff_erases: &mut HashMap<u32, uinput_ff_erase>,
+) -> Result<()> {
+    while let Some(message) = inbox.next()? {
+        let devices_changed = matches!(
+            message,
+            Message::AddDevice(..)
+                | Message::DeviceSnapshot(..)
+                | Message::RemoveDevice(_)
+                | Message::UpdateDevice(..)
+        );
+        match message {
+            Message::Ping(ping) => inbox.pings.push(ping.seq),
+            Message::Pong(_) => inbox.ponged = true,
+            Message::AddDevice(add_dev, abs) => {
+                if let Some(uinput) = init_uinput(&add_dev, &abs, options) {
+                    register_uinput(epoll, inputs_by_id, fd_to_id, add_dev.id, uinput);
+                }
+            }
+            Message::DeviceSnapshot(count, data) => {
+                let records = decode_snapshot(&data)
+                    .filter(|records| records.len() == count as usize)
+                    .ok_or_else(|| Error::Protocol("corrupt device snapshot".to_string()))?;
+                for record in records {
+                    let ty = MessageType::AddDevice as u32;
+                    check_len(ty, record.len(), message::payload_len(ty, &record))?;
+                    let (add_dev, abs) = device::read_record(&mut &record[..])?;
+                    if let Some(uinput) = init_uinput(&add_dev, &abs, options) {
+                        register_uinput(epoll, inputs_by_id, fd_to_id, add_dev.id, uinput);
+                    }
+                }
+            }
+            Message::InstanceTag(instance_tag) => options.instance_tag = Some(instance_tag.tag),
+            Message::RemoveDevice(id) => unregister_uinput(epoll, inputs_by_id, fd_to_id, id),
+            Message::JoystickMap(map) => {
+                if let Some(uinput) = inputs_by_id.get(&map.id) {
+                    if let Err(e) = apply_joystick_map(uinput, &map) {
+                        warn!(
+                            "Failed to apply the joystick mapping of device {}, error: {:?}",
+                            map.id, e
+                        );
+                    }
+                }
+            }
+            Message::UpdateAbsInfo(update) => {
+                if let Some(uinput) = inputs_by_id.get(&update.id) {
+                    if let Err(e) = apply_abs_info(uinput, &update) {
+                        warn!(
+                            "Failed to update axis {} of device {}, error: {:?}",
+                            update.axis, update.id, e
+                        );
+                    }
+                }
+            }
+            Message::UpdateDevice(add_dev, abs) => {
+                // A uinput device can't be changed once created, replace it
+                let uinput = init_uinput(&add_dev, &abs, options);
+                unregister_uinput(epoll, inputs_by_id, fd_to_id, add_dev.id);
+                if let Some(uinput) = uinput {
+                    register_uinput(epoll, inputs_by_id, fd_to_id, add_dev.id, uinput);
+                }
+            }
+            Message::InputEvent(event) => {
+                if let Some(dev) = inputs_by_id.get(&event.id) {
+                    if let Err(e) = dev.write(&[event.to_input_event()]) {
+                        warn!("Failed to write to device {}, error: {:?}", event.id, e);
+                    }
+                }
+            }
+            Message::InputFrame(id, events) => {
+                if let Some(dev) = inputs_by_id.get(&id) {
+                    let events: Vec<_> = events.iter().map(InputEvent::to_input_event).collect();
+                    if let Err(e) = dev.write(&events) {
+                        warn!("Failed to write to device {}, error: {:?}", id, e);
+                    }
+                }
+            }
+            Message::FFUpload(upload) => {
+                let Some(dev) = inputs_by_id.get(&upload.id) else {
+                    continue;
+                };
+                if let Some(mut ff_up) = ff_uploads.remove(&upload.request_id) {
+                    ff_up.retval = upload.retval;
+                    ff_up.effect = upload.effect;
+                    if let Err(e) = dev.ff_upload_end(&ff_up) {
+                        warn!(
+                            "Failed to end an upload to device {}, error: {:?}",
+                            upload.id, e
+                        );
+                    }
+                }
+            }
+            Message::FFErase(erase) => {
+                let Some(dev) = inputs_by_id.get(&erase.id) else {
+                    continue;
+                };
+                if let Some(ff_ers) = ff_erases.remove(&erase.request_id) {
+                    if let Err(e) = dev.ff_erase_end(&ff_ers) {
+                        warn!(
+                            "Failed to end an erase on device {}, error: {:?}",
+                            erase.id, e
+                        );
+                    }
+                }
+            }
+            Message::Subscribe(_) => debug!("Skipping a subscription, only clients send them"),
+            Message::Unknown(ty, payload) => {
+                debug!("Skipping unknown message {} of {} bytes", ty, payload.len());
+            }
+        }
+        if let Some(path) = &options.status_file {
+            if devices_changed {
+                if let Err(e) = write_status(path, inputs_by_id) {
+                    warn!("Failed to write the status file, error: {:?}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many events are read from a uinput device at once.
+const UINPUT_BATCH: usize = 64;
+/// Events handled per `epoll_wait`, the socket and the devices can all be ready at once.
+const EPOLL_BATCH: usize = 32;
+
+/// Appends a message to `out`, to send a whole batch with one write.
+fn push_message(out: &mut Vec<u8>, ty: MessageType, payload: &[u8]) {
+    out.extend_from_slice(&message_header(ty, payload.len()));
+    out.extend_from_slice(payload);
+}
+
+fn handle_uinput_events(
+    sock: &mut dyn Transport,
+    id: u64,
+    uinput: &UInputHandle<File>,
+    ff_uploads: &mut HashMap<u32, uinput_ff_upload>,
+    ff_erases: &mut HashMap<u32, uinput_ff_erase>,
+) -> Result<()> {
+    let mut evts = [empty_input_event(); UINPUT_BATCH];
+    let mut out = Vec::new();
+    loop {
+        let count = match uinput.read(&mut evts) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Failed to read from the uinput device {}: {}", id, e);
+                break;
+            }
+        };
+        for ev in &evts[..count] {
+            if ev.type_ != EventKind::UInput as u16 {
+                let event = InputEvent::new(id, *ev).into_wire();
+                push_message(&mut out, MessageType::InputEvent, struct_as_bytes(&event));
+            } else if ev.code == UInputKind::ForceFeedbackUpload as u16 {
+                let mut upload = uinput_ff_upload {
+                    request_id: ev.value as u32,
+                    retval: 0,
+                    effect: ff_effect_empty(),
+                    old: ff_effect_empty(),
+                };
+                uinput.ff_upload_begin(&mut upload).unwrap();
+                push_message(
+                    &mut out,
+                    MessageType::FFUpload,
+                    &FFUpload {
+                        id,
+                        request_id: upload.request_id,
+                        retval: 0,
+                        effect: upload.effect,
+                    }
+                    .into_wire()
+                    .to_bytes(),
+                );
+                ff_uploads.insert(upload.request_id, upload);
+            } else if ev.code == UInputKind::ForceFeedbackErase as u16 {
+                let mut erase = uinput_ff_erase {
+                    request_id: ev.value as u32,
+                    retval: 0,
+                    effect_id: 0,
+                };
+                uinput.ff_erase_begin(&mut erase).unwrap();
+                push_message(
+                    &mut out,
+                    MessageType::FFErase,
+                    struct_as_bytes(
+                        &FFErase {
+                            id,
+                            request_id: erase.request_id,
+                            effect_id: erase.effect_id,
+                        }
+                        .into_wire(),
+                    ),
+                );
+                ff_erases.insert(erase.request_id, erase);
+            } else {
+                debug!("Ignoring unknown uinput event: {:?}", ev);
+            }
+        }
+        if count < UINPUT_BATCH {
+            break;
+        }
+    }
+    sock.write_all(&out)?;
+    Ok(())
+}
+
+fn connect_vsock(addr: &VsockAddr) -> io::Result<UnixStream> {
     let sock_fd = socket(
         AddressFamily::Vsock,
         SockType::Stream,
         SockFlag::empty(),
         None,
-    )
-    .unwrap();
-    connect(sock_fd.as_raw_fd(), &VsockAddr::new(2, 3334)).unwrap();
-    let mut sock = UnixStream::from(sock_fd);
-    let c_hello = ClientHello { version: 0 };
+    )?;
+    connect(sock_fd.as_raw_fd(), addr)?;
+    Ok(UnixStream::from(sock_fd))
+}
+
+/// The server is often not up yet when the VM boots, keep trying until it is.
+fn connect_to_server(options: &Options, signals: &File) -> Box<dyn Transport> {
+    let addr = VsockAddr::new(options.cid, options.port);
+    let start = Instant::now();
+    let mut delay = CONNECT_RETRY_MIN;
+    loop {
+        let res = match (&options.unix, &options.tcp) {
+            (Some(path), _) => UnixStream::connect(path).map(|sock| Box::new(sock) as _),
+            (_, Some(addr)) => TcpStream::connect(addr).and_then(|sock| {
+                sock.set_nodelay(true)?;
+                Ok(Box::new(sock) as _)
+            }),
+            (None, None) => connect_vsock(&addr).map(|sock| Box::new(sock) as _),
+        };
+        let err = match res {
+            Ok(sock) => return sock,
+            Err(e) => e,
+        };
+        if options
+            .connect_timeout
+            .is_some_and(|timeout| start.elapsed() + delay > timeout)
+        {
+            error!("Unable to connect to the server, giving up, error: {}", err);
+            process::exit(EXIT_DISCONNECTED);
+        }
+        warn!(
+            "Unable to connect to the server, retrying in {:?}, error: {}",
+            delay, err
+        );
+        sleep_or_stop(signals, delay);
+        delay = (delay * 2).min(CONNECT_RETRY_MAX);
+    }
+}
+
+/// Exits if SIGTERM or SIGINT came since the last call. There are no devices
+/// to log for SIGUSR2 while not connected.
+fn stop_if_asked(signals: &File) {
+    for sig in signal::received(signals) {
+        if sig == libc::SIGUSR2 {
+            info!("Not connected, no devices");
+        } else {
+            info!("Stopping on signal {}", sig);
+            process::exit(0);
+        }
+    }
+}
+
+/// Sleeps for `delay`, unless asked to stop in the meantime.
+fn sleep_or_stop(signals: &File, delay: Duration) {
+    let deadline = Instant::now() + delay;
+    // SIGUSR2 wakes us up too, keep sleeping after it
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        let mut pollfd = libc::pollfd {
+            fd: signals.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: pollfd is a single valid entry
+        unsafe { libc::poll(&mut pollfd, 1, left.as_millis() as i32) };
+        stop_if_asked(signals);
+    }
+}
+
+/// Talks to the server until the connection is lost, and returns why.
+fn serve(options: &mut Options, signals: &File) -> Error {
+    let mut sock = connect_to_server(options, signals);
+    // A restarted server picks a new tag
+    options.instance_tag = None;
+    let c_hello = ClientHello {
+        version: HELLO_INSTANCE_TAG | HELLO_PING | PROTOCOL_VERSION,
+    }
+    .into_wire();
     let c_hello_data = unsafe {
         slice::from_raw_parts(
             &c_hello as *const ClientHello as *const u8,
             mem::size_of::<ClientHello>(),
         )
     };
-    sock.write_all(c_hello_data).unwrap();
     let mut s_hello_data = [0u8; mem::size_of::<ServerHello>()];
-    sock.read_exact(&mut s_hello_data).unwrap();
+    if let Err(e) = sock
+        .write_all(c_hello_data)
+        .and_then(|_| sock.read_exact(&mut s_hello_data))
+    {
+        return e.into();
+    }
+    let s_hello = u32::from_le_bytes(s_hello_data);
+    let theirs = s_hello & HELLO_VERSION_MASK;
+    if protocol_major(theirs) != protocol_major(PROTOCOL_VERSION) {
+        return Error::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs,
+        };
+    }
+    // Only sent when it leaves devices out, older servers don't take it
+    if options.subscription.count != 0 && theirs >= SUBSCRIBE_VERSION {
+        if let Err(e) = write_message(&mut *sock, &Message::Subscribe(options.subscription)) {
+            return e;
+        }
+    }
+    let pings = s_hello & HELLO_PING != 0;
+    let mut last_ping = Instant::now();
+    let mut ping_sent = None;
+    let mut ping_seq = 0;
     let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
     epoll
         .add(
@@ -168,147 +833,137 @@ fn main() {
             EpollEvent::new(EpollFlags::EPOLLIN, sock.as_raw_fd() as u64),
         )
         .unwrap();
+    epoll
+        .add(
+            signals,
+            EpollEvent::new(EpollFlags::EPOLLIN, signals.as_raw_fd() as u64),
+        )
+        .unwrap();
     let mut inputs_by_id = HashMap::new();
     let mut fd_to_id = HashMap::new();
     let mut ff_uploads = HashMap::<u32, uinput_ff_upload>::new();
     let mut ff_erases = HashMap::<u32, uinput_ff_erase>::new();
-    loop {
-        let mut evts = [EpollEvent::empty()];
-        match epoll.wait(&mut evts, EpollTimeout::NONE) {
-            Err(Errno::EINTR) | Ok(0) => {
-                continue;
-            }
-            Ok(_) => {}
-            e => {
-                e.unwrap();
-            }
-        }
-        let fd = evts[0].data();
-        if fd == sock.as_raw_fd() as u64 {
-            let mut cmd_data = [0u8; mem::size_of::<MessageType>()];
-            sock.read_exact(&mut cmd_data).unwrap();
-            match u32::from_ne_bytes(cmd_data) {
-                ADD_DEVICE => {
-                    let (id, uinput) = init_uinput(&mut sock, user_id);
-                    let raw = uinput.as_inner().as_raw_fd() as u64;
-                    epoll
-                        .add(uinput.as_inner(), EpollEvent::new(EpollFlags::EPOLLIN, raw))
-                        .unwrap();
-                    inputs_by_id.insert(id, uinput);
-                    fd_to_id.insert(raw, id);
+    let mut inbox = Inbox::default();
+    let err = 'serve: loop {
+        // Without pings a dead server goes unnoticed until we have something to send
+        let mut timeout = EpollTimeout::NONE;
+        if pings {
+            if last_ping.elapsed() >= PING_INTERVAL {
+                if ping_sent.is_some_and(|sent: Instant| sent.elapsed() >= PING_TIMEOUT) {
+                    break Error::Unresponsive;
                 }
-                REMOVE_DEVICE => {
-                    let mut remove_dev_data = [0u8; mem::size_of::<RemoveDevice>()];
-                    sock.read_exact(&mut remove_dev_data).unwrap();
-                    let remove_dev = unsafe {
-                        (remove_dev_data.as_ptr() as *const RemoveDevice)
-                            .as_ref()
-                            .unwrap()
-                    };
-                    if let Some(uinput) = inputs_by_id.remove(&remove_dev.id) {
-                        let raw = uinput.as_inner().as_raw_fd() as u64;
-                        fd_to_id.remove(&raw);
-                        epoll.delete(uinput.as_inner()).unwrap();
-                        uinput.dev_destroy().unwrap();
-                    }
-                }
-                INPUT_EVENT => {
-                    let mut event_data = [0u8; mem::size_of::<InputEvent>()];
-                    sock.read_exact(&mut event_data).unwrap();
-                    let event =
-                        unsafe { (event_data.as_ptr() as *const InputEvent).as_ref().unwrap() };
-                    let dev = inputs_by_id.get(&event.id);
-                    if dev.is_none() {
-                        continue;
+                if ping_sent.is_none() {
+                    ping_seq += 1;
+                    let ping = Message::Ping(Ping { seq: ping_seq });
+                    if let Err(e) = write_message(&mut *sock, &ping) {
+                        break e;
                     }
-                    dev.unwrap().write(&[event.to_input_event()]).unwrap();
+                    ping_sent = Some(Instant::now());
                 }
-                FF_UPLOAD => {
-                    let mut upload_data = [0u8; mem::size_of::<FFUpload>()];
-                    sock.read_exact(&mut upload_data).unwrap();
-                    let upload =
-                        unsafe { (upload_data.as_ptr() as *const FFUpload).as_ref().unwrap() };
-                    let dev = inputs_by_id.get(&upload.id);
-                    if dev.is_none() {
+                last_ping = Instant::now();
+            }
+            let left = PING_INTERVAL.saturating_sub(last_ping.elapsed());
+            timeout = EpollTimeout::try_from(left).unwrap();
+        }
+        let mut evts = [EpollEvent::empty(); EPOLL_BATCH];
+        let count = match epoll.wait(&mut evts, timeout) {
+            Err(Errno::EINTR) | Ok(0) => {
+                continue;
+            }
+            res => res.unwrap(),
+        };
+        // A device removed by a message can still have events in the batch,
+        // fd_to_id no longer knows its fd then
+        for evt in &evts[..count] {
+            let fd = evt.data();
+            if fd == signals.as_raw_fd() as u64 {
+                for sig in signal::received(signals) {
+                    if sig == libc::SIGUSR2 {
+                        log_devices(&inputs_by_id);
                         continue;
                     }
-                    if let Some(mut ff_up) = ff_uploads.remove(&upload.request_id) {
-                        ff_up.effect = upload.effect;
-                        dev.unwrap().ff_upload_end(&ff_up).unwrap();
-                    }
+                    info!("Stopping on signal {}", sig);
+                    // Don't leave ghost controllers behind for the games to see
+                    teardown(options, &inputs_by_id);
+                    process::exit(0);
                 }
-                FF_ERASE => {
-                    let mut erase_resp_data = [0u8; mem::size_of::<FFErase>()];
-                    sock.read_exact(&mut erase_resp_data).unwrap();
-                    let erase = unsafe {
-                        (erase_resp_data.as_ptr() as *const FFErase)
-                            .as_ref()
-                            .unwrap()
-                    };
-                    let dev = inputs_by_id.get(&erase.id);
-                    if dev.is_none() {
-                        continue;
+                continue;
+            }
+            let res = if fd == sock.as_raw_fd() as u64 {
+                inbox.fill(&*sock).and_then(|()| {
+                    handle_server_messages(
+                        &mut inbox,
+                        options,
+                        &epoll,
+                        &mut inputs_by_id,
+                        &mut fd_to_id,
+                        &mut ff_uploads,
+                        &mut ff_erases,
+                    )?;
+                    if mem::take(&mut inbox.ponged) {
+                        ping_sent = None;
                     }
-                    if let Some(ff_ers) = ff_erases.remove(&erase.request_id) {
-                        dev.unwrap().ff_erase_end(&ff_ers).unwrap();
+                    for seq in inbox.pings.drain(..) {
+                        write_message(&mut *sock, &Message::Pong(Ping { seq }))?;
                     }
-                }
-                m => panic!("Unknown message {}", m),
+                    Ok(())
+                })
+            } else if let Some(id) = fd_to_id.get(&fd) {
+                let uinput = inputs_by_id.get(id).unwrap();
+                handle_uinput_events(&mut *sock, *id, uinput, &mut ff_uploads, &mut ff_erases)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = res {
+                break 'serve e;
             }
-        } else if let Some(id) = fd_to_id.get(&fd) {
-            let uinput = inputs_by_id.get(id).unwrap();
-            let mut evts = [empty_input_event()];
-            while let Ok(count) = uinput.read(&mut evts) {
-                if count == 0 {
-                    break;
-                }
-                if evts[0].type_ == EventKind::UInput as u16 {
-                    if evts[0].code == UInputKind::ForceFeedbackUpload as u16 {
-                        let mut upload = uinput_ff_upload {
-                            request_id: evts[0].value as u32,
-                            retval: 0,
-                            effect: ff_effect_empty(),
-                            old: ff_effect_empty(),
-                        };
-                        uinput.ff_upload_begin(&mut upload).unwrap();
-                        struct_to_socket(&mut sock, &MessageType::FFUpload).unwrap();
-                        struct_to_socket(
-                            &mut sock,
-                            &FFUpload {
-                                id: *id,
-                                request_id: upload.request_id,
-                                effect: upload.effect,
-                            },
-                        )
-                        .unwrap();
-                        ff_uploads.insert(upload.request_id, upload);
-                    } else if evts[0].code == UInputKind::ForceFeedbackErase as u16 {
-                        let mut erase = uinput_ff_erase {
-                            request_id: evts[0].value as u32,
-                            retval: 0,
-                            effect_id: 0,
-                        };
-                        uinput.ff_erase_begin(&mut erase).unwrap();
-                        struct_to_socket(&mut sock, &MessageType::FFErase).unwrap();
-                        struct_to_socket(
-                            &mut sock,
-                            &FFErase {
-                                id: *id,
-                                request_id: erase.request_id,
-                                effect_id: erase.effect_id,
-                            },
-                        )
-                        .unwrap();
-                        ff_erases.insert(erase.request_id, erase);
-                    } else {
-                        eprintln!("Ignoring unknown uinput event: {:?}", evts[0]);
-                    }
-                } else {
-                    let ev = InputEvent::new(*id, evts[0]);
-                    struct_to_socket(&mut sock, &MessageType::InputEvent).unwrap();
-                    struct_to_socket(&mut sock, &ev).unwrap();
-                }
+        }
+    };
+    teardown(options, &inputs_by_id);
+    err
+}
+
+/// Makes sure devices can be created before connecting, every one of them
+/// would fail otherwise.
+fn check_uinput() {
+    if let Err(e) = File::options().read(true).write(true).open("/dev/uinput") {
+        error!("Unable to open /dev/uinput, error: {}", e);
+        match e.kind() {
+            io::ErrorKind::NotFound => {
+                error!("Load the uinput module (modprobe uinput) or build it into the kernel")
+            }
+            io::ErrorKind::PermissionDenied => {
+                error!("Run the client as root, or give it write access to /dev/uinput")
             }
+            _ => {}
         }
+        process::exit(EXIT_UINPUT);
+    }
+}
+
+fn main() {
+    hidpipe::log::init();
+    let mut options = parse_args();
+    check_uinput();
+    let signals = signal::pipe(&[libc::SIGUSR2, libc::SIGTERM, libc::SIGINT]).unwrap();
+    let mut delay = CONNECT_RETRY_MIN;
+    loop {
+        let start = Instant::now();
+        match serve(&mut options, &signals) {
+            Error::Disconnected => info!("Server closed the connection"),
+            err @ Error::VersionMismatch { .. } => {
+                error!("Unable to talk to the server, {}", err);
+                process::exit(EXIT_VERSION);
+            }
+            err => warn!("Lost connection to the server, error: {}", err),
+        }
+        // Don't hammer a server that keeps dropping us right away
+        delay = if start.elapsed() > CONNECT_RETRY_MAX {
+            CONNECT_RETRY_MIN
+        } else {
+            (delay * 2).min(CONNECT_RETRY_MAX)
+        };
+        info!("Reconnecting in {:?}", delay);
+        sleep_or_stop(&signals, delay);
     }
 }
@@ -0,0 +1,80 @@
+//! The control socket lets a second `hidpipe-server` invocation (or any other
+//! local tool) change the behaviour of the running server. Commands are single
+//! lines of text, the server answers with a reply and closes the connection.
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+pub enum Command {
+    List,
+    Clients,
+    Enable(u64),
+    Disable(u64),
+    /// Keeps the connection open and streams the state of a device
+    Watch(u64),
+    /// Hands the force feedback of a device to a client
+    Rumble(u64, u64),
+    /// Sends the actual state of a device to the clients, to unstick buttons
+    Reset(u64),
+    /// Starts forwarding the devices matching an event node or `vendor:product`,
+    /// even the ones the server would leave alone
+    Forward(String),
+    /// Closes a device and tells the clients it is gone
+    Unforward(u64),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Command> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        Some(match words[..] {
+            ["list"] => Command::List,
+            ["clients"] => Command::Clients,
+            ["enable", id] => Command::Enable(id.parse().ok()?),
+            ["disable", id] => Command::Disable(id.parse().ok()?),
+            ["watch", id] => Command::Watch(id.parse().ok()?),
+            ["reset", id] => Command::Reset(id.parse().ok()?),
+            ["forward", device] => Command::Forward(device.to_string()),
+            ["unforward", id] => Command::Unforward(id.parse().ok()?),
+            ["rumble", id, client] => Command::Rumble(id.parse().ok()?, client.parse().ok()?),
+            _ => return None,
+        })
+    }
+    fn to_line(&self) -> String {
+        match self {
+            Command::List => "list\n".to_string(),
+            Command::Clients => "clients\n".to_string(),
+            Command::Enable(id) => format!("enable {}\n", id),
+            Command::Disable(id) => format!("disable {}\n", id),
+            Command::Watch(id) => format!("watch {}\n", id),
+            Command::Rumble(id, client) => format!("rumble {} {}\n", id, client),
+            Command::Reset(id) => format!("reset {}\n", id),
+            Command::Forward(device) => format!("forward {}\n", device),
+            Command::Unforward(id) => format!("unforward {}\n", id),
+        }
+    }
+}
+
+pub fn socket_path() -> Result<String> {
+    let xdg_dir = env::var("XDG_RUNTIME_DIR").map_err(|e| Error::new(ErrorKind::NotFound, e))?;
+    Ok(format!("{}/hidpipe-control", xdg_dir))
+}
+
+/// Sends a command to the running server and copies its reply to `out`
+/// until the server closes the connection.
+pub fn send<W: Write>(cmd: &Command, out: &mut W) -> Result<()> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    stream.write_all(cmd.to_line().as_bytes())?;
+    io::copy(&mut stream, out)?;
+    Ok(())
+}
+
+/// Reads the command from a freshly accepted control connection.
+pub fn read_command(stream: &UnixStream) -> Result<Option<Command>> {
+    // Don't let a stuck control client stall the event loop
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(Command::parse(&line))
+}
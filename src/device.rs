@@ -0,0 +1,847 @@
+//! A device record is an `AddDevice` followed by the `AbsoluteInfo` of each of
+//! its axes, one for every bit set in `absbits`, by increasing code. The server
+//! builds them from the devices it forwards, the client (and `hidpipe-replay`)
+//! turns them back into uinput devices.
+
+use crate::{struct_as_bytes, warn, AddDevice, Padded, Result, Wire};
+use input_linux::bitmask::BitmaskTrait;
+use input_linux::{
+    AbsoluteAxis, AbsoluteInfo, Bitmask, EvdevHandle, EventKind, ForceFeedbackKind, InputId,
+    InputProperty, Key, LedKind, MiscKind, RelativeAxis, SoundKind, SwitchKind, UInputHandle,
+};
+use input_linux_sys::{input_absinfo, input_id, uinput_abs_setup, uinput_setup};
+use libc::{c_char, O_NONBLOCK};
+use nix::ioctl_write_int;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::{mem, ptr};
+
+fn bitmask_from_slice<T, A>(s: &T::Array) -> Bitmask<T>
+where
+    A: AsRef<[u8]>,
+    T: BitmaskTrait<Array = A>,
+{
+    let mut bm = Bitmask::<T>::default();
+    bm.copy_from_slice(s.as_ref());
+    bm
+}
+
+ioctl_write_int!(ui_set_propbit, b'U', 110);
+
+/// The capabilities the heuristics deciding what to forward look at, so they
+/// can also be run on a device record instead of a real device.
+pub trait Capabilities {
+    fn device_properties(&self) -> io::Result<Bitmask<InputProperty>>;
+    fn event_bits(&self) -> io::Result<Bitmask<EventKind>>;
+    fn absolute_mask(&self) -> io::Result<Bitmask<AbsoluteAxis>>;
+    fn key_mask(&self) -> io::Result<Bitmask<Key>>;
+    fn relative_mask(&self) -> io::Result<Bitmask<RelativeAxis>>;
+    fn device_id(&self) -> io::Result<InputId>;
+    /// The phys of the device, without the trailing nul.
+    fn physical_location(&self) -> io::Result<Vec<u8>>;
+}
+
+impl<F: AsRawFd> Capabilities for EvdevHandle<F> {
+    fn device_properties(&self) -> io::Result<Bitmask<InputProperty>> {
+        EvdevHandle::device_properties(self)
+    }
+    fn event_bits(&self) -> io::Result<Bitmask<EventKind>> {
+        EvdevHandle::event_bits(self)
+    }
+    fn absolute_mask(&self) -> io::Result<Bitmask<AbsoluteAxis>> {
+        EvdevHandle::absolute_mask(self)
+    }
+    fn key_mask(&self) -> io::Result<Bitmask<Key>> {
+        EvdevHandle::key_mask(self)
+    }
+    fn relative_mask(&self) -> io::Result<Bitmask<RelativeAxis>> {
+        EvdevHandle::relative_mask(self)
+    }
+    fn device_id(&self) -> io::Result<InputId> {
+        EvdevHandle::device_id(self)
+    }
+    fn physical_location(&self) -> io::Result<Vec<u8>> {
+        let mut phys = EvdevHandle::physical_location(self)?;
+        if let Some(nul) = phys.iter().position(|c| *c == 0) {
+            phys.truncate(nul);
+        }
+        Ok(phys)
+    }
+}
+
+impl Capabilities for AddDevice {
+    fn device_properties(&self) -> io::Result<Bitmask<InputProperty>> {
+        Ok(bitmask_from_slice(&self.propbits))
+    }
+    fn event_bits(&self) -> io::Result<Bitmask<EventKind>> {
+        Ok(bitmask_from_slice(&self.evbits))
+    }
+    fn absolute_mask(&self) -> io::Result<Bitmask<AbsoluteAxis>> {
+        Ok(bitmask_from_slice(&self.absbits))
+    }
+    fn key_mask(&self) -> io::Result<Bitmask<Key>> {
+        Ok(bitmask_from_slice(&self.keybits))
+    }
+    fn relative_mask(&self) -> io::Result<Bitmask<RelativeAxis>> {
+        Ok(bitmask_from_slice(&self.relbits))
+    }
+    fn device_id(&self) -> io::Result<InputId> {
+        Ok(self.input_id)
+    }
+    fn physical_location(&self) -> io::Result<Vec<u8>> {
+        Ok(self.phys.split(|c| *c == 0).next().unwrap().to_vec())
+    }
+}
+
+/// The kinds of devices the server can be told to forward, in the order they
+/// are tried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceClass {
+    Gamepad,
+    Mouse,
+    Keyboard,
+}
+
+impl DeviceClass {
+    pub fn parse(name: &str) -> Option<DeviceClass> {
+        match name {
+            "gamepad" => Some(DeviceClass::Gamepad),
+            "mouse" => Some(DeviceClass::Mouse),
+            "keyboard" => Some(DeviceClass::Keyboard),
+            _ => None,
+        }
+    }
+    /// Whether a device belongs to this class.
+    pub fn matches<D: Capabilities>(self, evdev: &D) -> io::Result<bool> {
+        match self {
+            DeviceClass::Gamepad => is_joystick(evdev),
+            DeviceClass::Mouse => is_mouse(evdev),
+            DeviceClass::Keyboard => is_keyboard(evdev),
+        }
+    }
+}
+
+/// Whether a device looks like a gamepad, joystick or wheel.
+pub fn is_joystick<D: Capabilities>(evdev: &D) -> io::Result<bool> {
+    let props = evdev.device_properties()?;
+    let no = Ok(false);
+    if props.get(InputProperty::Accelerometer)
+        || props.get(InputProperty::PointingStick)
+        || props.get(InputProperty::TopButtonPad)
+        || props.get(InputProperty::ButtonPad)
+        || props.get(InputProperty::SemiMultiTouch)
+    {
+        return no;
+    }
+    let keys = evdev.key_mask()?;
+    // Digital pads have no stick, and report their d-pad as buttons too
+    let gamepad_buttons = keys.get(Key::ButtonSouth)
+        || (keys.get(Key::ButtonDpadUp) && keys.get(Key::ButtonDpadDown));
+    let events = evdev.event_bits()?;
+    let axes = evdev.absolute_mask()?;
+    if !events.get(EventKind::Absolute) || !axes.get(AbsoluteAxis::X) || !axes.get(AbsoluteAxis::Y)
+    {
+        return Ok(gamepad_buttons);
+    }
+    Ok(keys.get(Key::ButtonTrigger)
+        || keys.get(Key::ButtonSouth)
+        || keys.get(Key::Button1)
+        || axes.get(AbsoluteAxis::RX)
+        || axes.get(AbsoluteAxis::RY)
+        || axes.get(AbsoluteAxis::Throttle)
+        || axes.get(AbsoluteAxis::Rudder)
+        || axes.get(AbsoluteAxis::Wheel)
+        || axes.get(AbsoluteAxis::Gas)
+        || axes.get(AbsoluteAxis::Brake))
+}
+
+/// Whether a device moves a pointer and can click.
+pub fn is_mouse<D: Capabilities>(evdev: &D) -> io::Result<bool> {
+    let axes = evdev.relative_mask()?;
+    Ok(evdev.event_bits()?.get(EventKind::Relative)
+        && axes.get(RelativeAxis::X)
+        && axes.get(RelativeAxis::Y)
+        && evdev.key_mask()?.get(Key::ButtonLeft))
+}
+
+/// How many keyboard keys a device needs to count as a keyboard, the power
+/// button and media keys of many devices show up as a few keys too.
+const KEYBOARD_MIN_KEYS: usize = 20;
+
+/// Whether a device is a keyboard, numeric keypads included.
+pub fn is_keyboard<D: Capabilities>(evdev: &D) -> io::Result<bool> {
+    let keys = evdev
+        .key_mask()?
+        .iter()
+        .filter(|key| (*key as u16) < Key::Button0 as u16)
+        .count();
+    Ok(keys >= KEYBOARD_MIN_KEYS)
+}
+
+/// Whether a device is a touchpad that can be clicked, as found on controllers
+/// (and laptops, the caller has to tell them apart).
+pub fn is_touchpad<D: Capabilities>(evdev: &D) -> io::Result<bool> {
+    Ok(evdev.device_properties()?.get(InputProperty::ButtonPad)
+        && evdev
+            .absolute_mask()?
+            .get(AbsoluteAxis::MultitouchPositionX))
+}
+
+/// Whether a device is the motion sensor of a controller.
+pub fn is_motion_sensor<D: Capabilities>(evdev: &D) -> io::Result<bool> {
+    Ok(evdev.device_properties()?.get(InputProperty::Accelerometer)
+        && evdev.event_bits()?.get(EventKind::Absolute))
+}
+
+/// Whether a device is a racing wheel with a centering spring that can be
+/// turned off through force feedback.
+pub fn has_autocenter<F: AsRawFd>(evdev: &EvdevHandle<F>) -> io::Result<bool> {
+    Ok(evdev.absolute_mask()?.get(AbsoluteAxis::Wheel)
+        && evdev
+            .force_feedback_bits()?
+            .get(ForceFeedbackKind::Autocenter))
+}
+
+/// Iterates over the numbers of the bits set in a raw bitmask.
+pub fn set_bits(bits: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    (0..bits.len() * 8).filter(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+/// How much of a string is read from a device, strings that long are cut short
+/// anyway.
+const MAX_STRING: usize = 256;
+
+/// Copies the nul terminated string `src` into `dst`, cutting it short on a
+/// character boundary if it doesn't fit, and fills the rest of `dst` with nuls.
+/// Returns whether it was cut short.
+pub fn copy_string(dst: &mut [u8], src: &[u8]) -> bool {
+    let src = src.split(|c| *c == 0).next().unwrap();
+    let mut len = src.len().min(dst.len() - 1);
+    // Don't leave the start of a multibyte sequence without its continuation bytes
+    while len > 0 && len < src.len() && src[len] & 0xc0 == 0x80 {
+        len -= 1;
+    }
+    dst[..len].copy_from_slice(&src[..len]);
+    dst[len..].fill(0);
+    len < src.len()
+}
+
+/// Makes a string received from the peer valid UTF-8 and nul terminated.
+pub fn sanitize_string(buf: &mut [u8]) {
+    let string = buf.split(|c| *c == 0).next().unwrap();
+    let string = String::from_utf8_lossy(string).into_owned();
+    copy_string(buf, string.as_bytes());
+}
+
+/// Reads a string a device may not have into `buf`, leaving it empty then, as
+/// the kernel reports a missing string with ENOENT.
+fn optional_string(
+    buf: &mut [u8],
+    read: impl FnOnce(&mut [u8]) -> io::Result<usize>,
+) -> io::Result<()> {
+    let mut string = [0; MAX_STRING];
+    match read(&mut string) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+        res => _ = res?,
+    }
+    copy_string(buf, &string);
+    Ok(())
+}
+
+/// Builds the record of a device, to be announced under `id`.
+pub fn record<F: AsRawFd>(evdev: &EvdevHandle<F>, id: u64) -> io::Result<Vec<u8>> {
+    // Axes input-linux doesn't know can't be queried, leave them out of the
+    // bitmask too so that it matches the AbsoluteInfos that follow
+    let mut abs = Bitmask::<AbsoluteAxis>::default();
+    for axis in evdev.absolute_bits()?.iter() {
+        abs.insert(axis);
+    }
+    let evbits = *evdev.event_bits()?.data();
+    let keybits = *evdev.key_bits()?.data();
+    let relbits = *evdev.relative_bits()?.data();
+    let absbits = *abs.data();
+    let mscbits = *evdev.misc_bits()?.data();
+    let ledbits = *evdev.led_bits()?.data();
+    let sndbits = *evdev.sound_bits()?.data();
+    let swbits = *evdev.switch_bits()?.data();
+    let propbits = *evdev.device_properties()?.data();
+    let ffbits = *evdev.force_feedback_bits()?.data();
+    let input_id = evdev.device_id()?;
+    let ff_effects = evdev.effects_count()? as u32;
+    let mut name_buf = [0; MAX_STRING];
+    evdev.device_name_buf(&mut name_buf)?;
+    let mut name = [0; 80];
+    if copy_string(&mut name, &name_buf) {
+        warn!(
+            "The name of device {} is longer than {} bytes, it is cut short to {}",
+            id,
+            name.len() - 1,
+            String::from_utf8_lossy(name.split(|c| *c == 0).next().unwrap())
+        );
+    }
+    let mut uniq = [0; 64];
+    optional_string(&mut uniq, |buf| evdev.unique_id_buf(buf))?;
+    let mut phys = [0; 64];
+    optional_string(&mut phys, |buf| evdev.physical_location_buf(buf))?;
+    let add_dev = AddDevice {
+        evbits,
+        keybits,
+        relbits,
+        absbits,
+        mscbits,
+        ledbits,
+        id,
+        sndbits,
+        swbits,
+        propbits,
+        input_id,
+        name,
+        uniq,
+        phys,
+        ff_effects,
+        ffbits,
+    };
+    record_from(add_dev, |axis| evdev.absolute_info(axis))
+}
+
+/// Builds a record from its `AddDevice`, asking `absolute_info` for the info of
+/// each axis set in `absbits`.
+fn record_from(
+    mut add_dev: AddDevice,
+    absolute_info: impl Fn(AbsoluteAxis) -> io::Result<AbsoluteInfo>,
+) -> io::Result<Vec<u8>> {
+    // MSC_TIMESTAMP stays, it counts microseconds from an arbitrary point of
+    // the device's choosing and only the difference between two of them means
+    // anything, which survives the trip as is
+    let mut mscbits = bitmask_from_slice::<MiscKind, _>(&add_dev.mscbits);
+    mscbits.remove(MiscKind::Scancode);
+    add_dev.mscbits = *mscbits.data();
+    let abs = bitmask_from_slice::<AbsoluteAxis, _>(&add_dev.absbits);
+    let mut record = add_dev.into_wire().to_bytes();
+    for bit in abs.iter() {
+        let info = absolute_info(bit)?;
+        record.extend_from_slice(struct_as_bytes(&info.into_wire()));
+    }
+    Ok(record)
+}
+
+/// Makes devices of a model show up as another one, for games that only accept
+/// the controllers they know.
+pub struct Spoof {
+    pub vendor: u16,
+    pub product: u16,
+    pub as_vendor: u16,
+    pub as_product: u16,
+    pub as_version: Option<u16>,
+}
+
+impl Spoof {
+    /// Parses `vendor:product=vendor:product[:version]`, in hex.
+    pub fn parse(s: &str) -> Option<Spoof> {
+        let hex = |s: &str| u16::from_str_radix(s, 16).ok();
+        let (from, to) = s.split_once('=')?;
+        let (vendor, product) = from.split_once(':')?;
+        let mut to = to.split(':');
+        let spoof = Spoof {
+            vendor: hex(vendor)?,
+            product: hex(product)?,
+            as_vendor: hex(to.next()?)?,
+            as_product: hex(to.next()?)?,
+            as_version: match to.next() {
+                Some(version) => Some(hex(version)?),
+                None => None,
+            },
+        };
+        to.next().is_none().then_some(spoof)
+    }
+}
+
+/// What the created device should do differently from the record.
+#[derive(Default)]
+pub struct Overrides<'a> {
+    pub bustype: Option<u16>,
+    /// Put in front of the phys of the device on the host
+    pub phys: Option<String>,
+    /// Leave out the keyboard keys, mouse buttons and relative axes, so
+    /// desktops don't take the device for a keyboard or a mouse
+    pub gamepad_only: bool,
+    pub spoofs: &'a [Spoof],
+}
+
+impl Overrides<'_> {
+    /// The id the device created for a record with the given id gets.
+    pub fn input_id(&self, mut id: InputId) -> InputId {
+        if let Some(bustype) = self.bustype {
+            id.bustype = bustype;
+        }
+        let spoof = self
+            .spoofs
+            .iter()
+            .find(|spoof| spoof.vendor == id.vendor && spoof.product == id.product);
+        if let Some(spoof) = spoof {
+            id.vendor = spoof.as_vendor;
+            id.product = spoof.as_product;
+            id.version = spoof.as_version.unwrap_or(id.version);
+        }
+        id
+    }
+}
+
+/// Keys that make desktops treat a device as a keyboard or a mouse.
+pub fn is_desktop_key(key: Key) -> bool {
+    let code = key as u16;
+    code < Key::Button0 as u16
+        || (Key::ButtonLeft as u16..Key::ButtonTrigger as u16).contains(&code)
+}
+
+/// Reads a record, the `AddDevice` and the `AbsoluteInfo` of each of its axes.
+pub fn read_record<R: Read>(record: &mut R) -> Result<(AddDevice, Vec<AbsoluteInfo>)> {
+    let mut add_dev_data = [0u8; mem::size_of::<AddDevice>()];
+    record.read_exact(&mut add_dev_data)?;
+    // SAFETY: AddDevice is made of integers and arrays of them
+    let mut add_dev =
+        unsafe { ptr::read_unaligned(add_dev_data.as_ptr() as *const AddDevice) }.into_native();
+    sanitize_string(&mut add_dev.name);
+    sanitize_string(&mut add_dev.uniq);
+    sanitize_string(&mut add_dev.phys);
+    let mut abs = Vec::new();
+    for _ in set_bits(add_dev.absbits.as_ref()) {
+        let mut absinfo_data = [0u8; mem::size_of::<AbsoluteInfo>()];
+        record.read_exact(&mut absinfo_data)?;
+        // SAFETY: AbsoluteInfo is made of integers
+        let info = unsafe { ptr::read_unaligned(absinfo_data.as_ptr() as *const AbsoluteInfo) };
+        abs.push(info.into_native());
+    }
+    Ok((add_dev, abs))
+}
+
+/// Creates the uinput device matching a record read with [`read_record`].
+pub fn create_uinput(
+    add_dev: &AddDevice,
+    abs: &[AbsoluteInfo],
+    overrides: &Overrides,
+) -> io::Result<UInputHandle<File>> {
+    let uinput = UInputHandle::new(
+        File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open("/dev/uinput")?,
+    );
+    let mut evbits = bitmask_from_slice::<EventKind, _>(&add_dev.evbits);
+    if overrides.gamepad_only {
+        evbits.remove(EventKind::Relative);
+    }
+    for evbit in evbits.iter() {
+        uinput.set_evbit(evbit)?;
+    }
+    for keybit in bitmask_from_slice::<Key, _>(&add_dev.keybits).iter() {
+        if !overrides.gamepad_only || !is_desktop_key(keybit) {
+            uinput.set_keybit(keybit)?;
+        }
+    }
+    if !overrides.gamepad_only {
+        for relbit in bitmask_from_slice::<RelativeAxis, _>(&add_dev.relbits).iter() {
+            uinput.set_relbit(relbit)?;
+        }
+    }
+    // Paired by position, an axis we don't know still has its AbsoluteInfo
+    for (code, abs_info) in set_bits(add_dev.absbits.as_ref()).zip(abs) {
+        let Ok(absbit) = AbsoluteAxis::from_code(code as u16) else {
+            continue;
+        };
+        uinput.set_absbit(absbit)?;
+        uinput.abs_setup(&uinput_abs_setup {
+            code: code as u16,
+            absinfo: input_absinfo {
+                value: abs_info.value,
+                minimum: abs_info.minimum,
+                maximum: abs_info.maximum,
+                fuzz: abs_info.fuzz,
+                flat: abs_info.flat,
+                resolution: abs_info.resolution,
+            },
+        })?;
+    }
+    for mscbit in bitmask_from_slice::<MiscKind, _>(&add_dev.mscbits).iter() {
+        uinput.set_mscbit(mscbit)?;
+    }
+    for ledbit in bitmask_from_slice::<LedKind, _>(&add_dev.ledbits).iter() {
+        uinput.set_ledbit(ledbit)?;
+    }
+    for sndbit in bitmask_from_slice::<SoundKind, _>(&add_dev.sndbits).iter() {
+        uinput.set_sndbit(sndbit)?;
+    }
+    for swbit in bitmask_from_slice::<SwitchKind, _>(&add_dev.swbits).iter() {
+        uinput.set_swbit(swbit)?;
+    }
+    // Properties change how userspace treats the whole device, so set them by
+    // number to also keep the ones newer than input-linux
+    for propbit in set_bits(add_dev.propbits.as_ref()) {
+        // SAFETY: UI_SET_PROPBIT takes an integer
+        unsafe { ui_set_propbit(uinput.as_inner().as_raw_fd(), propbit as _) }?;
+    }
+    for ffbit in bitmask_from_slice::<ForceFeedbackKind, _>(&add_dev.ffbits).iter() {
+        uinput.set_ffbit(ffbit)?;
+    }
+    let id = overrides.input_id(add_dev.input_id);
+    uinput.dev_setup(&uinput_setup {
+        id: input_id {
+            bustype: id.bustype,
+            vendor: id.vendor,
+            product: id.product,
+            version: id.version,
+        },
+        name: add_dev.name.map(|c| c as c_char),
+        ff_effects_max: add_dev.ff_effects,
+    })?;
+    let host_phys = add_dev.phys.split(|c| *c == 0).next().unwrap();
+    let phys = match &overrides.phys {
+        Some(prefix) if host_phys.is_empty() => Some(prefix.as_bytes().to_vec()),
+        Some(prefix) => Some([prefix.as_bytes(), b"/", host_phys].concat()),
+        None if host_phys.is_empty() => None,
+        None => Some(host_phys.to_vec()),
+    };
+    match phys.map(CString::new) {
+        Some(Ok(phys)) => uinput.set_phys(&phys)?,
+        Some(Err(e)) => warn!(
+            "Not setting the phys of device {}, it has a NUL at {}",
+            add_dev.id,
+            e.nul_position()
+        ),
+        None => {}
+    }
+    uinput.dev_create()?;
+    Ok(uinput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input_linux::sys::{BUS_BLUETOOTH, BUS_USB};
+
+    /// A device made of the capabilities the heuristics look at.
+    #[derive(Default)]
+    struct Caps {
+        props: Bitmask<InputProperty>,
+        events: Bitmask<EventKind>,
+        abs: Bitmask<AbsoluteAxis>,
+        keys: Bitmask<Key>,
+        rel: Bitmask<RelativeAxis>,
+    }
+
+    impl Caps {
+        fn new(keys: &[Key], abs: &[AbsoluteAxis]) -> Caps {
+            let mut caps = Caps::default();
+            caps.events.insert(EventKind::Synchronize);
+            for key in keys {
+                caps.events.insert(EventKind::Key);
+                caps.keys.insert(*key);
+            }
+            for axis in abs {
+                caps.events.insert(EventKind::Absolute);
+                caps.abs.insert(*axis);
+            }
+            caps
+        }
+    }
+
+    impl Capabilities for Caps {
+        fn device_properties(&self) -> io::Result<Bitmask<InputProperty>> {
+            Ok(self.props)
+        }
+        fn event_bits(&self) -> io::Result<Bitmask<EventKind>> {
+            Ok(self.events)
+        }
+        fn absolute_mask(&self) -> io::Result<Bitmask<AbsoluteAxis>> {
+            Ok(self.abs)
+        }
+        fn key_mask(&self) -> io::Result<Bitmask<Key>> {
+            Ok(self.keys)
+        }
+        fn relative_mask(&self) -> io::Result<Bitmask<RelativeAxis>> {
+            Ok(self.rel)
+        }
+        fn device_id(&self) -> io::Result<InputId> {
+            Ok(InputId::default())
+        }
+        fn physical_location(&self) -> io::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn empty_device() -> AddDevice {
+        // SAFETY: AddDevice is made of integers and arrays of them
+        unsafe { mem::zeroed() }
+    }
+
+    #[test]
+    fn abs_infos_land_on_their_axes() {
+        use AbsoluteAxis::*;
+        let mut abs = Bitmask::<AbsoluteAxis>::default();
+        for axis in [Misc, Hat0X, X, RZ] {
+            abs.insert(axis);
+        }
+        let mut add_dev = empty_device();
+        add_dev.absbits = *abs.data();
+        // Each axis gets an info that tells which it is
+        let record = record_from(add_dev, |axis| {
+            let code = axis as i32;
+            Ok(AbsoluteInfo {
+                value: code,
+                minimum: -code,
+                maximum: code * 100,
+                fuzz: 0,
+                flat: code,
+                resolution: 1,
+            })
+        })
+        .unwrap();
+        let (add_dev, infos) = read_record(&mut &record[..]).unwrap();
+        let axes: Vec<_> = set_bits(add_dev.absbits.as_ref()).collect();
+        assert_eq!(
+            axes,
+            [X, RZ, Hat0X, Misc].map(|axis| axis as usize).to_vec()
+        );
+        assert_eq!(infos.len(), axes.len());
+        for (code, info) in axes.into_iter().zip(infos) {
+            let code = code as i32;
+            assert_eq!(
+                (info.value, info.minimum, info.maximum, info.flat),
+                (code, -code, code * 100, code)
+            );
+        }
+    }
+
+    #[test]
+    fn input_properties_survive_a_record() {
+        let props = [
+            InputProperty::Pointer,
+            InputProperty::Direct,
+            InputProperty::ButtonPad,
+            InputProperty::SemiMultiTouch,
+            InputProperty::TopButtonPad,
+            InputProperty::PointingStick,
+            InputProperty::Accelerometer,
+        ];
+        for prop in props {
+            let mut bits = Bitmask::<InputProperty>::default();
+            bits.insert(prop);
+            let mut add_dev = empty_device();
+            add_dev.propbits = *bits.data();
+            let record = record_from(add_dev, |_| unreachable!()).unwrap();
+            let (add_dev, _) = read_record(&mut &record[..]).unwrap();
+            let props: Vec<_> = add_dev.device_properties().unwrap().iter().collect();
+            assert_eq!(props, [prop]);
+        }
+    }
+
+    #[test]
+    fn timestamps_survive_a_record() {
+        let mut bits = Bitmask::<MiscKind>::default();
+        bits.insert(MiscKind::Timestamp);
+        bits.insert(MiscKind::Scancode);
+        let mut add_dev = empty_device();
+        add_dev.mscbits = *bits.data();
+        let record = record_from(add_dev, |_| unreachable!()).unwrap();
+        let (add_dev, _) = read_record(&mut &record[..]).unwrap();
+        let misc: Vec<_> = bitmask_from_slice::<MiscKind, _>(&add_dev.mscbits)
+            .iter()
+            .collect();
+        assert_eq!(misc, [MiscKind::Timestamp]);
+    }
+
+    #[test]
+    fn joysticks() {
+        use AbsoluteAxis::*;
+        let xbox_pad = Caps::new(
+            &[Key::ButtonSouth, Key::ButtonEast, Key::ButtonStart],
+            &[X, Y, Z, RX, RY, RZ, Hat0X, Hat0Y],
+        );
+        assert!(is_joystick(&xbox_pad).unwrap());
+        // The pedals and the paddles of a G29
+        let wheel = Caps::new(
+            &[Key::ButtonTrigger, Key::ButtonThumb],
+            &[X, Y, Z, RZ, Hat0X],
+        );
+        assert!(is_joystick(&wheel).unwrap());
+        // Dance mats show their arrows as a d-pad on two axes
+        let ddr_pad = Caps::new(&[Key::ButtonTrigger, Key::ButtonThumb2], &[X, Y]);
+        assert!(is_joystick(&ddr_pad).unwrap());
+        // An NES style pad, its d-pad as buttons and no axis at all
+        let digital_pad = Caps::new(
+            &[
+                Key::ButtonDpadUp,
+                Key::ButtonDpadDown,
+                Key::ButtonDpadLeft,
+                Key::ButtonDpadRight,
+                Key::ButtonEast,
+                Key::ButtonStart,
+            ],
+            &[],
+        );
+        assert!(is_joystick(&digital_pad).unwrap());
+    }
+
+    #[test]
+    fn not_joysticks() {
+        use AbsoluteAxis::*;
+        // The motion sensor of a DualShock 4 has all the axes of a gamepad
+        let mut accelerometer = Caps::new(&[], &[X, Y, Z, RX, RY, RZ]);
+        accelerometer.props.insert(InputProperty::Accelerometer);
+        assert!(!is_joystick(&accelerometer).unwrap());
+        assert!(is_motion_sensor(&accelerometer).unwrap());
+        let mut trackpad = Caps::new(
+            &[Key::ButtonLeft, Key::ButtonToolFinger],
+            &[X, Y, MultitouchPositionX, MultitouchPositionY],
+        );
+        trackpad.props.insert(InputProperty::ButtonPad);
+        assert!(!is_joystick(&trackpad).unwrap());
+        assert!(is_touchpad(&trackpad).unwrap());
+        let mut mouse = Caps::new(&[Key::ButtonLeft, Key::ButtonRight], &[]);
+        mouse.events.insert(EventKind::Relative);
+        mouse.rel.insert(RelativeAxis::X);
+        mouse.rel.insert(RelativeAxis::Y);
+        assert!(!is_joystick(&mouse).unwrap());
+        assert!(is_mouse(&mouse).unwrap());
+        // A tablet pen moves on X and Y but has none of the rest
+        let pen = Caps::new(&[Key::ButtonToolPen, Key::ButtonTouch], &[X, Y, Pressure]);
+        assert!(!is_joystick(&pen).unwrap());
+    }
+
+    fn xbox_pad() -> InputId {
+        InputId {
+            bustype: BUS_USB,
+            vendor: 0x045e,
+            product: 0x028e,
+            version: 0x0110,
+        }
+    }
+
+    fn as_str(buf: &[u8]) -> &str {
+        std::str::from_utf8(buf.split(|c| *c == 0).next().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_character_boundary() {
+        // 40 three byte characters, cut after 79 bytes would split the 27th
+        let name = "手".repeat(40);
+        assert_eq!(name.len(), 120);
+        let mut buf = [0xffu8; 80];
+        assert!(copy_string(&mut buf, name.as_bytes()));
+        assert_eq!(as_str(&buf), "手".repeat(26));
+        assert!(buf[78..].iter().all(|c| *c == 0));
+
+        let mut buf = [0xffu8; 80];
+        assert!(!copy_string(&mut buf, b"Pad\0garbage"));
+        assert_eq!(as_str(&buf), "Pad");
+        assert!(buf[3..].iter().all(|c| *c == 0));
+        // The last byte is kept for the nul
+        let mut buf = [0u8; 4];
+        assert!(!copy_string(&mut buf, b"abc"));
+        assert!(copy_string(&mut buf, b"abcd"));
+        assert_eq!(&buf, b"abc\0");
+    }
+
+    #[test]
+    fn strings_from_the_peer_are_sanitized() {
+        // Not nul terminated, the last character doesn't fit
+        let mut buf = [0u8; 80];
+        buf.copy_from_slice(&"é".repeat(40).as_bytes()[..80]);
+        sanitize_string(&mut buf);
+        assert_eq!(as_str(&buf), "é".repeat(39));
+        // Invalid UTF-8 is replaced
+        let mut buf = [0u8; 16];
+        buf[..5].copy_from_slice(b"a\xffb\xc3c");
+        sanitize_string(&mut buf);
+        assert_eq!(as_str(&buf), "a\u{fffd}b\u{fffd}c");
+    }
+
+    #[test]
+    fn spoof_parse() {
+        let spoof = Spoof::parse("2dc8:6001=045e:028e").unwrap();
+        assert_eq!(
+            (
+                spoof.vendor,
+                spoof.product,
+                spoof.as_vendor,
+                spoof.as_product
+            ),
+            (0x2dc8, 0x6001, 0x045e, 0x028e)
+        );
+        assert_eq!(spoof.as_version, None);
+        let spoof = Spoof::parse("2dc8:6001=045e:028e:0114").unwrap();
+        assert_eq!(spoof.as_version, Some(0x0114));
+        for bad in [
+            "",
+            "2dc8:6001",
+            "2dc8:6001=",
+            "2dc8=045e:028e",
+            "2dc8:6001=045e",
+            "2dc8:6001=045e:028e:0114:1",
+            "2dc8:6001=045e:zzzz",
+            "12345:6001=045e:028e",
+            "2dc8:6001=045e:028e:",
+        ] {
+            assert!(Spoof::parse(bad).is_none(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn spoofed_ids() {
+        let spoofs = [
+            Spoof::parse("2dc8:6001=045e:028e").unwrap(),
+            Spoof::parse("045e:028e=054c:09cc:8111").unwrap(),
+        ];
+        let overrides = Overrides {
+            bustype: None,
+            phys: None,
+            gamepad_only: false,
+            spoofs: &spoofs,
+        };
+        let id = |vendor, product| InputId {
+            bustype: BUS_USB,
+            vendor,
+            product,
+            version: 0x0100,
+        };
+        let spoofed = overrides.input_id(id(0x2dc8, 0x6001));
+        assert_eq!(
+            (spoofed.vendor, spoofed.product, spoofed.version),
+            (0x045e, 0x028e, 0x0100)
+        );
+        // Spoofs don't chain, and the version is replaced when given
+        let spoofed = overrides.input_id(id(0x045e, 0x028e));
+        assert_eq!(
+            (spoofed.vendor, spoofed.product, spoofed.version),
+            (0x054c, 0x09cc, 0x8111)
+        );
+        // Same vendor, another product
+        let spoofed = overrides.input_id(id(0x2dc8, 0x6002));
+        assert_eq!(
+            (spoofed.vendor, spoofed.product, spoofed.version),
+            (0x2dc8, 0x6002, 0x0100)
+        );
+    }
+
+    #[test]
+    fn bustype_override() {
+        let mut overrides = Overrides {
+            bustype: None,
+            phys: None,
+            gamepad_only: false,
+            spoofs: &[],
+        };
+        assert_eq!(overrides.input_id(xbox_pad()).bustype, BUS_USB);
+        overrides.bustype = Some(BUS_BLUETOOTH);
+        let id = overrides.input_id(xbox_pad());
+        assert_eq!(id.bustype, BUS_BLUETOOTH);
+        assert_eq!(
+            (id.vendor, id.product, id.version),
+            (0x045e, 0x028e, 0x0110)
+        );
+    }
+}
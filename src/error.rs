@@ -0,0 +1,86 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Any I/O error not covered below
+    Io(io::Error),
+    /// The peer closed the connection
+    Disconnected,
+    /// The peer sent something that does not follow the protocol
+    Protocol(String),
+    /// The device with this id went away while we were using it
+    DeviceGone(u64),
+    /// The device with this id is still there but failed a query
+    DeviceFailed(u64, io::Error),
+    /// The peer speaks another version of the protocol
+    VersionMismatch { ours: u32, theirs: u32 },
+    /// The peer left this many bytes unread, it isn't keeping up
+    Backlog(usize),
+    /// The peer stopped answering pings
+    Unresponsive,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Wraps an error returned by an operation on device `id`.
+    pub fn from_device(id: u64, e: io::Error) -> Error {
+        if e.raw_os_error() == Some(libc::ENODEV) {
+            Error::DeviceGone(id)
+        } else {
+            Error::DeviceFailed(id, e)
+        }
+    }
+    /// Nothing went wrong, the operation just has to be retried later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe => Error::Disconnected,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Disconnected => write!(f, "connection closed"),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::DeviceGone(id) => write!(f, "device {} is gone", id),
+            Error::DeviceFailed(id, e) => write!(f, "device {} failed: {}", id, e),
+            Error::VersionMismatch { ours, theirs } => write!(
+                f,
+                "protocol version mismatch, we speak {}.{} and the peer {}.{}",
+                ours >> 8,
+                ours & 0xff,
+                theirs >> 8,
+                theirs & 0xff
+            ),
+            Error::Backlog(len) => write!(f, "{} bytes left unread", len),
+            Error::Unresponsive => write!(f, "no answer to pings"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! The legacy joystick interface numbers axes and buttons itself. The order is
+//! derived from the capabilities, which are forwarded as is, but it can also be
+//! remapped (with `jscal` for example), so the server reads the mapping of the
+//! host node and the client applies it to the one in the guest.
+
+use crate::JoystickMap;
+use nix::{ioctl_read, ioctl_write_ptr};
+use std::fs::{self, File};
+use std::io::Result;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+pub const AXMAP_LEN: usize = 0x40;
+pub const BTNMAP_LEN: usize = 0x200;
+
+ioctl_read!(jsiocgaxes, b'j', 0x11, u8);
+ioctl_read!(jsiocgbuttons, b'j', 0x12, u8);
+ioctl_write_ptr!(jsiocsaxmap, b'j', 0x31, [u8; AXMAP_LEN]);
+ioctl_read!(jsiocgaxmap, b'j', 0x32, [u8; AXMAP_LEN]);
+ioctl_write_ptr!(jsiocsbtnmap, b'j', 0x33, [u16; BTNMAP_LEN]);
+ioctl_read!(jsiocgbtnmap, b'j', 0x34, [u16; BTNMAP_LEN]);
+
+/// Finds the joystick node next to an input device's event node, given the
+/// sysfs directory of the input device.
+pub fn find_node(sys_path: &Path) -> Option<PathBuf> {
+    for entry in fs::read_dir(sys_path).ok()? {
+        let name = entry.ok()?.file_name();
+        if name.to_string_lossy().starts_with("js") {
+            return Some(Path::new("/dev/input").join(name));
+        }
+    }
+    None
+}
+
+/// Finds the joystick node of the device an event node belongs to.
+pub fn node_for_evdev<F: AsRawFd>(evdev: &F) -> Option<PathBuf> {
+    let node = fs::read_link(format!("/proc/self/fd/{}", evdev.as_raw_fd())).ok()?;
+    let sys_path = Path::new("/sys/class/input")
+        .join(node.file_name()?)
+        .join("device");
+    find_node(&sys_path)
+}
+
+pub fn read_map(js: &File, id: u64) -> Result<JoystickMap> {
+    let mut map = JoystickMap {
+        id,
+        axes: 0,
+        buttons: 0,
+        axmap: [0; AXMAP_LEN],
+        btnmap: [0; BTNMAP_LEN],
+    };
+    // SAFETY: each ioctl writes to a buffer of the size encoded in its number
+    unsafe {
+        jsiocgaxes(js.as_raw_fd(), &mut map.axes)?;
+        jsiocgbuttons(js.as_raw_fd(), &mut map.buttons)?;
+        jsiocgaxmap(js.as_raw_fd(), &mut map.axmap)?;
+        jsiocgbtnmap(js.as_raw_fd(), &mut map.btnmap)?;
+    }
+    Ok(map)
+}
+
+/// Applies a mapping read with [`read_map`], returns the mapping the node has
+/// afterwards so the caller can check it took.
+pub fn write_map(js: &File, map: &JoystickMap) -> Result<JoystickMap> {
+    // SAFETY: each ioctl reads from a buffer of the size encoded in its number
+    unsafe {
+        jsiocsaxmap(js.as_raw_fd(), &map.axmap)?;
+        jsiocsbtnmap(js.as_raw_fd(), &map.btnmap)?;
+    }
+    read_map(js, map.id)
+}
@@ -0,0 +1,918 @@
+//! JSON framing, for clients written in languages that can't easily mirror the
+//! layout of the native messages. A client asks for it by setting
+//! [`HELLO_JSON`](crate::HELLO_JSON) in the version of its hello. The hellos
+//! themselves stay binary, everything after them is a little endian `u32`
+//! length followed by that many bytes of a JSON object. The README describes
+//! the object of each message.
+//!
+//! Messages are translated to and from their native form at the edge, so the
+//! rest of the server doesn't know which framing a client uses.
+
+use crate::device::{self, set_bits};
+use crate::{
+    joydev, message_header, struct_as_bytes, AddDevice, Error, FFErase, FFUpload, InputEvent,
+    InstanceTag, JoystickMap, MessageType, Padded, Ping, RemoveDevice, Result, Subscribe,
+    UpdateAbsInfo, Wire, MAX_MESSAGE_SIZE, MAX_SUBSCRIPTIONS,
+};
+use input_linux::sys::{ff_effect, ff_replay, ff_trigger};
+use input_linux::{AbsoluteInfo, InputId};
+use std::fmt::Write;
+use std::{mem, ptr};
+
+const TYPE_NAMES: [(&str, MessageType); 14] = [
+    ("add_device", MessageType::AddDevice),
+    ("remove_device", MessageType::RemoveDevice),
+    ("input_event", MessageType::InputEvent),
+    ("ff_upload", MessageType::FFUpload),
+    ("ff_erase", MessageType::FFErase),
+    ("device_snapshot", MessageType::DeviceSnapshot),
+    ("instance_tag", MessageType::InstanceTag),
+    ("update_device", MessageType::UpdateDevice),
+    ("joystick_map", MessageType::JoystickMap),
+    ("ping", MessageType::Ping),
+    ("pong", MessageType::Pong),
+    ("update_abs_info", MessageType::UpdateAbsInfo),
+    ("input_frame", MessageType::InputFrame),
+    ("subscribe", MessageType::Subscribe),
+];
+
+fn protocol_error(reason: &str) -> Error {
+    Error::Protocol(format!("json: {}", reason))
+}
+
+/// Reads a `T` from the start of a native payload.
+fn payload_as<T>(payload: &[u8]) -> Result<T> {
+    if payload.len() < mem::size_of::<T>() {
+        return Err(protocol_error("truncated payload"));
+    }
+    // SAFETY: only used on the message structs, made of integers and arrays of them
+    Ok(unsafe { ptr::read_unaligned(payload.as_ptr() as *const T) })
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn list<T: ToString>(items: impl Iterator<Item = T>) -> String {
+    let items: Vec<_> = items.map(|item| item.to_string()).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn effect_to_json(effect: &ff_effect) -> String {
+    format!(
+        "{{\"type\":{},\"id\":{},\"direction\":{},\"trigger\":{{\"button\":{},\"interval\":{}}},\
+         \"replay\":{{\"length\":{},\"delay\":{}}},\"u\":{}}}",
+        effect.type_,
+        effect.id,
+        effect.direction,
+        effect.trigger.button,
+        effect.trigger.interval,
+        effect.replay.length,
+        effect.replay.delay,
+        list(effect.u.iter()),
+    )
+}
+
+fn record_to_json(name: &str, payload: &[u8]) -> Result<String> {
+    let (dev, abs) = device::read_record(&mut &payload[..])?;
+    let dev_name = dev.name.split(|c| *c == 0).next().unwrap();
+    let uniq = dev.uniq.split(|c| *c == 0).next().unwrap();
+    let phys = dev.phys.split(|c| *c == 0).next().unwrap();
+    let abs = abs.iter().zip(set_bits(dev.absbits.as_ref())).map(|(info, code)| {
+        format!(
+            "{{\"code\":{},\"value\":{},\"minimum\":{},\"maximum\":{},\"fuzz\":{},\"flat\":{},\"resolution\":{}}}",
+            code, info.value, info.minimum, info.maximum, info.fuzz, info.flat, info.resolution
+        )
+    });
+    Ok(format!(
+        "{{\"type\":\"{}\",\"id\":{},\"name\":{},\"uniq\":{},\"phys\":{},\
+         \"input_id\":{{\"bustype\":{},\"vendor\":{},\"product\":{},\"version\":{}}},\
+         \"ff_effects\":{},\"ev\":{},\"key\":{},\"rel\":{},\"abs\":{},\"msc\":{},\"led\":{},\
+         \"snd\":{},\"sw\":{},\"prop\":{},\"ff\":{}}}",
+        name,
+        dev.id,
+        quote(&String::from_utf8_lossy(dev_name)),
+        quote(&String::from_utf8_lossy(uniq)),
+        quote(&String::from_utf8_lossy(phys)),
+        dev.input_id.bustype,
+        dev.input_id.vendor,
+        dev.input_id.product,
+        dev.input_id.version,
+        dev.ff_effects,
+        list(set_bits(dev.evbits.as_ref())),
+        list(set_bits(dev.keybits.as_ref())),
+        list(set_bits(dev.relbits.as_ref())),
+        list(abs),
+        list(set_bits(dev.mscbits.as_ref())),
+        list(set_bits(dev.ledbits.as_ref())),
+        list(set_bits(dev.sndbits.as_ref())),
+        list(set_bits(dev.swbits.as_ref())),
+        list(set_bits(dev.propbits.as_ref())),
+        list(set_bits(dev.ffbits.as_ref())),
+    ))
+}
+
+/// Turns a native message into a JSON frame.
+pub fn encode(ty: MessageType, payload: &[u8]) -> Result<Vec<u8>> {
+    let name = TYPE_NAMES.iter().find(|(_, t)| *t == ty).unwrap().0;
+    let json = match ty {
+        MessageType::AddDevice | MessageType::UpdateDevice => record_to_json(name, payload)?,
+        MessageType::RemoveDevice => {
+            let msg = payload_as::<RemoveDevice>(payload)?.into_native();
+            format!("{{\"type\":\"{}\",\"id\":{}}}", name, msg.id)
+        }
+        MessageType::InputEvent => {
+            let ev = payload_as::<InputEvent>(payload)?.into_native();
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"time_sec\":{},\"time_usec\":{},\"event_type\":{},\"code\":{},\"value\":{}}}",
+                name, ev.id, ev.time_sec, ev.time_usec, ev.ty, ev.code, ev.value
+            )
+        }
+        MessageType::FFUpload => {
+            let msg = payload_as::<FFUpload>(payload)?.into_native();
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"request_id\":{},\"retval\":{},\"effect\":{}}}",
+                name,
+                msg.id,
+                msg.request_id,
+                msg.retval,
+                effect_to_json(&msg.effect)
+            )
+        }
+        MessageType::FFErase => {
+            let msg = payload_as::<FFErase>(payload)?.into_native();
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"request_id\":{},\"effect_id\":{}}}",
+                name, msg.id, msg.request_id, msg.effect_id
+            )
+        }
+        MessageType::InstanceTag => {
+            let msg = payload_as::<InstanceTag>(payload)?.into_native();
+            format!("{{\"type\":\"{}\",\"tag\":{}}}", name, msg.tag)
+        }
+        MessageType::UpdateAbsInfo => {
+            let msg = payload_as::<UpdateAbsInfo>(payload)?.into_native();
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"code\":{},\"value\":{},\"minimum\":{},\"maximum\":{},\"fuzz\":{},\"flat\":{},\"resolution\":{}}}",
+                name,
+                msg.id,
+                msg.axis,
+                msg.info.value,
+                msg.info.minimum,
+                msg.info.maximum,
+                msg.info.fuzz,
+                msg.info.flat,
+                msg.info.resolution
+            )
+        }
+        MessageType::Ping | MessageType::Pong => {
+            let msg = payload_as::<Ping>(payload)?.into_native();
+            format!("{{\"type\":\"{}\",\"seq\":{}}}", name, msg.seq)
+        }
+        MessageType::JoystickMap => {
+            let msg = payload_as::<JoystickMap>(payload)?.into_native();
+            let axes = (msg.axes as usize).min(joydev::AXMAP_LEN);
+            let buttons = (msg.buttons as usize).min(joydev::BTNMAP_LEN);
+            format!(
+                "{{\"type\":\"{}\",\"id\":{},\"axmap\":{},\"btnmap\":{}}}",
+                name,
+                msg.id,
+                list(msg.axmap[..axes].iter()),
+                list(msg.btnmap[..buttons].iter())
+            )
+        }
+        MessageType::Subscribe => {
+            let msg = payload_as::<Subscribe>(payload)?.into_native();
+            let count = (msg.count as usize).min(MAX_SUBSCRIPTIONS);
+            let ids: Vec<_> = msg.ids[..count]
+                .iter()
+                .map(|[vendor, product]| {
+                    format!("{{\"vendor\":{},\"product\":{}}}", vendor, product)
+                })
+                .collect();
+            format!("{{\"type\":\"{}\",\"ids\":[{}]}}", name, ids.join(","))
+        }
+        // Snapshots only save bandwidth, JSON clients get the records one by one
+        MessageType::DeviceSnapshot => return Err(protocol_error("snapshots can't be framed")),
+        // JSON clients get input events one by one
+        MessageType::InputFrame => return Err(protocol_error("input frames can't be framed")),
+    };
+    let mut frame = (json.len() as u32).to_le_bytes().to_vec();
+    frame.extend_from_slice(json.as_bytes());
+    Ok(frame)
+}
+
+/// Takes the first frame out of `buf` and turns it into a native message,
+/// returns the message and the number of bytes the frame used, or `None` when
+/// the frame isn't complete yet.
+pub fn decode(buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+    let Some(len) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(protocol_error(&format!("frame of {} bytes", len)));
+    }
+    let Some(json) = buf.get(4..4 + len) else {
+        return Ok(None);
+    };
+    let json = std::str::from_utf8(json).map_err(|_| protocol_error("frame isn't UTF-8"))?;
+    Ok(Some((to_native(&Parser::parse(json)?)?, 4 + len)))
+}
+
+fn message<T>(ty: MessageType, data: &T) -> Vec<u8> {
+    let mut message = message_header(ty, mem::size_of::<T>()).to_vec();
+    message.extend_from_slice(struct_as_bytes(data));
+    message
+}
+
+fn set_bit(bits: &mut [u8], bit: usize) -> Result<()> {
+    let byte = bits
+        .get_mut(bit / 8)
+        .ok_or_else(|| protocol_error(&format!("bit {} out of range", bit)))?;
+    *byte |= 1 << (bit % 8);
+    Ok(())
+}
+
+fn bits_from_json(msg: &Value, name: &str, bits: &mut [u8]) -> Result<()> {
+    for bit in msg.get(name)?.array()? {
+        set_bit(bits, bit.int()?)?;
+    }
+    Ok(())
+}
+
+fn record_from_json(msg: &Value) -> Result<Vec<u8>> {
+    // SAFETY: AddDevice is made of integers and arrays of them
+    let mut dev: AddDevice = unsafe { mem::zeroed() };
+    dev.id = msg.get("id")?.int()?;
+    device::copy_string(&mut dev.name, msg.get("name")?.string()?.as_bytes());
+    device::copy_string(&mut dev.uniq, msg.get("uniq")?.string()?.as_bytes());
+    device::copy_string(&mut dev.phys, msg.get("phys")?.string()?.as_bytes());
+    let input_id = msg.get("input_id")?;
+    dev.input_id = InputId {
+        bustype: input_id.get("bustype")?.int()?,
+        vendor: input_id.get("vendor")?.int()?,
+        product: input_id.get("product")?.int()?,
+        version: input_id.get("version")?.int()?,
+    };
+    dev.ff_effects = msg.get("ff_effects")?.int()?;
+    bits_from_json(msg, "ev", dev.evbits.as_mut())?;
+    bits_from_json(msg, "key", dev.keybits.as_mut())?;
+    bits_from_json(msg, "rel", dev.relbits.as_mut())?;
+    bits_from_json(msg, "msc", dev.mscbits.as_mut())?;
+    bits_from_json(msg, "led", dev.ledbits.as_mut())?;
+    bits_from_json(msg, "snd", dev.sndbits.as_mut())?;
+    bits_from_json(msg, "sw", dev.swbits.as_mut())?;
+    bits_from_json(msg, "prop", dev.propbits.as_mut())?;
+    bits_from_json(msg, "ff", dev.ffbits.as_mut())?;
+    // The infos follow the record by increasing code, whatever order they came in
+    let mut abs = Vec::new();
+    for axis in msg.get("abs")?.array()? {
+        let code: usize = axis.get("code")?.int()?;
+        set_bit(dev.absbits.as_mut(), code)?;
+        let info = AbsoluteInfo {
+            value: axis.get("value")?.int()?,
+            minimum: axis.get("minimum")?.int()?,
+            maximum: axis.get("maximum")?.int()?,
+            fuzz: axis.get("fuzz")?.int()?,
+            flat: axis.get("flat")?.int()?,
+            resolution: axis.get("resolution")?.int()?,
+        };
+        abs.retain(|(other, _)| *other != code);
+        abs.push((code, info));
+    }
+    abs.sort_by_key(|(code, _)| *code);
+    let mut record = dev.into_wire().to_bytes();
+    for (_, info) in abs {
+        record.extend_from_slice(struct_as_bytes(&info.into_wire()));
+    }
+    Ok(record)
+}
+
+fn effect_from_json(effect: &Value) -> Result<ff_effect> {
+    let trigger = effect.get("trigger")?;
+    let replay = effect.get("replay")?;
+    let u = effect.get("u")?.array()?;
+    if u.len() != 4 {
+        return Err(protocol_error("effect parameters aren't 4 words"));
+    }
+    Ok(ff_effect {
+        type_: effect.get("type")?.int()?,
+        id: effect.get("id")?.int()?,
+        direction: effect.get("direction")?.int()?,
+        trigger: ff_trigger {
+            button: trigger.get("button")?.int()?,
+            interval: trigger.get("interval")?.int()?,
+        },
+        replay: ff_replay {
+            length: replay.get("length")?.int()?,
+            delay: replay.get("delay")?.int()?,
+        },
+        u: [u[0].int()?, u[1].int()?, u[2].int()?, u[3].int()?],
+    })
+}
+
+/// The native form of a message, header included.
+fn to_native(msg: &Value) -> Result<Vec<u8>> {
+    let name = msg.get("type")?.string()?;
+    let ty = TYPE_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .ok_or_else(|| protocol_error(&format!("unknown message {}", name)))?
+        .1;
+    Ok(match ty {
+        MessageType::AddDevice | MessageType::UpdateDevice => {
+            let record = record_from_json(msg)?;
+            let mut message = message_header(ty, record.len()).to_vec();
+            message.extend(record);
+            message
+        }
+        MessageType::RemoveDevice => message(
+            ty,
+            &RemoveDevice {
+                id: msg.get("id")?.int()?,
+            }
+            .into_wire(),
+        ),
+        MessageType::InputEvent => message(
+            ty,
+            &InputEvent {
+                time_sec: msg.get("time_sec")?.int()?,
+                time_usec: msg.get("time_usec")?.int()?,
+                id: msg.get("id")?.int()?,
+                ty: msg.get("event_type")?.int()?,
+                code: msg.get("code")?.int()?,
+                value: msg.get("value")?.int()?,
+            }
+            .into_wire(),
+        ),
+        MessageType::FFUpload => message(
+            ty,
+            &FFUpload {
+                id: msg.get("id")?.int()?,
+                request_id: msg.get("request_id")?.int()?,
+                retval: msg.get("retval")?.int()?,
+                effect: effect_from_json(msg.get("effect")?)?,
+            }
+            .into_wire(),
+        ),
+        MessageType::FFErase => message(
+            ty,
+            &FFErase {
+                id: msg.get("id")?.int()?,
+                request_id: msg.get("request_id")?.int()?,
+                effect_id: msg.get("effect_id")?.int()?,
+            }
+            .into_wire(),
+        ),
+        MessageType::InstanceTag => message(
+            ty,
+            &InstanceTag {
+                tag: msg.get("tag")?.int()?,
+            }
+            .into_wire(),
+        ),
+        MessageType::UpdateAbsInfo => {
+            let update = UpdateAbsInfo {
+                id: msg.get("id")?.int()?,
+                axis: msg.get("code")?.int()?,
+                info: AbsoluteInfo {
+                    value: msg.get("value")?.int()?,
+                    minimum: msg.get("minimum")?.int()?,
+                    maximum: msg.get("maximum")?.int()?,
+                    fuzz: msg.get("fuzz")?.int()?,
+                    flat: msg.get("flat")?.int()?,
+                    resolution: msg.get("resolution")?.int()?,
+                },
+            };
+            let bytes = update.into_wire().to_bytes();
+            let mut message = message_header(ty, bytes.len()).to_vec();
+            message.extend(bytes);
+            message
+        }
+        MessageType::Ping | MessageType::Pong => message(
+            ty,
+            &Ping {
+                seq: msg.get("seq")?.int()?,
+            }
+            .into_wire(),
+        ),
+        MessageType::JoystickMap => {
+            let axmap = msg.get("axmap")?.array()?;
+            let btnmap = msg.get("btnmap")?.array()?;
+            // The counts are bytes, a longer map would lose its tail
+            let max = |len: usize| len.min(u8::MAX as usize);
+            if axmap.len() > max(joydev::AXMAP_LEN) || btnmap.len() > max(joydev::BTNMAP_LEN) {
+                return Err(protocol_error("joystick map too long"));
+            }
+            let mut map = JoystickMap {
+                id: msg.get("id")?.int()?,
+                axes: axmap.len() as u8,
+                buttons: btnmap.len() as u8,
+                axmap: [0; joydev::AXMAP_LEN],
+                btnmap: [0; joydev::BTNMAP_LEN],
+            };
+            for (dst, src) in map.axmap.iter_mut().zip(axmap) {
+                *dst = src.int()?;
+            }
+            for (dst, src) in map.btnmap.iter_mut().zip(btnmap) {
+                *dst = src.int()?;
+            }
+            message(ty, &map.into_wire())
+        }
+        MessageType::Subscribe => {
+            let ids = msg.get("ids")?.array()?;
+            if ids.len() > MAX_SUBSCRIPTIONS {
+                return Err(protocol_error("too many subscriptions"));
+            }
+            let mut subscribe = Subscribe {
+                count: ids.len() as u32,
+                ids: [[0; 2]; MAX_SUBSCRIPTIONS],
+            };
+            for (dst, src) in subscribe.ids.iter_mut().zip(ids) {
+                *dst = [src.get("vendor")?.int()?, src.get("product")?.int()?];
+            }
+            message(ty, &subscribe.into_wire())
+        }
+        MessageType::DeviceSnapshot => return Err(protocol_error("snapshots can't be framed")),
+        MessageType::InputFrame => return Err(protocol_error("input frames can't be framed")),
+    })
+}
+
+/// Just what the messages need: numbers are integers and there is no null.
+enum Value {
+    Bool,
+    Number(i128),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, name: &str) -> Result<&Value> {
+        let Value::Object(fields) = self else {
+            return Err(protocol_error(&format!(
+                "expected an object around {}",
+                name
+            )));
+        };
+        fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+            .ok_or_else(|| protocol_error(&format!("missing {}", name)))
+    }
+    fn int<T: TryFrom<i128>>(&self) -> Result<T> {
+        match self {
+            Value::Number(n) => {
+                T::try_from(*n).map_err(|_| protocol_error(&format!("{} out of range", n)))
+            }
+            _ => Err(protocol_error("expected a number")),
+        }
+    }
+    fn string(&self) -> Result<&str> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(protocol_error("expected a string")),
+        }
+    }
+    fn array(&self) -> Result<&[Value]> {
+        match self {
+            Value::Array(items) => Ok(items),
+            _ => Err(protocol_error("expected an array")),
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse(json: &str) -> Result<Value> {
+        let mut parser = Parser {
+            data: json.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.data.len() {
+            return Err(protocol_error("trailing data"));
+        }
+        Ok(value)
+    }
+    fn skip_whitespace(&mut self) {
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Result<u8> {
+        self.skip_whitespace();
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| protocol_error("unexpected end"))
+    }
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek()? != c {
+            return Err(protocol_error(&format!("expected {}", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    /// Whether the next character is `c`, which is then skipped.
+    fn accept(&mut self, c: u8) -> Result<bool> {
+        let found = self.peek()? == c;
+        if found {
+            self.pos += 1;
+        }
+        Ok(found)
+    }
+    fn value(&mut self, depth: usize) -> Result<Value> {
+        // Messages are shallow, this only stops a malicious client from blowing the stack
+        if depth > 8 {
+            return Err(protocol_error("too deep"));
+        }
+        match self.peek()? {
+            b'{' => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if !self.accept(b'}')? {
+                    loop {
+                        self.skip_whitespace();
+                        let name = self.string()?;
+                        self.expect(b':')?;
+                        fields.push((name, self.value(depth + 1)?));
+                        if !self.accept(b',')? {
+                            break;
+                        }
+                    }
+                    self.expect(b'}')?;
+                }
+                Ok(Value::Object(fields))
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.accept(b']')? {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if !self.accept(b',')? {
+                            break;
+                        }
+                    }
+                    self.expect(b']')?;
+                }
+                Ok(Value::Array(items))
+            }
+            b'"' => Ok(Value::String(self.string()?)),
+            b't' | b'f' => {
+                let rest = &self.data[self.pos..];
+                let len = if rest.starts_with(b"true") {
+                    4
+                } else if rest.starts_with(b"false") {
+                    5
+                } else {
+                    return Err(protocol_error("unexpected character"));
+                };
+                self.pos += len;
+                Ok(Value::Bool)
+            }
+            _ => {
+                let start = self.pos;
+                if self.data[self.pos] == b'-' {
+                    self.pos += 1;
+                }
+                while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.data[start..self.pos]).unwrap();
+                number
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| protocol_error("expected an integer"))
+            }
+        }
+    }
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let c = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| protocol_error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self
+                        .data
+                        .get(self.pos)
+                        .ok_or_else(|| protocol_error("unterminated string"))?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' | b'\\' | b'/' => escape as char,
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self
+                                .data
+                                .get(self.pos..self.pos + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| protocol_error("bad escape"))?;
+                            self.pos += 4;
+                            // Names don't need surrogate pairs, they are replaced
+                            char::from_u32(hex).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(protocol_error("bad escape")),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => out.push(c),
+            }
+        }
+        // The input was a str and escapes are whole characters
+        Ok(String::from_utf8(out).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HEADER_SIZE;
+    use input_linux::sys::FF_RUMBLE;
+
+    fn frame(json: &str) -> Vec<u8> {
+        let mut frame = (json.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(json.as_bytes());
+        frame
+    }
+
+    /// Encodes a native payload, decodes the frame and encodes the result
+    /// again, which has to give the same JSON. Returns the JSON.
+    fn round_trip(ty: MessageType, payload: &[u8]) -> String {
+        let encoded = encode(ty, payload).unwrap();
+        let (native, used) = decode(&encoded).unwrap().unwrap();
+        assert_eq!(used, encoded.len());
+        assert_eq!(
+            native[..HEADER_SIZE],
+            message_header(ty, native.len() - HEADER_SIZE)
+        );
+        assert_eq!(encode(ty, &native[HEADER_SIZE..]).unwrap(), encoded);
+        String::from_utf8(encoded[4..].to_vec()).unwrap()
+    }
+
+    fn decode_err(json: &str) -> bool {
+        decode(&frame(json)).is_err()
+    }
+
+    #[test]
+    fn input_event() {
+        let ev = InputEvent {
+            time_sec: 12,
+            time_usec: 34,
+            id: 5,
+            value: -1,
+            ty: 3,
+            code: 1,
+        };
+        let json = round_trip(MessageType::InputEvent, struct_as_bytes(&ev.into_wire()));
+        assert_eq!(
+            json,
+            "{\"type\":\"input_event\",\"id\":5,\"time_sec\":12,\"time_usec\":34,\
+             \"event_type\":3,\"code\":1,\"value\":-1}"
+        );
+    }
+
+    #[test]
+    fn add_and_update_device() {
+        // SAFETY: AddDevice is made of integers and arrays of them
+        let mut dev: AddDevice = unsafe { mem::zeroed() };
+        dev.id = 3;
+        dev.input_id = InputId {
+            bustype: 3,
+            vendor: 0x045e,
+            product: 0x028e,
+            version: 0x0110,
+        };
+        dev.ff_effects = 16;
+        device::copy_string(&mut dev.name, "Pad \"one\"\n".as_bytes());
+        device::copy_string(&mut dev.uniq, b"00:11:22:33:44:55");
+        dev.evbits.as_mut()[0] = 0b1011;
+        dev.keybits.as_mut()[0x130 / 8] = 1;
+        dev.absbits.as_mut()[0] = 0b1001;
+        dev.ffbits.as_mut()[FF_RUMBLE as usize / 8] = 1 << (FF_RUMBLE % 8);
+        let mut record = dev.into_wire().to_bytes();
+        for value in [-5, 7] {
+            let info = AbsoluteInfo {
+                value,
+                minimum: -32768,
+                maximum: 32767,
+                fuzz: 16,
+                flat: 128,
+                resolution: 0,
+            };
+            record.extend_from_slice(struct_as_bytes(&info.into_wire()));
+        }
+        let json = round_trip(MessageType::AddDevice, &record);
+        assert!(json.contains("\"name\":\"Pad \\\"one\\\"\\u000a\""));
+        assert!(json.contains("\"abs\":[{\"code\":0,\"value\":-5,"));
+        assert!(json.contains("{\"code\":3,\"value\":7,"));
+        let json = round_trip(MessageType::UpdateDevice, &record);
+        assert!(json.starts_with("{\"type\":\"update_device\",\"id\":3,"));
+    }
+
+    #[test]
+    fn abs_infos_follow_the_codes() {
+        let json = "{\"type\":\"add_device\",\"id\":1,\"name\":\"\",\"uniq\":\"\",\"phys\":\"\",\
+                    \"input_id\":{\"bustype\":3,\"vendor\":1,\"product\":2,\"version\":0},\
+                    \"ff_effects\":0,\"ev\":[3],\"key\":[],\"rel\":[],\
+                    \"abs\":[{\"code\":5,\"value\":1,\"minimum\":0,\"maximum\":255,\"fuzz\":0,\"flat\":0,\"resolution\":0},\
+                    {\"code\":2,\"value\":9,\"minimum\":0,\"maximum\":255,\"fuzz\":0,\"flat\":0,\"resolution\":0}],\
+                    \"msc\":[],\"led\":[],\"snd\":[],\"sw\":[],\"prop\":[],\"ff\":[]}";
+        let (native, _) = decode(&frame(json)).unwrap().unwrap();
+        let (dev, abs) = device::read_record(&mut &native[HEADER_SIZE..]).unwrap();
+        assert_eq!(set_bits(dev.absbits.as_ref()).collect::<Vec<_>>(), [2, 5]);
+        assert_eq!(
+            abs.iter().map(|info| info.value).collect::<Vec<_>>(),
+            [9, 1]
+        );
+    }
+
+    #[test]
+    fn small_messages() {
+        let json = round_trip(
+            MessageType::RemoveDevice,
+            struct_as_bytes(&RemoveDevice { id: 9 }.into_wire()),
+        );
+        assert_eq!(json, "{\"type\":\"remove_device\",\"id\":9}");
+        for ty in [MessageType::Ping, MessageType::Pong] {
+            round_trip(ty, struct_as_bytes(&Ping { seq: u64::MAX }));
+        }
+        let json = round_trip(
+            MessageType::InstanceTag,
+            struct_as_bytes(&InstanceTag { tag: 42 }),
+        );
+        assert_eq!(json, "{\"type\":\"instance_tag\",\"tag\":42}");
+        let erase = FFErase {
+            id: 1,
+            request_id: 2,
+            effect_id: 3,
+        };
+        round_trip(MessageType::FFErase, struct_as_bytes(&erase));
+        let update = UpdateAbsInfo {
+            id: 1,
+            axis: 2,
+            info: AbsoluteInfo {
+                value: 3,
+                minimum: -4,
+                maximum: 5,
+                fuzz: 6,
+                flat: 7,
+                resolution: 8,
+            },
+        };
+        round_trip(MessageType::UpdateAbsInfo, &update.into_wire().to_bytes());
+    }
+
+    #[test]
+    fn ff_upload() {
+        // SAFETY: ff_effect is made of integers and arrays of them
+        let mut effect: ff_effect = unsafe { mem::zeroed() };
+        effect.type_ = FF_RUMBLE;
+        effect.id = -1;
+        effect.replay.length = 1000;
+        effect.u = [0x4000_8000, 0, 0, 0];
+        let upload = FFUpload {
+            id: 2,
+            request_id: 7,
+            retval: -16,
+            effect,
+        };
+        let json = round_trip(MessageType::FFUpload, &upload.to_bytes());
+        assert!(json.contains("\"retval\":-16"));
+        assert!(json.contains("\"replay\":{\"length\":1000,\"delay\":0}"));
+    }
+
+    #[test]
+    fn joystick_map_and_subscribe() {
+        let mut map = JoystickMap {
+            id: 4,
+            axes: 2,
+            buttons: 1,
+            axmap: [0; joydev::AXMAP_LEN],
+            btnmap: [0; joydev::BTNMAP_LEN],
+        };
+        map.axmap[..2].copy_from_slice(&[1, 0]);
+        map.btnmap[0] = 0x130;
+        let json = round_trip(MessageType::JoystickMap, &map.to_bytes());
+        assert!(json.ends_with("\"axmap\":[1,0],\"btnmap\":[304]}"));
+        map.buttons = u8::MAX;
+        map.btnmap.fill(0x130);
+        round_trip(MessageType::JoystickMap, &map.to_bytes());
+        let mut subscribe = Subscribe {
+            count: 1,
+            ids: [[0; 2]; MAX_SUBSCRIPTIONS],
+        };
+        subscribe.ids[0] = [0x045e, 0x028e];
+        let json = round_trip(
+            MessageType::Subscribe,
+            struct_as_bytes(&subscribe.into_wire()),
+        );
+        assert_eq!(
+            json,
+            "{\"type\":\"subscribe\",\"ids\":[{\"vendor\":1118,\"product\":654}]}"
+        );
+    }
+
+    #[test]
+    fn incomplete_frames() {
+        let frame = frame("{\"type\":\"ping\",\"seq\":1}");
+        assert!(decode(&frame[..3]).unwrap().is_none());
+        assert!(decode(&frame[..frame.len() - 1]).unwrap().is_none());
+        // A frame is taken at a time
+        let mut two = frame.clone();
+        two.extend_from_slice(&frame);
+        assert_eq!(decode(&two).unwrap().unwrap().1, frame.len());
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let mut huge = ((MAX_MESSAGE_SIZE + 1) as u32).to_le_bytes().to_vec();
+        huge.push(b'{');
+        assert!(decode(&huge).is_err());
+        let mut binary = 2u32.to_le_bytes().to_vec();
+        binary.extend_from_slice(&[0xff, 0xfe]);
+        assert!(decode(&binary).is_err());
+
+        assert!(decode_err(""));
+        assert!(decode_err("[1,2"));
+        assert!(decode_err("{\"type\":\"ping\",\"seq\":1} x"));
+        assert!(decode_err("{\"type\":\"ping\",\"seq\":1.5}"));
+        assert!(decode_err("{\"type\":\"ping\",\"seq\":-1}"));
+        assert!(decode_err("{\"type\":\"ping\"}"));
+        assert!(decode_err("{\"type\":\"ping\",\"seq\":\"1\"}"));
+        assert!(decode_err("{\"type\":\"nope\"}"));
+        assert!(decode_err("{\"type\":\"pi\\q\"}"));
+        assert!(decode_err("{\"type\":\"ping"));
+        assert!(decode_err("{\"type\":\"device_snapshot\"}"));
+        assert!(decode_err("{\"type\":\"input_frame\"}"));
+        assert!(decode_err(&format!(
+            "{}1{}",
+            "[".repeat(20),
+            "]".repeat(20)
+        )));
+        assert!(decode_err(
+            "{\"type\":\"input_event\",\"id\":1,\"time_sec\":0,\"time_usec\":0,\
+             \"event_type\":70000,\"code\":0,\"value\":0}"
+        ));
+        assert!(decode_err(&format!(
+            "{{\"type\":\"subscribe\",\"ids\":[{}]}}",
+            vec!["{\"vendor\":1,\"product\":2}"; MAX_SUBSCRIPTIONS + 1].join(",")
+        )));
+        assert!(decode_err(&format!(
+            "{{\"type\":\"joystick_map\",\"id\":1,\"axmap\":{},\"btnmap\":[]}}",
+            list(std::iter::repeat_n(0, joydev::AXMAP_LEN + 1))
+        )));
+        // More buttons than the count can say
+        assert!(decode_err(&format!(
+            "{{\"type\":\"joystick_map\",\"id\":1,\"axmap\":[],\"btnmap\":{}}}",
+            list(std::iter::repeat_n(0x130, 300))
+        )));
+        assert!(decode_err(
+            "{\"type\":\"ff_upload\",\"id\":1,\"request_id\":1,\"retval\":0,\"effect\":\
+             {\"type\":80,\"id\":-1,\"direction\":0,\"trigger\":{\"button\":0,\"interval\":0},\
+             \"replay\":{\"length\":0,\"delay\":0},\"u\":[0,0,0]}}"
+        ));
+        // A bit past the end of the evbits
+        assert!(decode_err(
+            "{\"type\":\"add_device\",\"id\":1,\"name\":\"\",\"uniq\":\"\",\"phys\":\"\",\
+             \"input_id\":{\"bustype\":3,\"vendor\":1,\"product\":2,\"version\":0},\
+             \"ff_effects\":0,\"ev\":[9999],\"key\":[],\"rel\":[],\"abs\":[],\
+             \"msc\":[],\"led\":[],\"snd\":[],\"sw\":[],\"prop\":[],\"ff\":[]}"
+        ));
+    }
+
+    #[test]
+    fn native_only_messages_refuse_encoding() {
+        assert!(encode(MessageType::DeviceSnapshot, &[]).is_err());
+        assert!(encode(MessageType::InputFrame, &[]).is_err());
+        assert!(encode(MessageType::RemoveDevice, &[0; 4]).is_err());
+    }
+}
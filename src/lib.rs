@@ -1,18 +1,67 @@
 use input_linux::sys::{ff_effect, input_event, timeval};
 use input_linux::{
-    bitmask::BitmaskTrait, AbsoluteAxis, EventKind, ForceFeedbackKind, InputId, InputProperty, Key,
-    LedKind, MiscKind, RelativeAxis, SoundKind, SwitchKind,
+    bitmask::BitmaskTrait, AbsoluteAxis, AbsoluteInfo, EventKind, ForceFeedbackKind, InputId,
+    InputProperty, Key, LedKind, MiscKind, RelativeAxis, SoundKind, SwitchKind, SynchronizeKind,
 };
-use std::io::{Result, Write};
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::{AsFd, AsRawFd};
 use std::os::unix::net::UnixStream;
+use std::time::Duration;
 use std::{mem, slice};
 
+pub mod control;
+pub mod device;
+mod error;
+pub mod joydev;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod log;
+pub mod message;
+pub mod signal;
+pub mod touchpad;
+pub mod trace;
+pub mod wire;
+
+pub use error::{Error, Result};
+pub use wire::{Padded, Wire};
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct ClientHello {
     pub version: u32,
 }
 
+/// The protocol version, in the low 16 bits of a hello, with the major version
+/// in the high byte. Peers only talk if their major versions are equal, a new
+/// minor version only adds things older peers don't have to know about.
+pub const PROTOCOL_VERSION: u32 = 0x0402;
+/// The bits of a hello holding the protocol version, the others are flags.
+pub const HELLO_VERSION_MASK: u32 = 0xffff;
+
+/// The major version of the hello `version`.
+pub fn protocol_major(version: u32) -> u32 {
+    (version & HELLO_VERSION_MASK) >> 8
+}
+
+/// Set in the version of a [`ClientHello`] to ask for JSON framing, the server
+/// sets it in its [`ServerHello`] when it agrees. Only servers built with the
+/// `json` feature do.
+pub const HELLO_JSON: u32 = 1 << 31;
+
+/// Set in the version of a hello by peers that answer pings. Each side only
+/// pings the other if it set it.
+pub const HELLO_PING: u32 = 1 << 30;
+
+/// Set in the version of a [`ClientHello`] by clients that put the instance tag
+/// in the phys of their devices. Only those get sent one.
+pub const HELLO_INSTANCE_TAG: u32 = 1 << 29;
+
+/// How often a peer that answers pings gets one.
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+/// A peer that doesn't answer a ping for that long is considered dead.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct ServerHello {
@@ -20,25 +69,41 @@ pub struct ServerHello {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MessageType {
     AddDevice,
     RemoveDevice,
     InputEvent,
     FFUpload,
     FFErase,
+    DeviceSnapshot,
+    InstanceTag,
+    /// An `AddDevice` for a device the client already has, whose capabilities changed
+    UpdateDevice,
+    JoystickMap,
+    /// Asks the peer to send the same `Ping` back as a `Pong`
+    Ping,
+    Pong,
+    /// New limits for an axis of a device the client already has
+    UpdateAbsInfo,
+    /// The events of one report of a device, up to its `SYN_REPORT`
+    InputFrame,
+    /// The models of the devices a client wants
+    Subscribe,
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FFUpload {
     pub id: u64,
     pub request_id: u32,
+    /// Set by the server when it refuses the upload, as a negative errno
+    pub retval: i32,
     pub effect: ff_effect,
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct FFErase {
     pub id: u64,
     pub request_id: u32,
@@ -46,7 +111,7 @@ pub struct FFErase {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct AddDevice {
     pub id: u64,
     pub evbits: <EventKind as BitmaskTrait>::Array,
@@ -62,16 +127,111 @@ pub struct AddDevice {
     pub input_id: InputId,
     pub ff_effects: u32,
     pub name: [u8; 80],
+    /// The unique id of the device, often its serial or its Bluetooth address,
+    /// empty when it has none
+    pub uniq: [u8; 64],
+    /// Where the device is plugged in on the host, empty when it doesn't say
+    pub phys: [u8; 64],
 }
 
+/// Replaces the initial `AddDevice` messages when the server uses compact
+/// snapshots, followed by `len` bytes produced by [`encode_snapshot`].
 #[repr(C)]
 #[derive(Debug)]
-pub struct RemoveDevice {
+pub struct DeviceSnapshot {
+    pub count: u32,
+    pub len: u32,
+}
+
+/// Sent by the server right after its hello. Clients put the tag in the phys of
+/// the devices they create, so a server running on the same machine as its
+/// clients can tell them apart from real devices and doesn't forward them again.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceTag {
+    pub tag: u64,
+}
+
+/// Sent as both a ping and its pong, the pong carries the `seq` of the ping it
+/// answers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Ping {
+    pub seq: u64,
+}
+
+/// Start of the phys of every device created by a tagged client. The server
+/// doesn't guess whether devices carrying it are joysticks, whichever server
+/// they came from.
+pub const PHYS_MARKER: &str = "hidpipe:";
+
+pub fn instance_tag_phys(tag: u64) -> String {
+    format!("{}{:016x}", PHYS_MARKER, tag)
+}
+
+/// The axis and button mapping of the joystick node of a device on the host,
+/// see [`joydev`].
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct JoystickMap {
     pub id: u64,
+    pub axes: u8,
+    pub buttons: u8,
+    pub axmap: [u8; joydev::AXMAP_LEN],
+    pub btnmap: [u16; joydev::BTNMAP_LEN],
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateAbsInfo {
+    pub id: u64,
+    pub axis: u32,
+    pub info: AbsoluteInfo,
+}
+
+/// Followed by `count` [`InputEvent`]s of device `id`, which the client writes
+/// to the device at once.
 #[repr(C)]
 #[derive(Debug)]
+pub struct InputFrame {
+    pub id: u64,
+    pub count: u64,
+}
+
+/// The first protocol version whose servers take a [`Subscribe`].
+pub const SUBSCRIBE_VERSION: u32 = 0x0402;
+
+/// The most models a [`Subscribe`] can list.
+pub const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// Sent by a client after the hello to only get the devices whose vendor and
+/// product ids are among the first `count` of `ids`. Devices it already got
+/// that don't match are removed. Clients that never send one get every
+/// device, as do those that send an empty list.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Subscribe {
+    pub count: u32,
+    /// Vendor and product ids
+    pub ids: [[u16; 2]; MAX_SUBSCRIPTIONS],
+}
+
+impl Subscribe {
+    /// Whether a device with the given id is one the client wants.
+    pub fn matches(&self, id: &InputId) -> bool {
+        let count = (self.count as usize).min(MAX_SUBSCRIPTIONS);
+        count == 0 || self.ids[..count].contains(&[id.vendor, id.product])
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct RemoveDevice {
+    pub id: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
 pub struct InputEvent {
     pub time_sec: i64,
     pub time_usec: i64,
@@ -105,6 +265,20 @@ impl InputEvent {
     }
 }
 
+/// Terminates a burst of events the server made up (as opposed to events read
+/// from a device). Every such burst has to end with one, otherwise the guest
+/// does not commit the state until the device sends a report of its own.
+pub fn syn_report(id: u64) -> InputEvent {
+    InputEvent {
+        time_sec: 0,
+        time_usec: 0,
+        id,
+        value: 0,
+        ty: EventKind::Synchronize as u16,
+        code: SynchronizeKind::Report as u16,
+    }
+}
+
 pub fn empty_input_event() -> input_event {
     input_event {
         time: timeval {
@@ -117,10 +291,216 @@ pub fn empty_input_event() -> input_event {
     }
 }
 
-pub fn struct_to_socket<T>(socket: &mut UnixStream, data: &T) -> Result<()> {
+/// A connection the protocol can run over. A vsock connection is a
+/// `UnixStream` made from the connected socket, both behave the same.
+pub trait Transport: Read + Write + AsFd + AsRawFd {}
+
+impl Transport for UnixStream {}
+impl Transport for TcpStream {}
+
+/// The version of the crate, with the commit it was built from when the
+/// build knew it.
+pub fn version() -> String {
+    match option_env!("HIDPIPE_GIT_HASH") {
+        Some(hash) => format!("{} ({})", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// Handles `--help` and `--version`, which every binary takes and which exit
+/// right away.
+pub fn common_flag(arg: &str, name: &str, usage: &str) {
+    match arg {
+        "--help" => {
+            println!("{}", usage);
+            std::process::exit(0);
+        }
+        "--version" => {
+            println!("{} {}", name, version());
+            std::process::exit(0);
+        }
+        _ => {}
+    }
+}
+
+pub fn struct_as_bytes<T>(data: &T) -> &[u8] {
     let size = mem::size_of::<T>();
     // SAFETY:
     // We are taking a ref, so it is valid for reads, properly aligned, and nobody can write to it
-    let v = unsafe { slice::from_raw_parts(data as *const T as *const u8, size) };
-    socket.write_all(v)
+    unsafe { slice::from_raw_parts(data as *const T as *const u8, size) }
+}
+
+pub fn struct_to_socket<T>(socket: &mut (impl Write + ?Sized), data: &T) -> Result<()> {
+    socket.write_all(struct_as_bytes(data))?;
+    Ok(())
+}
+
+/// Size of the header in front of every message: its type and the length of its
+/// payload, as little endian `u32`s.
+pub const HEADER_SIZE: usize = 8;
+
+/// The header of a message of type `ty` with `len` bytes of payload.
+pub fn message_header(ty: MessageType, len: usize) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&(ty as u32).to_le_bytes());
+    header[4..].copy_from_slice(&(len as u32).to_le_bytes());
+    header
+}
+
+/// The type and payload length in a message header.
+pub fn parse_header(header: &[u8; HEADER_SIZE]) -> (u32, usize) {
+    let ty = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap());
+    (ty, len as usize)
+}
+
+/// Fails unless a message of type `ty` came with the `expected` payload length,
+/// so peers that disagree on the layout of a message hang up instead of reading
+/// garbage from then on.
+pub fn check_len(ty: u32, len: usize, expected: usize) -> Result<()> {
+    if len != expected {
+        return Err(Error::Protocol(format!(
+            "message {} of {} bytes where {} were expected",
+            ty, len, expected
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the message header and its payload with as few syscalls as possible,
+/// so the peer never wakes up for a header without the data that follows it.
+pub fn message_to_socket<T>(
+    socket: &mut (impl Write + ?Sized),
+    ty: MessageType,
+    data: &T,
+) -> Result<()> {
+    message_bytes_to_socket(socket, ty, struct_as_bytes(data))
+}
+
+pub fn message_bytes_to_socket(
+    socket: &mut (impl Write + ?Sized),
+    ty: MessageType,
+    payload: &[u8],
+) -> Result<()> {
+    let header = message_header(ty, payload.len());
+    write_all_vectored(socket, &mut [IoSlice::new(&header), IoSlice::new(payload)])
+}
+
+fn write_all_vectored(socket: &mut (impl Write + ?Sized), bufs: &mut [IoSlice]) -> Result<()> {
+    let mut bufs = bufs;
+    while !bufs.is_empty() {
+        match socket.write_vectored(bufs) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+            Ok(written) => IoSlice::advance_slices(&mut bufs, written),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a `T` that was sent with [`struct_to_socket`].
+///
+/// # Safety
+///
+/// Any bit pattern must be a valid `T`.
+pub unsafe fn struct_from_socket<T>(socket: &mut (impl Read + ?Sized)) -> Result<T> {
+    let mut data = mem::MaybeUninit::<T>::zeroed();
+    let v = slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+    socket.read_exact(v)?;
+    Ok(data.assume_init())
+}
+
+const RLE_ZEROS: u8 = 0x80;
+
+/// Upper bound for a snapshot, both as sent and once decoded. A record is a few
+/// kilobytes, so this leaves room for hundreds of devices.
+pub const MAX_SNAPSHOT_SIZE: usize = 4 << 20;
+
+/// Upper bound for any fixed size message, peers never need to buffer more.
+pub const MAX_MESSAGE_SIZE: usize = 64 << 10;
+
+/// Packs device records (an `AddDevice` followed by its `AbsoluteInfo`s) for a
+/// [`DeviceSnapshot`]. Each record is XORed with the previous one and runs of
+/// zeros are squeezed, so identical controllers cost a handful of bytes.
+pub fn encode_snapshot(records: &[Vec<u8>]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    let mut prev: &[u8] = &[];
+    for record in records {
+        raw.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        raw.extend(
+            record
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ prev.get(i).unwrap_or(&0)),
+        );
+        prev = record;
+    }
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < raw.len() {
+        let zeros = raw[i..].iter().take(0x80).take_while(|b| **b == 0).count();
+        if zeros > 0 {
+            out.push(RLE_ZEROS | (zeros - 1) as u8);
+            i += zeros;
+        } else {
+            let literal = raw[i..].iter().take(0x80).take_while(|b| **b != 0).count();
+            out.push((literal - 1) as u8);
+            out.extend_from_slice(&raw[i..i + literal]);
+            i += literal;
+        }
+    }
+    out
+}
+
+/// Reverses [`encode_snapshot`], returns `None` if the data is corrupt or
+/// decodes to more than [`MAX_SNAPSHOT_SIZE`].
+pub fn decode_snapshot(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut raw = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        let run = (control & !RLE_ZEROS) as usize + 1;
+        if raw.len() + run > MAX_SNAPSHOT_SIZE {
+            return None;
+        }
+        i += 1;
+        if control & RLE_ZEROS != 0 {
+            raw.resize(raw.len() + run, 0);
+        } else {
+            raw.extend_from_slice(data.get(i..i + run)?);
+            i += run;
+        }
+    }
+    let mut records: Vec<Vec<u8>> = Vec::new();
+    let mut rest = &raw[..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let delta = rest.get(4..4 + len)?;
+        let prev = records.last().map(|r| &r[..]).unwrap_or(&[]);
+        let record = delta
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ prev.get(i).unwrap_or(&0))
+            .collect();
+        records.push(record);
+        rest = &rest[4 + len..];
+    }
+    Some(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input_linux::sys::{EV_SYN, SYN_REPORT};
+
+    #[test]
+    fn syn_report_commits_the_state() {
+        let ev = syn_report(3).to_input_event();
+        assert_eq!(
+            (ev.type_, ev.code, ev.value),
+            (EV_SYN as u16, SYN_REPORT as u16, 0)
+        );
+        assert_eq!(syn_report(3).id, 3);
+    }
 }
@@ -0,0 +1,291 @@
+//! Whole messages, read and written with their header. The byte order and the
+//! length checks are handled here, so users only see native structs.
+
+use crate::{
+    check_len, device, parse_header, struct_as_bytes, struct_from_socket, AddDevice,
+    DeviceSnapshot, Error, FFErase, FFUpload, InputEvent, InputFrame, InstanceTag, JoystickMap,
+    MessageType, Padded, Ping, RemoveDevice, Result, Subscribe, UpdateAbsInfo, Wire, HEADER_SIZE,
+    MAX_SNAPSHOT_SIZE,
+};
+use input_linux::AbsoluteInfo;
+use std::io::{IoSlice, Read, Write};
+use std::{mem, ptr};
+
+#[derive(Debug)]
+pub enum Message {
+    AddDevice(Box<AddDevice>, Vec<AbsoluteInfo>),
+    RemoveDevice(u64),
+    InputEvent(InputEvent),
+    FFUpload(Box<FFUpload>),
+    FFErase(FFErase),
+    /// The number of records and the data made by [`crate::encode_snapshot`]
+    DeviceSnapshot(u32, Vec<u8>),
+    InstanceTag(InstanceTag),
+    UpdateDevice(Box<AddDevice>, Vec<AbsoluteInfo>),
+    JoystickMap(Box<JoystickMap>),
+    Ping(Ping),
+    Pong(Ping),
+    UpdateAbsInfo(UpdateAbsInfo),
+    /// The id of the device and its events
+    InputFrame(u64, Vec<InputEvent>),
+    Subscribe(Subscribe),
+    /// A message of a type this version doesn't know, and its payload
+    Unknown(u32, Vec<u8>),
+}
+
+const ADD_DEVICE: u32 = MessageType::AddDevice as u32;
+const REMOVE_DEVICE: u32 = MessageType::RemoveDevice as u32;
+const INPUT_EVENT: u32 = MessageType::InputEvent as u32;
+const FF_UPLOAD: u32 = MessageType::FFUpload as u32;
+const FF_ERASE: u32 = MessageType::FFErase as u32;
+const DEVICE_SNAPSHOT: u32 = MessageType::DeviceSnapshot as u32;
+const INSTANCE_TAG: u32 = MessageType::InstanceTag as u32;
+const UPDATE_DEVICE: u32 = MessageType::UpdateDevice as u32;
+const JOYSTICK_MAP: u32 = MessageType::JoystickMap as u32;
+const PING: u32 = MessageType::Ping as u32;
+const PONG: u32 = MessageType::Pong as u32;
+const UPDATE_ABS_INFO: u32 = MessageType::UpdateAbsInfo as u32;
+const INPUT_FRAME: u32 = MessageType::InputFrame as u32;
+const SUBSCRIBE: u32 = MessageType::Subscribe as u32;
+
+/// Reads a `T` from the front of a payload.
+fn take<T>(payload: &mut &[u8]) -> Result<T> {
+    // SAFETY: only used for the message structs, which are made of integers
+    // and arrays of them
+    unsafe { struct_from_socket(payload) }
+}
+
+/// The payload length a message of type `ty` should have, going by what the
+/// start of its payload says about itself. Messages of unknown types are
+/// whatever their header says.
+pub fn payload_len(ty: u32, payload: &[u8]) -> usize {
+    match ty {
+        ADD_DEVICE | UPDATE_DEVICE => {
+            let Some(add_dev) = payload.get(..mem::size_of::<AddDevice>()) else {
+                return mem::size_of::<AddDevice>();
+            };
+            // SAFETY: AddDevice is made of integers and arrays of them
+            let add_dev = unsafe { ptr::read_unaligned(add_dev.as_ptr() as *const AddDevice) };
+            let axes = device::set_bits(add_dev.absbits.as_ref()).count();
+            mem::size_of::<AddDevice>() + axes * mem::size_of::<AbsoluteInfo>()
+        }
+        DEVICE_SNAPSHOT => {
+            let Some(snapshot) = payload.get(..mem::size_of::<DeviceSnapshot>()) else {
+                return mem::size_of::<DeviceSnapshot>();
+            };
+            // SAFETY: DeviceSnapshot is made of integers
+            let snapshot =
+                unsafe { ptr::read_unaligned(snapshot.as_ptr() as *const DeviceSnapshot) }
+                    .into_native();
+            mem::size_of::<DeviceSnapshot>() + snapshot.len as usize
+        }
+        INPUT_FRAME => {
+            let Some(frame) = payload.get(..mem::size_of::<InputFrame>()) else {
+                return mem::size_of::<InputFrame>();
+            };
+            // SAFETY: InputFrame is made of integers
+            let frame =
+                unsafe { ptr::read_unaligned(frame.as_ptr() as *const InputFrame) }.into_native();
+            // Saturating, a bogus count must fail the length check, not overflow
+            (frame.count as usize)
+                .saturating_mul(mem::size_of::<InputEvent>())
+                .saturating_add(mem::size_of::<InputFrame>())
+        }
+        REMOVE_DEVICE => mem::size_of::<RemoveDevice>(),
+        INPUT_EVENT => mem::size_of::<InputEvent>(),
+        FF_UPLOAD => mem::size_of::<FFUpload>(),
+        FF_ERASE => mem::size_of::<FFErase>(),
+        INSTANCE_TAG => mem::size_of::<InstanceTag>(),
+        JOYSTICK_MAP => mem::size_of::<JoystickMap>(),
+        PING | PONG => mem::size_of::<Ping>(),
+        UPDATE_ABS_INFO => mem::size_of::<UpdateAbsInfo>(),
+        SUBSCRIBE => mem::size_of::<Subscribe>(),
+        _ => payload.len(),
+    }
+}
+
+/// Checks the header of a message before its payload is buffered. Messages of
+/// the types that always have the same length must have it, the others can't
+/// be larger than a snapshot, the largest messages.
+pub fn check_header(ty: u32, len: usize) -> Result<()> {
+    match ty {
+        REMOVE_DEVICE | INPUT_EVENT | FF_UPLOAD | FF_ERASE | INSTANCE_TAG | JOYSTICK_MAP | PING
+        | PONG | UPDATE_ABS_INFO | SUBSCRIBE => check_len(ty, len, payload_len(ty, &[])),
+        _ if len > mem::size_of::<DeviceSnapshot>() + MAX_SNAPSHOT_SIZE => {
+            Err(Error::Protocol(format!("message of {} bytes", len)))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Message {
+    /// Decodes the whole payload of a message of type `ty`, failing if it
+    /// doesn't have the length that type takes.
+    pub fn decode(ty: u32, payload: &[u8]) -> Result<Message> {
+        check_len(ty, payload.len(), payload_len(ty, payload))?;
+        let mut payload = payload;
+        let payload = &mut payload;
+        Ok(match ty {
+            ADD_DEVICE | UPDATE_DEVICE => {
+                let (add_dev, abs) = device::read_record(payload)?;
+                if ty == ADD_DEVICE {
+                    Message::AddDevice(Box::new(add_dev), abs)
+                } else {
+                    Message::UpdateDevice(Box::new(add_dev), abs)
+                }
+            }
+            DEVICE_SNAPSHOT => {
+                let snapshot = take::<DeviceSnapshot>(payload)?.into_native();
+                if snapshot.len as usize > MAX_SNAPSHOT_SIZE {
+                    return Err(Error::Protocol(format!(
+                        "device snapshot of {} bytes",
+                        snapshot.len
+                    )));
+                }
+                Message::DeviceSnapshot(snapshot.count, payload.to_vec())
+            }
+            INPUT_FRAME => {
+                let frame = take::<InputFrame>(payload)?.into_native();
+                let events = (0..frame.count)
+                    .map(|_| Ok(take::<InputEvent>(payload)?.into_native()))
+                    .collect::<Result<_>>()?;
+                Message::InputFrame(frame.id, events)
+            }
+            REMOVE_DEVICE => Message::RemoveDevice(take::<RemoveDevice>(payload)?.into_native().id),
+            INPUT_EVENT => Message::InputEvent(take::<InputEvent>(payload)?.into_native()),
+            FF_UPLOAD => Message::FFUpload(Box::new(take::<FFUpload>(payload)?.into_native())),
+            FF_ERASE => Message::FFErase(take::<FFErase>(payload)?.into_native()),
+            INSTANCE_TAG => Message::InstanceTag(take::<InstanceTag>(payload)?.into_native()),
+            JOYSTICK_MAP => {
+                Message::JoystickMap(Box::new(take::<JoystickMap>(payload)?.into_native()))
+            }
+            PING => Message::Ping(take::<Ping>(payload)?.into_native()),
+            PONG => Message::Pong(take::<Ping>(payload)?.into_native()),
+            UPDATE_ABS_INFO => {
+                Message::UpdateAbsInfo(take::<UpdateAbsInfo>(payload)?.into_native())
+            }
+            SUBSCRIBE => Message::Subscribe(take::<Subscribe>(payload)?.into_native()),
+            ty => Message::Unknown(ty, payload.to_vec()),
+        })
+    }
+
+    /// The type of the message and its payload as it goes on the wire.
+    pub fn encode(&self) -> (u32, Vec<u8>) {
+        let record = |add_dev: &AddDevice, abs: &[AbsoluteInfo]| {
+            let mut record = add_dev.clone().into_wire().to_bytes();
+            for info in abs {
+                record.extend_from_slice(struct_as_bytes(&info.into_wire()));
+            }
+            record
+        };
+        match self {
+            Message::AddDevice(add_dev, abs) => (ADD_DEVICE, record(add_dev, abs)),
+            Message::UpdateDevice(add_dev, abs) => (UPDATE_DEVICE, record(add_dev, abs)),
+            Message::RemoveDevice(id) => (
+                REMOVE_DEVICE,
+                struct_as_bytes(&RemoveDevice { id: *id }.into_wire()).to_vec(),
+            ),
+            Message::InputEvent(ev) => (INPUT_EVENT, struct_as_bytes(&ev.into_wire()).to_vec()),
+            Message::FFUpload(upload) => (FF_UPLOAD, upload.into_wire().to_bytes()),
+            Message::FFErase(erase) => (FF_ERASE, struct_as_bytes(&erase.into_wire()).to_vec()),
+            Message::DeviceSnapshot(count, data) => {
+                let snapshot = DeviceSnapshot {
+                    count: *count,
+                    len: data.len() as u32,
+                };
+                let mut payload = struct_as_bytes(&snapshot.into_wire()).to_vec();
+                payload.extend_from_slice(data);
+                (DEVICE_SNAPSHOT, payload)
+            }
+            Message::InstanceTag(tag) => (INSTANCE_TAG, struct_as_bytes(&tag.into_wire()).to_vec()),
+            Message::JoystickMap(map) => (JOYSTICK_MAP, map.clone().into_wire().to_bytes()),
+            Message::Ping(ping) => (PING, struct_as_bytes(&ping.into_wire()).to_vec()),
+            Message::Pong(ping) => (PONG, struct_as_bytes(&ping.into_wire()).to_vec()),
+            Message::UpdateAbsInfo(update) => (UPDATE_ABS_INFO, update.into_wire().to_bytes()),
+            Message::InputFrame(id, events) => {
+                let frame = InputFrame {
+                    id: *id,
+                    count: events.len() as u64,
+                };
+                let mut payload = struct_as_bytes(&frame.into_wire()).to_vec();
+                for ev in events {
+                    payload.extend_from_slice(struct_as_bytes(&ev.into_wire()));
+                }
+                (INPUT_FRAME, payload)
+            }
+            Message::Subscribe(subscribe) => {
+                (SUBSCRIBE, struct_as_bytes(&subscribe.into_wire()).to_vec())
+            }
+            Message::Unknown(ty, payload) => (*ty, payload.clone()),
+        }
+    }
+}
+
+/// Reads the next message, waiting until it arrived whole.
+pub fn read_message(socket: &mut (impl Read + ?Sized)) -> Result<Message> {
+    let mut header = [0u8; HEADER_SIZE];
+    socket.read_exact(&mut header)?;
+    let (ty, len) = parse_header(&header);
+    check_header(ty, len)?;
+    let mut payload = vec![0u8; len];
+    socket.read_exact(&mut payload)?;
+    Message::decode(ty, &payload)
+}
+
+/// Writes a message with its header, like [`crate::message_bytes_to_socket`].
+pub fn write_message(socket: &mut (impl Write + ?Sized), message: &Message) -> Result<()> {
+    let (ty, payload) = message.encode();
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&ty.to_le_bytes());
+    header[4..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    let mut bufs = [IoSlice::new(&header), IoSlice::new(&payload)];
+    crate::write_all_vectored(socket, &mut bufs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record with `axes` axes, and its length.
+    fn record(axes: u8) -> Vec<u8> {
+        // SAFETY: AddDevice is made of integers and arrays of them
+        let mut add_dev: AddDevice = unsafe { mem::zeroed() };
+        add_dev.absbits[0] = (1u16 << axes).wrapping_sub(1) as u8;
+        let mut record = add_dev.into_wire().to_bytes();
+        record.resize(
+            record.len() + axes as usize * mem::size_of::<AbsoluteInfo>(),
+            0,
+        );
+        record
+    }
+
+    #[test]
+    fn record_length() {
+        for axes in [0, 3] {
+            let record = record(axes);
+            assert_eq!(payload_len(ADD_DEVICE, &record), record.len());
+            assert!(Message::decode(ADD_DEVICE, &record).is_ok());
+            let mut long = record.clone();
+            long.push(0);
+            assert!(Message::decode(ADD_DEVICE, &long).is_err());
+            assert!(Message::decode(ADD_DEVICE, &record[..record.len() - 1]).is_err());
+        }
+        // Too short to say how many axes follow
+        assert_eq!(
+            payload_len(ADD_DEVICE, &[0; 8]),
+            mem::size_of::<AddDevice>()
+        );
+    }
+
+    #[test]
+    fn headers() {
+        let event = mem::size_of::<InputEvent>();
+        assert!(check_header(INPUT_EVENT, event).is_ok());
+        assert!(check_header(INPUT_EVENT, 4 << 20).is_err());
+        assert!(check_header(PING, 0).is_err());
+        // Records and snapshots only have an upper bound until their payload is in
+        assert!(check_header(ADD_DEVICE, 4 << 20).is_ok());
+        assert!(check_header(DEVICE_SNAPSHOT, 8 << 20).is_err());
+        assert!(check_header(0xffff, 16).is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+use hidpipe::{error, info, warn};
+use nix::sys::socket::{
+    accept, bind, listen, socket, AddressFamily, Backlog, SockFlag, SockType, VsockAddr,
+};
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::thread;
+
+/// The port the client connects to.
+const DEFAULT_PORT: u32 = 3334;
+
+const USAGE: &str = "\
+Usage: hidpipe-proxy [--port <vsock port>] [<server socket>]
+       hidpipe-proxy --help | --version";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+/// Copies everything from `from` to `to`, then tells `to` nothing else is coming.
+fn pipe(mut from: UnixStream, mut to: UnixStream) {
+    let mut buf = [0u8; 16 << 10];
+    loop {
+        let len = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if to.write_all(&buf[..len]).is_err() {
+            break;
+        }
+    }
+    _ = to.shutdown(Shutdown::Write);
+    _ = from.shutdown(Shutdown::Read);
+}
+
+fn forward(guest: UnixStream, server_path: &str) -> io::Result<()> {
+    let server = UnixStream::connect(server_path)?;
+    let (guest_read, server_read) = (guest.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || pipe(guest_read, server));
+    pipe(server_read, guest);
+    upstream.join().unwrap();
+    Ok(())
+}
+
+fn main() {
+    hidpipe::log::init();
+    let mut port = DEFAULT_PORT;
+    let mut server_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        hidpipe::common_flag(&arg, "hidpipe-proxy", USAGE);
+        match arg.as_str() {
+            "--port" => {
+                port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ if server_path.is_none() => server_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let server_path = server_path.unwrap_or_else(|| match env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) => format!("{}/hidpipe", dir),
+        Err(e) => {
+            error!("Unable to get XDG_RUNTIME_DIR, error: {:?}", e);
+            process::exit(1);
+        }
+    });
+
+    let listener = socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .unwrap();
+    bind(
+        listener.as_raw_fd(),
+        &VsockAddr::new(libc::VMADDR_CID_ANY, port),
+    )
+    .unwrap();
+    listen(&listener, Backlog::new(16).unwrap()).unwrap();
+    info!("Forwarding vsock port {} to {}", port, server_path);
+    loop {
+        let fd = match accept(listener.as_raw_fd()) {
+            Ok(fd) => fd,
+            Err(e) => {
+                warn!("Failed to accept a connection, error: {}", e);
+                continue;
+            }
+        };
+        // SAFETY: the fd was just accepted and nothing else owns it
+        let guest = unsafe { UnixStream::from_raw_fd(fd) };
+        let server_path = server_path.clone();
+        // Each connection is a client, which lives as long as its VM
+        thread::spawn(move || {
+            if let Err(e) = forward(guest, &server_path) {
+                warn!("Unable to reach the server, error: {:?}", e);
+            }
+        });
+    }
+}
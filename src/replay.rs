@@ -0,0 +1,194 @@
+use hidpipe::device::{self, Overrides};
+use hidpipe::trace::parse_line;
+use input_linux::{
+    AbsoluteAxis, EvdevHandle, EventKind, Key, LedKind, MiscKind, RelativeAxis, SwitchKind,
+    UInputHandle,
+};
+use input_linux_sys::{input_absinfo, input_id, uinput_abs_setup, uinput_setup, BUS_VIRTUAL};
+use libc::c_char;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a traced device has to support to replay its events. The trace doesn't
+/// have the real capabilities, so they are guessed from the events it contains.
+#[derive(Default)]
+struct Capabilities {
+    codes: BTreeSet<(u16, u16)>,
+    abs_ranges: BTreeMap<u16, (i32, i32)>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: hidpipe-replay [--device <id>=<device file>]... <trace file>");
+    eprintln!("       hidpipe-replay --dump-device <event node> > <device file>");
+    process::exit(1);
+}
+
+/// Saves the record of a device, for `--device` to create an exact copy of it.
+fn dump_device(node: &str) {
+    let evdev = EvdevHandle::new(File::open(node).unwrap());
+    io::stdout()
+        .write_all(&device::record(&evdev, 0).unwrap())
+        .unwrap();
+}
+
+fn guess_device(id: u64, caps: &Capabilities) -> UInputHandle<File> {
+    let uinput = UInputHandle::new(
+        File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/uinput")
+            .unwrap(),
+    );
+    let mut kinds = BTreeSet::new();
+    for &(ty, code) in &caps.codes {
+        let kind = match EventKind::try_from(ty) {
+            Ok(kind) => kind,
+            Err(_) => continue,
+        };
+        let res = match kind {
+            EventKind::Key => Key::from_code(code).map(|key| uinput.set_keybit(key)),
+            EventKind::Relative => RelativeAxis::from_code(code).map(|rel| uinput.set_relbit(rel)),
+            EventKind::Misc => MiscKind::from_code(code).map(|msc| uinput.set_mscbit(msc)),
+            EventKind::Led => LedKind::from_code(code).map(|led| uinput.set_ledbit(led)),
+            EventKind::Switch => SwitchKind::from_code(code).map(|sw| uinput.set_swbit(sw)),
+            EventKind::Absolute => match AbsoluteAxis::from_code(code) {
+                Ok(axis) => {
+                    let (minimum, maximum) = caps.abs_ranges[&code];
+                    uinput.set_absbit(axis).unwrap();
+                    Ok(uinput.abs_setup(&uinput_abs_setup {
+                        code,
+                        absinfo: input_absinfo {
+                            value: minimum,
+                            minimum,
+                            maximum: maximum.max(minimum + 1),
+                            fuzz: 0,
+                            flat: 0,
+                            resolution: 0,
+                        },
+                    }))
+                }
+                Err(e) => Err(e),
+            },
+            _ => continue,
+        };
+        match res {
+            Ok(res) => {
+                res.unwrap();
+                kinds.insert(kind);
+            }
+            Err(_) => eprintln!(
+                "Device {} uses unknown code {}:{}, ignoring it",
+                id, ty, code
+            ),
+        }
+    }
+    for kind in kinds {
+        uinput.set_evbit(kind).unwrap();
+    }
+    let mut name = [0 as c_char; 80];
+    for (dst, src) in name
+        .iter_mut()
+        .zip(format!("hidpipe replay {}", id).bytes())
+    {
+        *dst = src as c_char;
+    }
+    uinput
+        .dev_setup(&uinput_setup {
+            id: input_id {
+                bustype: BUS_VIRTUAL,
+                vendor: 0,
+                product: 0,
+                version: 0,
+            },
+            name,
+            ff_effects_max: 0,
+        })
+        .unwrap();
+    uinput.dev_create().unwrap();
+    uinput
+}
+
+fn main() {
+    let mut records = BTreeMap::new();
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump-device" => {
+                dump_device(&args.next().unwrap_or_else(|| usage()));
+                return;
+            }
+            "--device" => {
+                let arg = args.next().unwrap_or_else(|| usage());
+                let (id, record) = arg.split_once('=').unwrap_or_else(|| usage());
+                let id = id.parse::<u64>().unwrap_or_else(|_| usage());
+                records.insert(id, record.to_string());
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let events: Vec<_> = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .filter_map(parse_line)
+        .collect();
+    if events.is_empty() {
+        eprintln!("{} does not contain any event", path);
+        process::exit(1);
+    }
+
+    let mut caps = BTreeMap::<u64, Capabilities>::new();
+    for ev in &events {
+        let dev = caps.entry(ev.id).or_default();
+        dev.codes.insert((ev.ty, ev.code));
+        if ev.ty == EventKind::Absolute as u16 {
+            let range = dev
+                .abs_ranges
+                .entry(ev.code)
+                .or_insert((ev.value, ev.value));
+            range.0 = range.0.min(ev.value);
+            range.1 = range.1.max(ev.value);
+        }
+    }
+    // Devices with a record are created exactly like the client would
+    let devices: BTreeMap<u64, UInputHandle<File>> = caps
+        .iter()
+        .map(|(id, caps)| match records.get(id) {
+            Some(record) => {
+                let mut record = File::open(record).unwrap();
+                let (add_dev, abs) = device::read_record(&mut record).unwrap();
+                let uinput = device::create_uinput(&add_dev, &abs, &Overrides::default()).unwrap();
+                (*id, uinput)
+            }
+            None => (*id, guess_device(*id, caps)),
+        })
+        .collect();
+    // Give udev and whatever listens to the devices a chance to pick them up
+    thread::sleep(Duration::from_secs(1));
+
+    let usecs = |ev: &hidpipe::InputEvent| ev.time_sec * 1_000_000 + ev.time_usec;
+    let first = usecs(&events[0]);
+    let start = Instant::now();
+    for ev in &events {
+        let due = start + Duration::from_micros((usecs(ev) - first).max(0) as u64);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        devices[&ev.id].write(&[ev.to_input_event()]).unwrap();
+    }
+    for dev in devices.values() {
+        dev.dev_destroy().unwrap();
+    }
+    println!(
+        "Replayed {} events on {} devices",
+        events.len(),
+        devices.len()
+    );
+}
@@ -1,130 +1,974 @@
-use input_linux::{evdev::EvdevHandle, AbsoluteAxis, EventKind, InputProperty, Key, MiscKind};
+use input_linux::sys::{input_event, BUS_VIRTUAL};
+use input_linux::{
+    evdev::EvdevHandle, AbsoluteAxis, AbsoluteInfo, Bitmask, EventKind, ForceFeedbackKind, InputId,
+    Key, LedKind, MiscKind, SwitchKind, SynchronizeKind,
+};
 use nix::errno::Errno;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
-use std::collections::hash_map;
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsStr;
-use std::io::{ErrorKind, Read, Result};
-use std::net::Shutdown;
-use std::os::fd::{AsRawFd, FromRawFd};
-use std::os::unix::fs::OpenOptionsExt;
+use nix::sys::socket::{self, AddressFamily, Backlog, SockFlag, SockType, VsockAddr};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind, Read, Result, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{chown, OpenOptionsExt, PermissionsExt};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{
     env,
     fs::{self, File},
-    mem,
+    mem, process, ptr,
 };
 use udev::{EventType, MonitorBuilder};
 
+use hidpipe::control::{self, Command};
+use hidpipe::device::{
+    self, has_autocenter, is_motion_sensor, is_touchpad, Capabilities, DeviceClass,
+};
+use hidpipe::joydev;
+use hidpipe::log::{self, Level};
+use hidpipe::signal;
+use hidpipe::touchpad::{self, Mouse};
+use hidpipe::trace::Trace;
 use hidpipe::{
-    empty_input_event, struct_to_socket, AddDevice, ClientHello, FFErase, FFUpload, InputEvent,
-    MessageType, RemoveDevice, ServerHello,
+    check_len, empty_input_event, encode_snapshot, instance_tag_phys, message_header, parse_header,
+    protocol_major, struct_as_bytes, syn_report, AddDevice, ClientHello, DeviceSnapshot, Error,
+    FFErase, FFUpload, InputEvent, InputFrame, InstanceTag, MessageType, Padded, Ping,
+    RemoveDevice, ServerHello, Subscribe, Transport, UpdateAbsInfo, Wire, HEADER_SIZE,
+    HELLO_INSTANCE_TAG, HELLO_JSON, HELLO_PING, HELLO_VERSION_MASK, MAX_SNAPSHOT_SIZE, PHYS_MARKER,
+    PING_INTERVAL, PING_TIMEOUT, PROTOCOL_VERSION,
 };
+use hidpipe::{debug, error, info, trace, warn};
 use nix::unistd::getresuid;
 
-fn is_joystick<F: AsRawFd>(evdev: &EvdevHandle<F>) -> Result<bool> {
-    let props = evdev.device_properties()?;
-    let no = Ok(false);
-    if props.get(InputProperty::Accelerometer)
-        || props.get(InputProperty::PointingStick)
-        || props.get(InputProperty::TopButtonPad)
-        || props.get(InputProperty::ButtonPad)
-        || props.get(InputProperty::SemiMultiTouch)
+fn trim_nul(mut s: Vec<u8>) -> Vec<u8> {
+    while s.last() == Some(&0) {
+        s.pop();
+    }
+    s
+}
+
+/// The node, name and ids of a device, for the logs.
+fn describe<F: AsRawFd>(dev_name: &str, evdev: &EvdevHandle<F>) -> io::Result<String> {
+    let name = trim_nul(evdev.device_name()?);
+    let id = evdev.device_id()?;
+    Ok(format!(
+        "{} ({}, {:04x}:{:04x})",
+        dev_name,
+        String::from_utf8_lossy(&name),
+        id.vendor,
+        id.product
+    ))
+}
+
+/// Binds a Unix socket that ends up with permissions `mode`. Whoever can
+/// connect gets the input of the forwarded devices, so the socket is created
+/// with only the owner allowed, rather than what the umask says until the
+/// permissions are set.
+fn bind_private(path: impl AsRef<Path>, mode: u32) -> Result<UnixListener> {
+    // SAFETY: umask can't fail. The server has a single thread, so nothing
+    // else creates files meanwhile
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    // SAFETY: as above
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Whether a device was created by another hidpipe or a tool like it, which
+/// would forward the device right back. `markers` are the phys prefixes of
+/// such devices.
+fn is_virtual(markers: &[String], dev: &impl Capabilities) -> Result<bool> {
+    if dev.device_id()?.bustype == BUS_VIRTUAL {
+        return Ok(true);
+    }
+    let phys = dev.physical_location()?;
+    Ok(markers
+        .iter()
+        .any(|marker| phys.starts_with(marker.as_bytes())))
+}
+
+/// The name and path of the event node udev told us about. The devnode can be
+/// a link (`/dev/input/by-id/...`) in some setups, the device is known by the
+/// node it points to.
+fn resolve_node(sysname: &OsStr, devnode: &Path) -> (OsString, PathBuf) {
+    match fs::canonicalize(devnode) {
+        Ok(node) => {
+            let name = node.file_name().unwrap_or(sysname).to_owned();
+            (name, node)
+        }
+        Err(_) => (sysname.to_owned(), devnode.to_owned()),
+    }
+}
+
+/// Builds the `AddDevice` message for a device, followed by the `AbsoluteInfo` of each axis.
+fn add_device_record<F: AsRawFd>(evdev: &EvdevHandle<F>, id: u64) -> Result<Vec<u8>> {
+    device::record(evdev, id)
+}
+
+fn send_add_device(evdevs: &EvdevContainer, id: u64, client: &mut Client) -> hidpipe::Result<()> {
+    send_device_record(evdevs, id, MessageType::AddDevice, client)
+}
+
+fn send_device_record(
+    evdevs: &EvdevContainer,
+    id: u64,
+    ty: MessageType,
+    client: &mut Client,
+) -> hidpipe::Result<()> {
+    let evdev = evdevs.get(id).unwrap();
+    let input_id = evdev.device_id().map_err(|e| Error::from_device(id, e))?;
+    if !client.wants(&input_id) {
+        // A device that changed into a model the client doesn't want is gone
+        if ty == MessageType::UpdateDevice {
+            client.write_removal(id)?;
+        }
+        client.hidden.insert(id);
+        return Ok(());
+    }
+    // And one that changed into a model it wants is new
+    let ty = if client.has(id) {
+        ty
+    } else {
+        MessageType::AddDevice
+    };
+    let record = evdevs.record(id).map_err(|e| Error::from_device(id, e))?;
+    client.write_message_bytes(ty, &record)?;
+    client.hidden.remove(&id);
+    send_switch_state(evdev, id, client)?;
+    if client.joydev_map {
+        send_joystick_map(evdev, id, client)?;
+    }
+    Ok(())
+}
+
+/// Brings the devices a client has in line with the subscription it just sent.
+fn apply_subscription(evdevs: &EvdevContainer, client: &mut Client) -> hidpipe::Result<()> {
+    for (id, evdev) in evdevs.iter() {
+        // The udev monitor will remove it
+        let Ok(input_id) = evdev.device_id() else {
+            continue;
+        };
+        match (client.has(id), client.wants(&input_id)) {
+            (true, false) => {
+                client.write_removal(id)?;
+                client.hidden.insert(id);
+            }
+            (false, true) => match send_add_device(evdevs, id, client) {
+                Err(e) if !is_client_error(&e) => {
+                    warn!("Not sending device {} to a client, error: {}", id, e)
+                }
+                res => res?,
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Switches only report changes, and the devices the client creates start
+/// with all of them off, so tell it which ones are on.
+fn send_switch_state<F: AsRawFd>(
+    evdev: &EvdevHandle<F>,
+    id: u64,
+    client: &mut Client,
+) -> hidpipe::Result<()> {
+    let mut state = Bitmask::<SwitchKind>::default();
+    evdev
+        .switch_state(&mut state)
+        .map_err(|e| Error::from_device(id, e))?;
+    let bits = evdev.switch_bits().map_err(|e| Error::from_device(id, e))?;
+    let mut any = false;
+    for switch in state.iter().filter(|switch| bits.get(*switch)) {
+        client.write_event(&InputEvent {
+            time_sec: 0,
+            time_usec: 0,
+            id,
+            ty: EventKind::Switch as u16,
+            code: switch as u16,
+            value: 1,
+        })?;
+        any = true;
+    }
+    if any {
+        client.write_event(&syn_report(id))?;
+    }
+    Ok(())
+}
+
+fn send_joystick_map<F: AsRawFd>(
+    evdev: &EvdevHandle<F>,
+    id: u64,
+    client: &mut Client,
+) -> hidpipe::Result<()> {
+    // Not every device gets a joystick node
+    let Some(path) = joydev::node_for_evdev(evdev) else {
+        return Ok(());
+    };
+    match File::open(&path).and_then(|js| joydev::read_map(&js, id)) {
+        Ok(map) => {
+            client.write_message_bytes(MessageType::JoystickMap, &map.into_wire().to_bytes())
+        }
+        Err(e) => {
+            warn!(
+                "Failed to read the joystick mapping of {}, error: {:?}",
+                path.display(),
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Turns off the centering spring of a racing wheel, which fights the force
+/// feedback of games.
+fn disable_autocenter(id: u64, evdev: &EvdevHandle<File>) {
+    match has_autocenter(evdev) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            warn!("Failed to query device {}, error: {:?}", id, e);
+            return;
+        }
+    }
+    let mut ev = empty_input_event();
+    ev.type_ = EventKind::ForceFeedback as u16;
+    ev.code = ForceFeedbackKind::Autocenter as u16;
+    match evdev.write(&[ev]) {
+        Ok(_) => debug!("Turned off the autocenter of device {}", id),
+        Err(e) => warn!(
+            "Failed to turn off the autocenter of device {}, error: {:?}",
+            id, e
+        ),
+    }
+}
+
+/// What the server needs of a source device to pass a client's input back to it.
+trait Source {
+    fn led_bits(&self) -> Result<Bitmask<LedKind>>;
+    fn write(&self, events: &[input_event]) -> Result<usize>;
+}
+
+impl<F: AsRawFd> Source for EvdevHandle<F> {
+    fn led_bits(&self) -> Result<Bitmask<LedKind>> {
+        EvdevHandle::led_bits(self)
+    }
+    fn write(&self, events: &[input_event]) -> Result<usize> {
+        EvdevHandle::write(self, events)
+    }
+}
+
+/// Writes an event from a client to its source device. LED events only go
+/// through for the LEDs the device has, as advertised in its record.
+fn write_to_source(source: &impl Source, event: &InputEvent) -> Result<()> {
+    if event.ty == EventKind::Led as u16
+        && !LedKind::from_code(event.code)
+            .is_ok_and(|led| source.led_bits().is_ok_and(|bits| bits.get(led)))
     {
-        return no;
-    }
-    let events = evdev.event_bits()?;
-    if !events.get(EventKind::Absolute) {
-        return no;
-    }
-    let axes = evdev.absolute_mask()?;
-    if !axes.get(AbsoluteAxis::X) || !axes.get(AbsoluteAxis::Y) {
-        return no;
-    }
-    let keys = evdev.key_mask()?;
-    Ok(keys.get(Key::ButtonTrigger)
-        || keys.get(Key::ButtonSouth)
-        || keys.get(Key::Button1)
-        || axes.get(AbsoluteAxis::RX)
-        || axes.get(AbsoluteAxis::RY)
-        || axes.get(AbsoluteAxis::Throttle)
-        || axes.get(AbsoluteAxis::Rudder)
-        || axes.get(AbsoluteAxis::Wheel)
-        || axes.get(AbsoluteAxis::Gas)
-        || axes.get(AbsoluteAxis::Brake))
-}
-
-fn send_add_device<F: AsRawFd>(evdev: &EvdevHandle<F>, client: &mut Client) -> Result<()> {
-    let abs = evdev.absolute_bits()?;
-    let evbits = *evdev.event_bits()?.data();
-    let keybits = *evdev.key_bits()?.data();
-    let relbits = *evdev.relative_bits()?.data();
-    let absbits = *abs.data();
-    let mut mscbits = evdev.misc_bits()?;
-    mscbits.remove(MiscKind::Scancode);
-    let mscbits = *mscbits.data();
-    let ledbits = *evdev.led_bits()?.data();
-    let sndbits = *evdev.sound_bits()?.data();
-    let swbits = *evdev.switch_bits()?.data();
-    let propbits = *evdev.device_properties()?.data();
-    let ffbits = *evdev.force_feedback_bits()?.data();
-    let input_id = evdev.device_id()?;
-    let ff_effects = evdev.effects_count()? as u32;
-    let id = evdev.as_raw_fd() as u64;
-    let mut name = [0; 80];
-    evdev.device_name_buf(&mut name)?;
-    client.write(&MessageType::AddDevice)?;
-    client.write(&AddDevice {
-        evbits,
-        keybits,
-        relbits,
-        absbits,
-        mscbits,
-        ledbits,
-        id,
-        sndbits,
-        swbits,
-        propbits,
-        input_id,
-        name,
-        ff_effects,
-        ffbits,
-    })?;
-    for bit in abs.iter() {
-        let info = evdev.absolute_info(bit)?;
-        client.write(&info)?;
+        return Ok(());
+    }
+    source.write(&[event.to_input_event()]).map(drop)
+}
+
+/// The part of a device record that describes what the device can do, without
+/// the current axis values.
+fn descriptor<F: AsRawFd>(evdev: &EvdevHandle<F>) -> Result<Vec<u8>> {
+    // Only compared with other descriptors of the same device, the id doesn't matter
+    let mut record = add_device_record(evdev, 0)?;
+    record.truncate(mem::size_of::<AddDevice>());
+    Ok(record)
+}
+
+fn send_snapshot(evdevs: &EvdevContainer, client: &mut Client) -> hidpipe::Result<()> {
+    let mut records = Vec::new();
+    for (id, _) in evdevs.iter() {
+        match evdevs.record(id) {
+            Ok(record) => records.push(record),
+            // The udev monitor will remove it, the client doesn't need to hear about it
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {}
+            Err(e) => warn!("Leaving device {} out of the snapshot, error: {:?}", id, e),
+        }
+    }
+    let data = encode_snapshot(&records);
+    let snapshot = DeviceSnapshot {
+        count: records.len() as u32,
+        len: data.len() as u32,
+    };
+    let mut payload = struct_as_bytes(&snapshot.into_wire()).to_vec();
+    payload.extend_from_slice(&data);
+    client.write_message_bytes(MessageType::DeviceSnapshot, &payload)?;
+    for (id, dev) in evdevs.iter() {
+        match send_switch_state(dev, id, client) {
+            Err(e) if !is_client_error(&e) => {}
+            res => res?,
+        }
+    }
+    if client.joydev_map {
+        for (id, dev) in evdevs.iter() {
+            send_joystick_map(dev, id, client)?;
+        }
     }
     Ok(())
 }
 
+/// What the server last saw of a device, kept up to date from its events.
+#[derive(Default)]
+struct DeviceState {
+    keys: BTreeSet<u16>,
+    abs: BTreeMap<u16, i32>,
+    ranges: BTreeMap<u16, (i32, i32)>,
+    descriptor: Vec<u8>,
+    /// Axes that went out of range at least once, to only complain once about each
+    out_of_range: BTreeSet<u16>,
+    /// The flat of each axis that has one, and the value clients last got
+    deadzones: BTreeMap<u16, (i32, i32)>,
+}
+
+impl DeviceState {
+    fn query<F: AsRawFd>(evdev: &EvdevHandle<F>) -> Result<DeviceState> {
+        let mut state = DeviceState {
+            descriptor: descriptor(evdev)?,
+            ..Default::default()
+        };
+        let mut keys = Bitmask::<Key>::default();
+        evdev.key_state(&mut keys)?;
+        for key in keys.iter() {
+            state.keys.insert(key as u16);
+        }
+        for axis in evdev.absolute_bits()?.iter() {
+            let info = evdev.absolute_info(axis)?;
+            state.abs.insert(axis as u16, info.value);
+            if info.flat > 0 {
+                state.deadzones.insert(axis as u16, (info.flat, info.value));
+            }
+            state.set_range(axis as u16, &info);
+        }
+        Ok(state)
+    }
+    /// Has events of axis `code` clamped to the range in `info`. Some devices
+    /// leave the range of axes they don't calibrate empty, those aren't.
+    fn set_range(&mut self, code: u16, info: &AbsoluteInfo) {
+        if info.minimum < info.maximum {
+            self.ranges.insert(code, (info.minimum, info.maximum));
+        } else {
+            self.ranges.remove(&code);
+        }
+    }
+    /// Bounds an absolute event to the range the device advertises, returns
+    /// whether it had to.
+    fn clamp(&mut self, ev: &mut input_event) -> bool {
+        if ev.type_ != EventKind::Absolute as u16 {
+            return false;
+        }
+        let Some(&(minimum, maximum)) = self.ranges.get(&ev.code) else {
+            return false;
+        };
+        let value = ev.value.clamp(minimum, maximum);
+        if value == ev.value {
+            return false;
+        }
+        ev.value = value;
+        self.out_of_range.insert(ev.code)
+    }
+    /// Whether an absolute event leaves its axis within its flat of the value
+    /// clients last got, which they can do without.
+    fn in_deadzone(&mut self, ev: &input_event) -> bool {
+        if ev.type_ != EventKind::Absolute as u16 {
+            return false;
+        }
+        let Some((flat, sent)) = self.deadzones.get_mut(&ev.code) else {
+            return false;
+        };
+        if ev.value.abs_diff(*sent) < *flat as u32 {
+            return true;
+        }
+        *sent = ev.value;
+        false
+    }
+    fn update(&mut self, ev: &input_event) {
+        if ev.type_ == EventKind::Key as u16 {
+            if ev.value == 0 {
+                self.keys.remove(&ev.code);
+            } else {
+                self.keys.insert(ev.code);
+            }
+        } else if ev.type_ == EventKind::Absolute as u16 {
+            self.abs.insert(ev.code, ev.value);
+        }
+    }
+    /// The events that bring a client to this state, a report ending with
+    /// `SYN_REPORT` where each of `keys` is pressed or released.
+    fn burst(&self, id: u64, keys: &Bitmask<Key>) -> Vec<InputEvent> {
+        let event = |ty: EventKind, code: u16, value: i32| InputEvent {
+            time_sec: 0,
+            time_usec: 0,
+            id,
+            ty: ty as u16,
+            code,
+            value,
+        };
+        let mut events = Vec::new();
+        for key in keys.iter() {
+            let pressed = self.keys.contains(&(key as u16));
+            events.push(event(EventKind::Key, key as u16, pressed as i32));
+        }
+        for (&code, &value) in &self.abs {
+            events.push(event(EventKind::Absolute, code, value));
+        }
+        events.push(syn_report(id));
+        events
+    }
+    fn describe(&self) -> String {
+        let mut line = String::new();
+        for (code, value) in &self.abs {
+            match AbsoluteAxis::from_code(*code) {
+                Ok(axis) => line.push_str(&format!("{:?}={} ", axis, value)),
+                Err(_) => line.push_str(&format!("abs{}={} ", code, value)),
+            }
+        }
+        line.push('|');
+        for code in &self.keys {
+            match Key::from_code(*code) {
+                Ok(key) => line.push_str(&format!(" {:?}", key)),
+                Err(_) => line.push_str(&format!(" key{}", code)),
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// How a `--devices-from` entry designates devices.
+#[derive(Clone)]
+enum DeviceMatch {
+    Path(PathBuf),
+    Id {
+        bustype: Option<u16>,
+        vendor: u16,
+        product: u16,
+    },
+}
+
+/// An entry of the `--devices-from` file.
+struct ListEntry {
+    device: DeviceMatch,
+    /// Bitmask of the event types to forward, all of them when unset
+    events: Option<u32>,
+}
+
+/// Names of the event types in `events=` filters.
+const EVENT_TYPE_NAMES: [(&str, EventKind); 8] = [
+    ("key", EventKind::Key),
+    ("rel", EventKind::Relative),
+    ("abs", EventKind::Absolute),
+    ("msc", EventKind::Misc),
+    ("sw", EventKind::Switch),
+    ("led", EventKind::Led),
+    ("snd", EventKind::Sound),
+    ("rep", EventKind::Autorepeat),
+];
+
+/// Parses a comma separated list of event types. Reports always go through,
+/// the events in between would never reach the guest otherwise.
+fn parse_event_types(list: &str) -> Option<u32> {
+    let mut mask = 1 << EventKind::Synchronize as u16;
+    for name in list.split(',') {
+        let (_, kind) = EVENT_TYPE_NAMES.iter().find(|(n, _)| *n == name)?;
+        mask |= 1 << *kind as u16;
+    }
+    Some(mask)
+}
+
+impl DeviceMatch {
+    fn parse(line: &str) -> Option<DeviceMatch> {
+        if line.starts_with('/') {
+            return Some(DeviceMatch::Path(PathBuf::from(line)));
+        }
+        let mut ids = line
+            .split(':')
+            .map(|id| u16::from_str_radix(id, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        let product = ids.pop()?;
+        let vendor = ids.pop()?;
+        let bustype = ids.pop();
+        if !ids.is_empty() {
+            return None;
+        }
+        Some(DeviceMatch::Id {
+            bustype,
+            vendor,
+            product,
+        })
+    }
+    fn matches<F: AsRawFd>(&self, file_name: &OsStr, evdev: &EvdevHandle<F>) -> Result<bool> {
+        Ok(match self {
+            // Resolved every time, /dev/input/by-id links come and go with their device
+            DeviceMatch::Path(path) => fs::canonicalize(path)
+                .is_ok_and(|path| fs::canonicalize(file_name).is_ok_and(|node| node == path)),
+            DeviceMatch::Id {
+                bustype,
+                vendor,
+                product,
+            } => {
+                let id = evdev.device_id()?;
+                bustype.is_none_or(|bustype| id.bustype == bustype)
+                    && id.vendor == *vendor
+                    && id.product == *product
+            }
+        })
+    }
+}
+
+fn matches_any<F: AsRawFd>(
+    devices: &[DeviceMatch],
+    file_name: &OsStr,
+    evdev: &EvdevHandle<F>,
+) -> Result<bool> {
+    for device in devices {
+        if device.matches(file_name, evdev)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl ListEntry {
+    fn parse(line: &str) -> Option<ListEntry> {
+        let mut words = line.split_whitespace();
+        let device = DeviceMatch::parse(words.next()?)?;
+        let events = match words.next() {
+            Some(filter) => Some(parse_event_types(filter.strip_prefix("events=")?)?),
+            None => None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        Some(ListEntry { device, events })
+    }
+}
+
+/// Reads the devices to forward, one event node path or `vendor:product` (in
+/// hex) per line, optionally followed by `events=` and the event types to
+/// forward. Empty lines and lines starting with `#` are skipped.
+fn load_device_list(path: &str) -> Result<Vec<ListEntry>> {
+    let mut list = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match ListEntry::parse(line) {
+            Some(entry) => list.push(entry),
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: expected a path or vendor:product, then events=<types>",
+                        path,
+                        i + 1
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(list)
+}
+
+/// A control connection streaming the state of a device.
+struct Watcher {
+    id: u64,
+    stream: UnixStream,
+    last_update: Option<Instant>,
+    /// What the stream couldn't take yet
+    queue: Vec<u8>,
+}
+
+/// A watcher that lets that much pile up isn't reading and is dropped.
+const WATCH_QUEUE_LIMIT: usize = 64 << 10;
+
+impl Watcher {
+    /// Queues a line and sends as much as the stream takes, so a line is never
+    /// cut short. Returns false once the watcher went away or stopped reading.
+    fn send(&mut self, line: &str) -> bool {
+        if self.queue.len() + line.len() > WATCH_QUEUE_LIMIT {
+            return false;
+        }
+        self.queue.extend_from_slice(line.as_bytes());
+        while !self.queue.is_empty() {
+            match self.stream.write(&self.queue) {
+                Ok(0) => return false,
+                Ok(written) => {
+                    self.queue.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Rumble is felt by everyone holding the device, and the effect ids of two
+/// clients would collide, so only one client at a time gets to drive it: the
+/// first one to upload an effect, unless another is picked over the control
+/// socket.
+#[derive(Default)]
+struct ForceFeedback {
+    owners: HashMap<u64, u64>,
+    /// Effects uploaded by the owners, erased from the device once they let go
+    /// of it
+    effects: EffectIds,
+}
+
+/// The device picks its own ids for the effects uploaded to it, this maps the
+/// ids the client's device picked to them, by device.
+#[derive(Default)]
+struct EffectIds {
+    ids: HashMap<(u64, i16), i16>,
+}
+
+impl EffectIds {
+    /// The id on device `id` of the effect the client knows as `effect`.
+    fn host(&self, id: u64, effect: i16) -> Option<i16> {
+        self.ids.get(&(id, effect)).copied()
+    }
+    fn uploaded(&mut self, id: u64, effect: i16, host: i16) {
+        self.ids.insert((id, effect), host);
+    }
+    /// Forgets an effect, returns its id on the device.
+    fn erased(&mut self, id: u64, effect: i16) -> Option<i16> {
+        self.ids.remove(&(id, effect))
+    }
+    /// Forgets the effects of a device, returns their ids on the device.
+    fn remove_device(&mut self, id: u64) -> Vec<i16> {
+        let mut removed = Vec::new();
+        self.ids.retain(|&(dev, _), &mut effect| {
+            if dev == id {
+                removed.push(effect);
+            }
+            dev != id
+        });
+        removed
+    }
+    /// Forgets the effects of the devices that went away.
+    fn retain_devices(&mut self, mut keep: impl FnMut(u64) -> bool) {
+        self.ids.retain(|(id, _), _| keep(*id));
+    }
+    /// Has a force feedback event from a client play the effect on the device.
+    /// Gain and autocenter apply to the whole device, the other codes play
+    /// the effect with that id.
+    fn translate(&self, event: &mut InputEvent) {
+        if event.ty == EventKind::ForceFeedback as u16
+            && event.code != ForceFeedbackKind::Gain as u16
+            && event.code != ForceFeedbackKind::Autocenter as u16
+        {
+            if let Some(effect) = self.host(event.id, event.code as i16) {
+                event.code = effect as u16;
+            }
+        }
+    }
+}
+
+impl ForceFeedback {
+    fn owner(&self, id: u64) -> Option<u64> {
+        self.owners.get(&id).copied()
+    }
+    /// Returns whether the client may drive the device, making it the owner if there is none.
+    fn claim(&mut self, id: u64, client: u64) -> bool {
+        *self.owners.entry(id).or_insert(client) == client
+    }
+    /// Uploads an effect for a client, the answer goes in `retval`.
+    fn upload(&mut self, evdev: &EvdevHandle<File>, client: u64, upload: &mut FFUpload) {
+        if !self.claim(upload.id, client) {
+            info!(
+                "Client {} can't upload effects to device {}, client {} drives it",
+                client,
+                upload.id,
+                self.owner(upload.id).unwrap()
+            );
+            upload.retval = -libc::EBUSY;
+            return;
+        }
+        let effect = upload.effect.id;
+        upload.effect.id = self.effects.host(upload.id, effect).unwrap_or(-1);
+        match evdev.send_force_feedback(&mut upload.effect) {
+            Ok(_) => self.effects.uploaded(upload.id, effect, upload.effect.id),
+            Err(e) => {
+                warn!(
+                    "Failed to upload an effect to device {}, error: {:?}",
+                    upload.id, e
+                );
+                upload.retval = -e.raw_os_error().unwrap_or(libc::EIO);
+            }
+        }
+        upload.effect.id = effect;
+    }
+    fn erase(&mut self, evdev: &EvdevHandle<File>, client: u64, erase: &FFErase) -> Result<()> {
+        if self.owner(erase.id) != Some(client) {
+            return Ok(());
+        }
+        if let Some(effect) = self.effects.erased(erase.id, erase.effect_id as i16) {
+            evdev.erase_force_feedback(effect)?;
+        }
+        Ok(())
+    }
+    fn set_owner(&mut self, evdevs: &EvdevContainer, id: u64, client: u64) {
+        if self.owner(id) != Some(client) {
+            self.release(evdevs, id);
+            self.owners.insert(id, client);
+        }
+    }
+    fn release(&mut self, evdevs: &EvdevContainer, id: u64) {
+        self.owners.remove(&id);
+        for effect in self.effects.remove_device(id) {
+            if let Some(evdev) = evdevs.get(id) {
+                let _ = evdev.erase_force_feedback(effect);
+            }
+        }
+    }
+    /// Releases the devices whose owner went away, and forgets the devices that
+    /// went away themselves, their effects went with their fd.
+    fn release_gone(&mut self, evdevs: &EvdevContainer, clients: &HashMap<u64, Client>) {
+        self.owners.retain(|id, _| evdevs.get(*id).is_some());
+        self.effects.retain_devices(|id| evdevs.get(id).is_some());
+        let gone: Vec<u64> = self
+            .owners
+            .iter()
+            .filter(|(_, client)| !clients.contains_key(client))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in gone {
+            self.release(evdevs, id);
+        }
+    }
+}
+
+/// Watchers are meant to be read by humans, no need to update them at the device's rate.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
 struct EvdevContainer {
-    fds_to_devs: HashMap<u64, EvdevHandle<File>>,
-    names_to_fds: HashMap<String, u64>,
+    /// Devices by id. Ids are never reused, unlike fds, so a client can't take
+    /// a new device for one that went away
+    ids_to_devs: HashMap<u64, EvdevHandle<File>>,
+    states: HashMap<u64, DeviceState>,
+    names_to_ids: HashMap<String, u64>,
+    names_to_paths: HashMap<String, OsString>,
+    motion: bool,
+    touchpads: TouchpadMode,
+    /// Motion sensors and touchpads waiting for their controller to show up
+    pending_sensors: HashMap<String, OsString>,
+    /// Touchpads forwarded as mice
+    mice: HashMap<u64, Mouse>,
+    disabled: HashSet<u64>,
+    lazy: bool,
+    active: bool,
+    instance_tag: Option<u64>,
+    /// Replaces the heuristics deciding what to forward when set
+    device_list: Option<Vec<ListEntry>>,
+    /// Event types to forward, for the devices that don't forward everything
+    event_filters: HashMap<u64, u32>,
+    /// In lazy mode, devices to open once a client connects
+    closed: HashMap<String, OsString>,
+    grab: bool,
+    skip_ungrabbed: bool,
+    /// Forwarded devices something else had already grabbed
+    ungrabbed: HashSet<u64>,
+    /// Forward a single joystick, the others wait on standby until it goes away
+    primary: bool,
+    /// Joysticks that can be the primary one, any when unset
+    primary_match: Option<DeviceMatch>,
+    /// Devices forwarded as joysticks whatever the heuristics say
+    allow: Vec<DeviceMatch>,
+    /// Devices never forwarded unless asked for over the control socket
+    deny: Vec<DeviceMatch>,
+    /// Phys prefixes of the virtual devices the heuristics leave alone
+    markers: Vec<String>,
+    /// Kinds of devices the heuristics pick, gamepads only by default
+    classes: Vec<DeviceClass>,
+    primary_id: Option<u64>,
+    /// Joysticks disabled while the primary one is there, in connection order
+    standby: Vec<u64>,
+    /// When each forwarded device showed up, by name, so that clients always
+    /// enumerate them in the same order
+    plug_order: HashMap<String, u64>,
+    plugged: u64,
+    /// Devices forwarded with the control socket, whatever they are
+    forced: Vec<DeviceMatch>,
+    next_id: u64,
+    /// Leave out the jitter of axes within their flat
+    deadzone_filter: bool,
 }
 
-fn insert_entry<K, V>(entry: hash_map::Entry<K, V>, v: V) -> &V {
-    match entry {
-        hash_map::Entry::Vacant(e) => e.insert(v),
-        hash_map::Entry::Occupied(mut e) => {
-            e.insert(v);
-            e.into_mut()
+/// Revokes our access before closing a device, so nothing we set up on it
+/// (such as a grab) can outlive us. Kernels without EVIOCREVOKE just close it.
+fn release(evdev: EvdevHandle<File>) {
+    match evdev.revoke() {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => {}
+        // The device was unplugged
+        Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {}
+        Err(e) => warn!("Failed to revoke a device, error: {:?}", e),
+    }
+}
+
+fn random_tag() -> u64 {
+    let mut tag = 0u64;
+    // SAFETY: the buffer is a valid u64
+    let res = unsafe {
+        libc::getrandom(
+            &mut tag as *mut u64 as *mut libc::c_void,
+            mem::size_of::<u64>(),
+            0,
+        )
+    };
+    if res != mem::size_of::<u64>() as isize {
+        panic!("getrandom failed: {:?}", io::Error::last_os_error());
+    }
+    tag
+}
+
+/// Every device and client costs a file descriptor, the default soft limit of
+/// 1024 is easy to reach with many VMs.
+fn raise_fd_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is a valid rlimit
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        warn!(
+            "Unable to get the file descriptor limit, error: {:?}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        // SAFETY: limit is a valid rlimit
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            warn!(
+                "Unable to raise the file descriptor limit, error: {:?}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+fn is_out_of_fds(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// Accepts a connection on `listener`. When we are out of file descriptors the
+/// connection can't be accepted, but it would stay in the backlog and wake us
+/// up forever, so `spare` is given up to accept and close it right away.
+fn accept_or_shed<S>(
+    mut accept: impl FnMut() -> Result<S>,
+    spare: &mut Option<File>,
+    what: &str,
+) -> Option<S> {
+    match accept() {
+        Ok(stream) => Some(stream),
+        Err(e) if is_out_of_fds(&e) => {
+            warn!("Out of file descriptors, turning away a {}", what);
+            spare.take();
+            drop(accept());
+            *spare = File::open("/dev/null").ok();
+            None
+        }
+        Err(e) => {
+            warn!("Failed to accept a {}, error: {:?}", what, e);
+            None
         }
     }
 }
 
 impl EvdevContainer {
-    fn new() -> EvdevContainer {
+    fn new(
+        options: &Options,
+        instance_tag: Option<u64>,
+        device_list: Option<Vec<ListEntry>>,
+    ) -> EvdevContainer {
         EvdevContainer {
-            fds_to_devs: HashMap::new(),
-            names_to_fds: HashMap::new(),
+            ids_to_devs: HashMap::new(),
+            states: HashMap::new(),
+            names_to_ids: HashMap::new(),
+            names_to_paths: HashMap::new(),
+            motion: options.motion,
+            touchpads: options.touchpads,
+            pending_sensors: HashMap::new(),
+            mice: HashMap::new(),
+            disabled: HashSet::new(),
+            lazy: options.lazy,
+            active: !options.lazy,
+            instance_tag,
+            device_list,
+            event_filters: HashMap::new(),
+            closed: HashMap::new(),
+            grab: options.grab,
+            skip_ungrabbed: options.skip_ungrabbed,
+            ungrabbed: HashSet::new(),
+            primary: options.primary,
+            primary_match: options.primary_match.clone(),
+            allow: options.allow.clone(),
+            deny: options.deny.clone(),
+            markers: [PHYS_MARKER.to_string()]
+                .into_iter()
+                .chain(options.skip_phys.iter().cloned())
+                .collect(),
+            classes: options
+                .classes
+                .clone()
+                .unwrap_or_else(|| vec![DeviceClass::Gamepad]),
+            primary_id: None,
+            standby: Vec::new(),
+            plug_order: HashMap::new(),
+            plugged: 0,
+            forced: Vec::new(),
+            next_id: 1,
+            deadzone_filter: options.deadzone_filter,
         }
     }
+    /// Motion sensors and touchpads are separate nodes, we match them to their
+    /// controller by uniq or phys.
+    fn has_controller_for<F: AsRawFd>(&self, sensor: &EvdevHandle<F>) -> Result<bool> {
+        let uniq = trim_nul(sensor.unique_id()?);
+        let phys = trim_nul(sensor.physical_location()?);
+        for dev in self.ids_to_devs.values() {
+            if is_motion_sensor(dev)? || is_touchpad(dev)? {
+                continue;
+            }
+            if !uniq.is_empty() && trim_nul(dev.unique_id()?) == uniq {
+                return Ok(true);
+            }
+            if !phys.is_empty() && trim_nul(dev.physical_location()?) == phys {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    /// The first of the classes we forward a device belongs to, devices passed
+    /// to `--allow` are gamepads.
+    fn classify(&self, evdev: &EvdevHandle<File>, allowed: bool) -> Result<Option<DeviceClass>> {
+        if allowed {
+            return Ok(Some(DeviceClass::Gamepad));
+        }
+        for class in &self.classes {
+            if class.matches(evdev)? {
+                return Ok(Some(*class));
+            }
+        }
+        Ok(None)
+    }
     fn check_and_add(
         &mut self,
         dev_name: &OsStr,
         file_name: &OsStr,
         epoll: &Epoll,
-    ) -> Result<Option<&EvdevHandle<File>>> {
+    ) -> Result<Option<u64>> {
         let dev_name = dev_name.to_string_lossy();
         if !dev_name.starts_with("event") {
             return Ok(None);
@@ -135,37 +979,396 @@ impl EvdevContainer {
             .custom_flags(libc::O_NONBLOCK)
             .open(file_name)?;
         let evdev = EvdevHandle::new(file);
-        if is_joystick(&evdev)? {
-            let raw = evdev.as_raw_fd() as u64;
-            self.names_to_fds.insert(dev_name.into_owned(), raw);
+        if let Some(tag) = self.instance_tag {
+            let phys = trim_nul(evdev.physical_location()?);
+            if phys.starts_with(instance_tag_phys(tag).as_bytes()) {
+                info!(
+                    "{} was created by one of our clients, skipping it",
+                    dev_name
+                );
+                return Ok(None);
+            }
+        }
+        let label = describe(&dev_name, &evdev)?;
+        let mut filter = None;
+        let mut joystick = false;
+        let allowed = matches_any(&self.allow, file_name, &evdev)?;
+        let forward = if matches_any(&self.forced, file_name, &evdev)? {
+            true
+        } else if matches_any(&self.deny, file_name, &evdev)? {
+            info!("{} is denied, not forwarding it", label);
+            false
+        } else if let Some(list) = &self.device_list {
+            let mut listed = false;
+            for entry in list {
+                if !listed && entry.device.matches(file_name, &evdev)? {
+                    listed = true;
+                    filter = entry.events;
+                }
+            }
+            if !listed {
+                info!("{} is not in the device list, not forwarding it", label);
+            }
+            listed
+        } else if !allowed && is_virtual(&self.markers, &evdev)? {
+            info!("{} is a virtual device, not forwarding it", label);
+            false
+        } else if let Some(class) = self.classify(&evdev, allowed)? {
+            joystick = self.primary && class == DeviceClass::Gamepad;
+            match &self.primary_match {
+                Some(primary) if joystick => primary.matches(file_name, &evdev)?,
+                _ => true,
+            }
+        } else if self.motion && is_motion_sensor(&evdev)? {
+            if !self.has_controller_for(&evdev)? {
+                info!("{} is a motion sensor, waiting for its controller", label);
+                self.pending_sensors
+                    .insert(dev_name.into_owned(), file_name.to_owned());
+                return Ok(None);
+            }
+            true
+        } else if self.touchpads != TouchpadMode::Ignore && is_touchpad(&evdev)? {
+            // Laptop touchpads never get a controller
+            if !self.has_controller_for(&evdev)? {
+                info!("{} is a touchpad, waiting for its controller", label);
+                self.pending_sensors
+                    .insert(dev_name.into_owned(), file_name.to_owned());
+                return Ok(None);
+            }
+            true
+        } else {
+            info!("{} is not a joystick, not forwarding it", label);
+            false
+        };
+        if forward && !self.plug_order.contains_key(dev_name.as_ref()) {
+            self.plug_order.insert(dev_name.to_string(), self.plugged);
+            self.plugged += 1;
+        }
+        if forward && !self.active {
+            info!(
+                "{} is a joystick, it will be opened when a client connects",
+                label
+            );
+            self.closed
+                .insert(dev_name.into_owned(), file_name.to_owned());
+            Ok(None)
+        } else if forward {
+            let id = self.next_id;
+            self.next_id += 1;
+            if self.grab {
+                if let Err(e) = evdev.grab(true) {
+                    warn!("Failed to grab {}, error: {:?}", dev_name, e);
+                    if self.skip_ungrabbed {
+                        info!("Not forwarding {}, it would be used twice", dev_name);
+                        return Ok(None);
+                    }
+                    self.ungrabbed.insert(id);
+                }
+            }
+            if let Some(filter) = filter {
+                self.event_filters.insert(id, filter);
+            }
+            if joystick {
+                if let Some(primary) = self.primary_id {
+                    info!(
+                        "{} is on standby, device {} is the primary joystick",
+                        dev_name, primary
+                    );
+                    self.standby.push(id);
+                    self.disabled.insert(id);
+                } else {
+                    self.primary_id = Some(id);
+                }
+            }
+            if self.touchpads == TouchpadMode::Mouse && is_touchpad(&evdev)? {
+                let x = evdev.absolute_info(AbsoluteAxis::X)?;
+                self.mice.insert(id, Mouse::new(x.maximum - x.minimum));
+            }
+            self.states
+                .insert(id, DeviceState::query(&evdev).unwrap_or_default());
+            self.names_to_paths
+                .insert(dev_name.to_string(), file_name.to_owned());
+            self.names_to_ids.insert(dev_name.into_owned(), id);
             epoll
-                .add(evdev.as_inner(), EpollEvent::new(EpollFlags::EPOLLIN, raw))
+                .add(
+                    evdev.as_inner(),
+                    EpollEvent::new(EpollFlags::EPOLLIN, KEY_DEVICE | id),
+                )
                 .unwrap();
-            Ok(Some(insert_entry(self.fds_to_devs.entry(raw), evdev)))
+            self.ids_to_devs.insert(id, evdev);
+            info!("Forwarding {} as device {}", label, id);
+            Ok(Some(id))
         } else {
             Ok(None)
         }
     }
+    /// Retries motion sensors and touchpads that showed up before their controller,
+    /// returns the ids of the ones that could be added now.
+    fn add_pending_sensors(&mut self, epoll: &Epoll) -> Vec<u64> {
+        let mut added = Vec::new();
+        for (name, path) in mem::take(&mut self.pending_sensors) {
+            match self.check_and_add(OsStr::new(&name), &path, epoll) {
+                Ok(Some(id)) => added.push(id),
+                Ok(None) => {}
+                Err(e) => warn!("Unable to add motion sensor {}, error: {:?}", name, e),
+            }
+        }
+        added
+    }
+    /// Opens the devices that were closed in lazy mode.
+    fn activate(&mut self, epoll: &Epoll) {
+        self.active = true;
+        for (name, path) in mem::take(&mut self.closed) {
+            if let Err(e) = self.check_and_add(OsStr::new(&name), &path, epoll) {
+                warn!("Unable to open {}, error: {:?}", name, e);
+            }
+        }
+        self.add_pending_sensors(epoll);
+    }
+    /// Closes every device until a client connects again, in lazy mode.
+    fn deactivate(&mut self, epoll: &Epoll) {
+        self.active = false;
+        self.disabled.clear();
+        self.primary_id = None;
+        self.standby.clear();
+        for (name, id) in mem::take(&mut self.names_to_ids) {
+            let evdev = self.ids_to_devs.remove(&id).unwrap();
+            epoll.delete(evdev.as_inner()).unwrap();
+            release(evdev);
+            self.states.remove(&id);
+            self.ungrabbed.remove(&id);
+            self.event_filters.remove(&id);
+            self.mice.remove(&id);
+            let path = self.names_to_paths.remove(&name).unwrap();
+            self.closed.insert(name, path);
+        }
+    }
+    /// Returns the id of the removed device if clients know about it.
     fn remove(&mut self, dev_name: &OsStr, epoll: &Epoll) -> Option<u64> {
+        self.pending_sensors
+            .remove(dev_name.to_string_lossy().as_ref());
+        self.closed.remove(dev_name.to_string_lossy().as_ref());
+        self.plug_order.remove(dev_name.to_string_lossy().as_ref());
+        self.names_to_paths
+            .remove(dev_name.to_string_lossy().as_ref());
         if let Some(id) = self
-            .names_to_fds
+            .names_to_ids
             .remove(dev_name.to_string_lossy().as_ref())
         {
-            let evdev = self.fds_to_devs.remove(&id).unwrap();
+            let evdev = self.ids_to_devs.remove(&id).unwrap();
+            let name = trim_nul(evdev.device_name().unwrap_or_default());
+            info!(
+                "Stopped forwarding device {} ({})",
+                id,
+                String::from_utf8_lossy(&name)
+            );
             epoll.delete(evdev.as_inner()).unwrap();
-            Some(id)
+            release(evdev);
+            self.states.remove(&id);
+            self.ungrabbed.remove(&id);
+            self.event_filters.remove(&id);
+            self.mice.remove(&id);
+            self.standby.retain(|standby| *standby != id);
+            if self.primary_id == Some(id) {
+                self.primary_id = None;
+            }
+            if self.disabled.remove(&id) {
+                None
+            } else {
+                Some(id)
+            }
         } else {
             None
         }
     }
+    /// Forwards the devices matching `device` from now on, returns the ids of
+    /// the ones that were opened.
+    fn force(&mut self, device: DeviceMatch, epoll: &Epoll) -> Result<Vec<u64>> {
+        self.forced.push(device);
+        let mut added = Vec::new();
+        for dir_ent in fs::read_dir("/dev/input/")? {
+            let dir_ent = dir_ent?;
+            let name = dir_ent.file_name();
+            if self
+                .names_to_ids
+                .contains_key(name.to_string_lossy().as_ref())
+            {
+                continue;
+            }
+            // Most nodes are for other users, only the matching ones matter
+            if let Ok(Some(id)) = self.check_and_add(&name, dir_ent.path().as_os_str(), epoll) {
+                added.push(id);
+            }
+        }
+        Ok(added)
+    }
+    /// Closes a device, and stops forcing it if it was. Returns its id if
+    /// clients know about it.
+    fn unforward(&mut self, id: u64, epoll: &Epoll) -> Option<u64> {
+        let (name, _) = self.names_to_ids.iter().find(|(_, other)| **other == id)?;
+        let path = &self.names_to_paths[name];
+        let evdev = &self.ids_to_devs[&id];
+        self.forced
+            .retain(|device| !device.matches(path, evdev).unwrap_or_default());
+        self.close(id, epoll)
+    }
+    /// Like [`EvdevContainer::remove`], by id.
+    fn close(&mut self, id: u64, epoll: &Epoll) -> Option<u64> {
+        let (name, _) = self.names_to_ids.iter().find(|(_, other)| **other == id)?;
+        self.remove(OsStr::new(&name.clone()), epoll)
+    }
+    /// Forwards the first joystick on standby once the primary one went away,
+    /// returns its id if clients have to be told.
+    fn promote(&mut self) -> Option<u64> {
+        if self.primary_id.is_some() || self.standby.is_empty() {
+            return None;
+        }
+        let id = self.standby.remove(0);
+        self.primary_id = Some(id);
+        self.disabled.remove(&id).then_some(id)
+    }
+    /// Checks whether a device changed its capabilities, returns its id if it
+    /// did and clients have to be told.
+    fn refresh(&mut self, dev_name: &OsStr) -> Option<u64> {
+        let id = *self.names_to_ids.get(dev_name.to_string_lossy().as_ref())?;
+        let new = match DeviceState::query(&self.ids_to_devs[&id]) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Failed to query device {}, error: {:?}", id, e);
+                return None;
+            }
+        };
+        let old = self.states.insert(id, new)?;
+        if old.descriptor == self.states[&id].descriptor || !self.is_enabled(id) {
+            // Clients still have the values they last got
+            let state = self.states.get_mut(&id).unwrap();
+            for (code, (_, sent)) in &mut state.deadzones {
+                if let Some((_, old_sent)) = old.deadzones.get(code) {
+                    *sent = *old_sent;
+                }
+            }
+            None
+        } else {
+            Some(id)
+        }
+    }
     fn get(&self, id: u64) -> Option<&EvdevHandle<File>> {
-        self.fds_to_devs.get(&id)
+        self.ids_to_devs.get(&id)
+    }
+    /// A device clients may write to, disabled ones are none of their business.
+    fn forwarded(&self, id: u64) -> Option<&EvdevHandle<File>> {
+        self.get(id).filter(|_| self.is_enabled(id))
+    }
+    fn state(&self, id: u64) -> Option<&DeviceState> {
+        self.states.get(&id)
+    }
+    fn clamp(&mut self, id: u64, ev: &mut input_event) -> bool {
+        self.states
+            .get_mut(&id)
+            .is_some_and(|state| state.clamp(ev))
+    }
+    fn in_deadzone(&mut self, id: u64, ev: &input_event) -> bool {
+        self.deadzone_filter
+            && self
+                .states
+                .get_mut(&id)
+                .is_some_and(|state| state.in_deadzone(ev))
+    }
+    fn update_state(&mut self, id: u64, ev: &input_event) {
+        if let Some(state) = self.states.get_mut(&id) {
+            state.update(ev);
+        }
     }
-    fn iter(&self) -> impl Iterator<Item = &EvdevHandle<File>> {
-        self.fds_to_devs.values()
+    /// Iterates over the devices that are forwarded to clients, in the order
+    /// they were plugged in.
+    fn iter(&self) -> impl Iterator<Item = (u64, &EvdevHandle<File>)> {
+        let mut ids: Vec<_> = self
+            .names_to_ids
+            .iter()
+            .filter(|(_, id)| self.is_enabled(**id))
+            .map(|(name, id)| (self.plug_order[name], *id))
+            .collect();
+        ids.sort();
+        ids.into_iter().map(|(_, id)| (id, &self.ids_to_devs[&id]))
+    }
+    /// Iterates over every device, including the ones that were disabled.
+    fn all(&self) -> impl Iterator<Item = (u64, &EvdevHandle<File>)> {
+        self.ids_to_devs.iter().map(|(id, dev)| (*id, dev))
+    }
+    fn is_enabled(&self, id: u64) -> bool {
+        !self.disabled.contains(&id)
+    }
+    /// The record clients get for a device, which is a mouse for touchpads forwarded as one.
+    fn record(&self, id: u64) -> Result<Vec<u8>> {
+        let record = add_device_record(&self.ids_to_devs[&id], id)?;
+        Ok(if self.mice.contains_key(&id) {
+            touchpad::mouse_record(&record)
+        } else {
+            record
+        })
+    }
+    /// Reads the state of a device again and returns the events that bring a
+    /// client to it, whatever it believes it to be. Every key is sent, the
+    /// guest's kernel drops the ones that don't change anything.
+    fn resync(&mut self, id: u64) -> Result<Vec<InputEvent>> {
+        let evdev = &self.ids_to_devs[&id];
+        let state = DeviceState::query(evdev)?;
+        let events = state.burst(id, &evdev.key_bits()?);
+        self.states.insert(id, state);
+        Ok(events)
+    }
+    /// The limits of every axis of a device, some controllers change them when
+    /// they are calibrated.
+    fn abs_infos(&self, id: u64) -> Result<Vec<UpdateAbsInfo>> {
+        let evdev = &self.ids_to_devs[&id];
+        let mut infos = Vec::new();
+        for axis in evdev.absolute_bits()?.iter() {
+            infos.push(UpdateAbsInfo {
+                id,
+                axis: axis as u32,
+                info: evdev.absolute_info(axis)?,
+            });
+        }
+        Ok(infos)
+    }
+    fn mouse_mut(&mut self, id: u64) -> Option<&mut Mouse> {
+        self.mice.get_mut(&id)
+    }
+    fn forwards(&self, id: u64, ty: u16) -> bool {
+        self.event_filters
+            .get(&id)
+            .is_none_or(|filter| ty < 32 && filter & (1 << ty) != 0)
+    }
+    fn is_ungrabbed(&self, id: u64) -> bool {
+        self.ungrabbed.contains(&id)
+    }
+    /// Returns whether the state of the device changed.
+    fn set_enabled(&mut self, id: u64, enabled: bool) -> bool {
+        if enabled {
+            self.disabled.remove(&id)
+        } else {
+            self.disabled.insert(id)
+        }
     }
 }
 
+/// The top byte of epoll keys says what woke us up, the rest is the fd of
+/// clients or the id of devices. Fds are reused as soon as they are closed,
+/// the kind keeps a client from being taken for a device or the other way
+/// around.
+const KEY_KIND_MASK: u64 = 0xff << 56;
+const KEY_UDEV: u64 = 1 << 56;
+const KEY_LISTEN: u64 = 2 << 56;
+const KEY_CONTROL: u64 = 3 << 56;
+const KEY_SIGNALS: u64 = 4 << 56;
+const KEY_CLIENT: u64 = 5 << 56;
+const KEY_DEVICE: u64 = 6 << 56;
+const KEY_TCP: u64 = 7 << 56;
+const KEY_VSOCK: u64 = 8 << 56;
+
+/// Events handled per `epoll_wait`, bursts from several devices take one call.
+const EPOLL_BATCH: usize = 32;
+
 #[derive(PartialEq, Eq)]
 enum WaitingFor {
     Hello,
@@ -173,13 +1376,57 @@ enum WaitingFor {
     InputEvent,
     FFUpload,
     FFErase,
+    Ping,
+    Pong,
+    Subscribe,
 }
 
+/// Once that much is waiting to be sent to a client, its motion events are
+/// held back and merged to let it catch up.
+const QUEUE_SOFT_LIMIT: usize = 64 << 10;
+/// A client that lets that much pile up is stuck and gets disconnected. It has
+/// to leave room for a snapshot.
+const QUEUE_HARD_LIMIT: usize = 2 * MAX_SNAPSHOT_SIZE;
+
+/// The first protocol version whose clients get input events in frames.
+const FRAMES_VERSION: u32 = 0x0401;
+/// A frame is sent early when a device reports that many events without a
+/// `SYN_REPORT`.
+const MAX_FRAME_EVENTS: usize = 256;
+/// How many events are read from a device at once.
+const DEVICE_BATCH: usize = 64;
+
 struct Client {
-    socket: UnixStream,
+    socket: Box<dyn Transport>,
     buf: Vec<u8>,
     filled: usize,
     waiting_for: WaitingFor,
+    /// Follow every device with the mapping of its joystick node
+    joydev_map: bool,
+    /// What the socket couldn't take yet
+    queue: Vec<u8>,
+    /// Whether epoll tells us when the socket can take more
+    polling_out: bool,
+    /// Motion held back while the queue is too long, sent once it drains
+    held: Vec<InputEvent>,
+    /// Motion events merged into later ones because the queue was too long
+    dropped: u64,
+    /// Whether the client asked for JSON framing
+    json: bool,
+    /// Native messages decoded from JSON frames, and what is left of the frames
+    decoded: Vec<u8>,
+    #[cfg(feature = "json")]
+    frames: Vec<u8>,
+    /// Whether the client answers pings
+    pings: bool,
+    /// Whether the client takes the events of a report in one message
+    input_frames: bool,
+    /// When the ping still waiting for its pong was sent
+    ping_sent: Option<Instant>,
+    /// The models the client asked for, every device until it does
+    subscription: Option<Subscribe>,
+    /// The devices the client doesn't get because of its subscription
+    hidden: HashSet<u64>,
 }
 
 enum ReadReply {
@@ -189,15 +1436,33 @@ enum ReadReply {
 }
 
 impl Client {
-    fn new(socket: UnixStream) -> Client {
+    fn new(socket: Box<dyn Transport>, joydev_map: bool) -> Client {
         Client {
             socket,
+            joydev_map,
             waiting_for: WaitingFor::Hello,
             buf: Vec::new(),
             filled: 0,
+            queue: Vec::new(),
+            polling_out: false,
+            held: Vec::new(),
+            dropped: 0,
+            json: false,
+            decoded: Vec::new(),
+            #[cfg(feature = "json")]
+            frames: Vec::new(),
+            pings: false,
+            input_frames: false,
+            ping_sent: None,
+            subscription: None,
+            hidden: HashSet::new(),
         }
     }
-    fn read(&mut self, size: usize) -> Result<ReadReply> {
+    fn read(&mut self, size: usize) -> hidpipe::Result<ReadReply> {
+        #[cfg(feature = "json")]
+        if self.json {
+            return self.read_json(size);
+        }
         if self.buf.is_empty() {
             self.buf.resize(size, 0);
         } else if self.buf.len() != size {
@@ -217,8 +1482,308 @@ impl Client {
             ReadReply::NotReady
         })
     }
-    fn write<T>(&mut self, data: &T) -> Result<()> {
-        struct_to_socket(&mut self.socket, data)
+    /// Reads like [`Client::read`], from the native messages decoded from the
+    /// frames. Messages are always decoded whole.
+    #[cfg(feature = "json")]
+    fn read_json(&mut self, size: usize) -> hidpipe::Result<ReadReply> {
+        if self.decoded.len() < size {
+            let mut buf = [0u8; 4096];
+            let read = self.socket.read(&mut buf)?;
+            if read == 0 {
+                return Ok(ReadReply::Hangup);
+            }
+            self.frames.extend_from_slice(&buf[..read]);
+            while let Some((message, used)) = hidpipe::json::decode(&self.frames)? {
+                self.decoded.extend(message);
+                self.frames.drain(..used);
+            }
+        }
+        if self.decoded.len() < size {
+            return Ok(ReadReply::NotReady);
+        }
+        Ok(ReadReply::Data(self.decoded.drain(..size).collect()))
+    }
+    /// Queues data for the client and sends as much as the socket takes.
+    fn send(&mut self, data: &[u8]) -> hidpipe::Result<()> {
+        if self.queue.len() + data.len() > QUEUE_HARD_LIMIT {
+            return Err(Error::Backlog(self.queue.len()));
+        }
+        self.queue.extend_from_slice(data);
+        self.flush()
+    }
+    fn flush(&mut self) -> hidpipe::Result<()> {
+        while !self.queue.is_empty() {
+            match self.socket.write(&self.queue) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(written) => {
+                    self.queue.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+    /// Whether the client doesn't keep up. Its motion events are then held
+    /// back, they only cost some resolution as the next one carries the new
+    /// value anyway.
+    fn behind(&self) -> bool {
+        self.queue.len() > QUEUE_SOFT_LIMIT
+    }
+    /// Merges a motion event into those held back.
+    fn hold(&mut self, ev: &InputEvent) {
+        if hold(&mut self.held, ev) {
+            self.dropped += 1;
+        }
+    }
+    /// Takes the motion held back for device `id`.
+    fn take_held(&mut self, id: u64) -> Vec<InputEvent> {
+        let (taken, held) = mem::take(&mut self.held)
+            .into_iter()
+            .partition(|ev| ev.id == id);
+        self.held = held;
+        taken
+    }
+    /// Sends what the socket can take now, then the motion held back as a
+    /// report for each device once the client caught up.
+    fn catch_up(&mut self) -> hidpipe::Result<()> {
+        self.flush()?;
+        while let Some(id) = self.held.first().map(|ev| ev.id) {
+            if self.behind() {
+                break;
+            }
+            let mut report = self.take_held(id);
+            report.push(syn_report(id));
+            self.write_frame(id, &report)?;
+        }
+        Ok(())
+    }
+    /// Has epoll wake us up when the socket can take more, while data is queued
+    /// or motion held back.
+    fn update_polling(&mut self, epoll: &Epoll, fd: u64) {
+        let queued = !self.queue.is_empty() || !self.held.is_empty();
+        if queued == self.polling_out {
+            return;
+        }
+        let flags = if queued {
+            EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT
+        } else {
+            EpollFlags::EPOLLIN
+        };
+        epoll
+            .modify(&self.socket, &mut EpollEvent::new(flags, KEY_CLIENT | fd))
+            .unwrap();
+        self.polling_out = queued;
+    }
+    fn write<T>(&mut self, data: &T) -> hidpipe::Result<()> {
+        self.send(struct_as_bytes(data))
+    }
+    fn write_message<T>(&mut self, ty: MessageType, data: &T) -> hidpipe::Result<()> {
+        self.write_message_bytes(ty, struct_as_bytes(data))
+    }
+    fn write_message_bytes(&mut self, ty: MessageType, payload: &[u8]) -> hidpipe::Result<()> {
+        let message = self.frame(ty, payload)?;
+        self.send(&message)
+    }
+    /// The message as it goes on the wire.
+    fn frame(&self, ty: MessageType, payload: &[u8]) -> hidpipe::Result<Vec<u8>> {
+        #[cfg(feature = "json")]
+        if self.json {
+            return hidpipe::json::encode(ty, payload);
+        }
+        let mut message = message_header(ty, payload.len()).to_vec();
+        message.extend_from_slice(payload);
+        Ok(message)
+    }
+    /// Tells the client a device went away, unless it never got it.
+    fn write_removal(&mut self, id: u64) -> hidpipe::Result<()> {
+        self.held.retain(|ev| ev.id != id);
+        if self.hidden.remove(&id) {
+            return Ok(());
+        }
+        self.write_message(MessageType::RemoveDevice, &RemoveDevice { id }.into_wire())
+    }
+    fn wants(&self, id: &InputId) -> bool {
+        self.subscription
+            .is_none_or(|subscription| subscription.matches(id))
+    }
+    /// Whether the client got device `id`, rather than leaving it out.
+    fn has(&self, id: u64) -> bool {
+        !self.hidden.contains(&id)
+    }
+    /// Sends an event, the motion held back for its device going out before
+    /// the next `SYN_REPORT` once the client caught up.
+    fn write_event(&mut self, ev: &InputEvent) -> hidpipe::Result<()> {
+        if self.behind() && is_coalescible(ev) {
+            self.hold(ev);
+            return Ok(());
+        }
+        if ev.ty == EventKind::Synchronize as u16
+            && ev.code == SynchronizeKind::Report as u16
+            && !self.behind()
+        {
+            for held in self.take_held(ev.id) {
+                self.send_event(&held)?;
+            }
+        }
+        self.send_event(ev)
+    }
+    fn send_event(&mut self, ev: &InputEvent) -> hidpipe::Result<()> {
+        let message = self.frame(MessageType::InputEvent, struct_as_bytes(&ev.into_wire()))?;
+        self.send(&message)
+    }
+    /// Sends the events of device `id` in one message, or one by one to
+    /// clients that don't take frames.
+    fn write_frame(&mut self, id: u64, events: &[InputEvent]) -> hidpipe::Result<()> {
+        if !self.has(id) {
+            return Ok(());
+        }
+        if !self.input_frames {
+            return events.iter().try_for_each(|ev| self.write_event(ev));
+        }
+        let behind = self.behind();
+        let mut kept = if behind {
+            Vec::new()
+        } else {
+            self.take_held(id)
+        };
+        for ev in events {
+            if behind && is_coalescible(ev) {
+                self.hold(ev);
+            } else {
+                kept.push(*ev);
+            }
+        }
+        if kept.is_empty() {
+            return Ok(());
+        }
+        let frame = InputFrame {
+            id,
+            count: kept.len() as u64,
+        };
+        let mut payload = struct_as_bytes(&frame.into_wire()).to_vec();
+        for ev in &kept {
+            payload.extend_from_slice(struct_as_bytes(&ev.into_wire()));
+        }
+        self.write_message_bytes(MessageType::InputFrame, &payload)
+    }
+}
+
+/// Whether the next event of the same code makes this one redundant. Multitouch
+/// slots and tracking ids aren't, they say which touch the next events are about.
+fn is_coalescible(ev: &InputEvent) -> bool {
+    ev.ty == EventKind::Relative as u16
+        || (ev.ty == EventKind::Absolute as u16
+            && ev.code != AbsoluteAxis::MultitouchSlot as u16
+            && ev.code != AbsoluteAxis::MultitouchTrackingId as u16)
+}
+
+/// Whether a later event of the same code can stand in for this one, once
+/// merged with it. Timestamps only say when the latest report was made.
+fn is_motion(ev: &InputEvent) -> bool {
+    is_coalescible(ev) || (ev.ty == EventKind::Misc as u16 && ev.code == MiscKind::Timestamp as u16)
+}
+
+/// Merges a motion event into those held back: the latest value of each axis
+/// of a device, and the sum of each relative one. Returns whether it took the
+/// place of an earlier one.
+fn hold(held: &mut Vec<InputEvent>, ev: &InputEvent) -> bool {
+    let earlier = held
+        .iter_mut()
+        .find(|held| held.id == ev.id && held.ty == ev.ty && held.code == ev.code);
+    match earlier {
+        Some(earlier) if ev.ty == EventKind::Relative as u16 => {
+            let value = earlier.value.saturating_add(ev.value);
+            *earlier = *ev;
+            earlier.value = value;
+            true
+        }
+        Some(earlier) => {
+            *earlier = *ev;
+            true
+        }
+        None => {
+            held.push(*ev);
+            false
+        }
+    }
+}
+
+/// Thins the reports of each device down to `--max-hz`.
+struct RateLimiter {
+    interval: Duration,
+    devices: HashMap<u64, Throttle>,
+}
+
+#[derive(Default)]
+struct Throttle {
+    last_sent: Option<Instant>,
+    /// The motion held back since then: the latest value of each axis, and the
+    /// sum of each relative one
+    pending: Vec<InputEvent>,
+}
+
+impl RateLimiter {
+    fn new(hz: u32) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(1) / hz,
+            devices: HashMap::new(),
+        }
+    }
+    /// Returns whether the events of device `id` go out now, in which case the
+    /// motion held back goes in front of them. A whole report that comes
+    /// sooner than the rate allows and only moves axes is held back instead.
+    /// Key presses and anything else that isn't motion always go through.
+    fn pass(&mut self, id: u64, frame: &mut Vec<InputEvent>, report: bool, now: Instant) -> bool {
+        let throttle = self.devices.entry(id).or_default();
+        let early = throttle
+            .last_sent
+            .is_some_and(|last| now < last + self.interval);
+        let only_motion = frame.iter().all(|ev| {
+            is_motion(ev)
+                || (ev.ty == EventKind::Synchronize as u16
+                    && ev.code == SynchronizeKind::Report as u16)
+        });
+        if report && early && only_motion {
+            for ev in frame.iter().filter(|ev| is_motion(ev)) {
+                hold(&mut throttle.pending, ev);
+            }
+            return false;
+        }
+        if report {
+            throttle.last_sent = Some(now);
+        }
+        frame.splice(0..0, mem::take(&mut throttle.pending));
+        true
+    }
+    /// When the motion held back for the first device is due.
+    fn next_due(&self) -> Option<Instant> {
+        self.devices
+            .values()
+            .filter(|throttle| !throttle.pending.is_empty())
+            .filter_map(|throttle| throttle.last_sent)
+            .min()
+            .map(|last| last + self.interval)
+    }
+    /// Takes the motion held back that is due at `now`, as a report for each
+    /// device.
+    fn due(&mut self, now: Instant) -> Vec<(u64, Vec<InputEvent>)> {
+        let mut due = Vec::new();
+        for (id, throttle) in &mut self.devices {
+            if throttle.pending.is_empty()
+                || throttle
+                    .last_sent
+                    .is_some_and(|last| now < last + self.interval)
+            {
+                continue;
+            }
+            throttle.last_sent = Some(now);
+            let mut report = mem::take(&mut throttle.pending);
+            report.push(syn_report(*id));
+            due.push((*id, report));
+        }
+        due
     }
 }
 
@@ -237,296 +1802,1895 @@ fn recv_from_client(
             clients.remove(&fd);
             None
         }
+        // Spurious wakeup, nothing was consumed so the client can just try again
+        Err(e) if e.is_transient() => None,
         Err(e) => {
-            eprintln!("Client {} disconnected with error: {:?}", fd, e);
-            epoll.delete(&client.socket).unwrap();
-            clients.remove(&fd);
+            drop_client(clients, epoll, fd, e);
             None
         }
     }
 }
 
-fn hangup_on_error_bcast<F>(clients: &mut HashMap<u64, Client>, epoll: &Epoll, mut f: F)
-where
-    F: FnMut(&mut Client) -> Result<()>,
-{
-    clients.retain(|k, v| {
-        if v.waiting_for == WaitingFor::Hello {
-            return true;
-        }
-        let res = f(v);
-        if res.is_err() {
-            eprintln!(
-                "Client {} disconnected with error: {:?}",
-                *k,
-                res.unwrap_err()
-            );
-            epoll.delete(&v.socket).unwrap();
-            false
-        } else {
-            true
-        }
-    });
+/// Pings the clients that answer pings, and drops those that stopped.
+fn ping_clients(clients: &mut HashMap<u64, Client>, epoll: &Epoll, seq: u64) {
+    let fds: Vec<u64> = clients
+        .iter()
+        .filter(|(_, client)| client.pings)
+        .map(|(fd, _)| *fd)
+        .collect();
+    for fd in fds {
+        let client = clients.get_mut(&fd).unwrap();
+        match client.ping_sent {
+            Some(sent) if sent.elapsed() >= PING_TIMEOUT => {
+                drop_client(clients, epoll, fd, Error::Unresponsive)
+            }
+            Some(_) => {}
+            None => hangup_on_error(clients, epoll, fd, |client| {
+                client.ping_sent = Some(Instant::now());
+                client.write_message(MessageType::Ping, &Ping { seq }.into_wire())
+            }),
+        }
+    }
+}
+
+fn drop_client(clients: &mut HashMap<u64, Client>, epoll: &Epoll, fd: u64, err: Error) {
+    info!("Client {} disconnected with error: {}", fd, err);
+    if let Some(client) = clients.remove(&fd) {
+        epoll.delete(&client.socket).unwrap();
+    }
+}
+
+/// Whether the client can survive `err`. A device going away or failing
+/// under us is not the client's fault.
+fn is_client_error(err: &Error) -> bool {
+    !matches!(err, Error::DeviceGone(_) | Error::DeviceFailed(..))
+}
+
+/// Returns the first error that was the fault of a device rather than of a client.
+fn hangup_on_error_bcast<F>(
+    clients: &mut HashMap<u64, Client>,
+    epoll: &Epoll,
+    mut f: F,
+) -> Option<Error>
+where
+    F: FnMut(&mut Client) -> hidpipe::Result<()>,
+{
+    hangup_on_error_bcast_with(clients, epoll, |client, _| f(client))
+}
+
+/// Like [`hangup_on_error_bcast`], the handler also gets the other clients,
+/// to send them something too for example. Each client is taken out of the
+/// map while it is handled, and those that fail are only dropped once every
+/// client was handled, so none is skipped or handled twice.
+fn hangup_on_error_bcast_with<F>(
+    clients: &mut HashMap<u64, Client>,
+    epoll: &Epoll,
+    mut f: F,
+) -> Option<Error>
+where
+    F: FnMut(&mut Client, &mut HashMap<u64, Client>) -> hidpipe::Result<()>,
+{
+    let mut failed = Vec::new();
+    let mut device_error = None;
+    let fds: Vec<u64> = clients.keys().copied().collect();
+    for fd in fds {
+        let Some(mut client) = clients.remove(&fd) else {
+            continue;
+        };
+        if client.waiting_for != WaitingFor::Hello {
+            match f(&mut client, clients) {
+                Err(e) if is_client_error(&e) => failed.push((fd, e)),
+                Err(e) => {
+                    warn!("Failed to send to client {}, error: {}", fd, e);
+                    device_error.get_or_insert(e);
+                }
+                Ok(()) => {}
+            }
+        }
+        clients.insert(fd, client);
+    }
+    for (fd, e) in failed {
+        drop_client(clients, epoll, fd, e);
+    }
+    device_error
+}
+
+/// Logs every device the server has open, asked for with SIGUSR2.
+fn log_devices(evdevs: &EvdevContainer) {
+    let mut devs: Vec<_> = evdevs.all().collect();
+    devs.sort_by_key(|(id, _)| *id);
+    info!("Forwarding {} devices", devs.len());
+    for (id, dev) in devs {
+        let name = dev.device_name().map(trim_nul).unwrap_or_default();
+        let input_id = dev.device_id().unwrap_or_default();
+        let ff = dev.effects_count().is_ok_and(|count| count > 0);
+        info!(
+            "Device {}: fd {}, {} ({:04x}:{:04x}), {}, {}",
+            id,
+            dev.as_inner().as_raw_fd(),
+            String::from_utf8_lossy(&name),
+            input_id.vendor,
+            input_id.product,
+            if evdevs.is_enabled(id) {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            if ff {
+                "force feedback"
+            } else {
+                "no force feedback"
+            }
+        );
+    }
+}
+
+/// Tells clients a device went away, and forwards the joystick on standby
+/// that takes its place if there is one.
+fn announce_removal(
+    evdevs: &mut EvdevContainer,
+    clients: &mut HashMap<u64, Client>,
+    epoll: &Epoll,
+    removed: Option<u64>,
+) {
+    if let Some(id) = removed {
+        hangup_on_error_bcast(clients, epoll, |client| client.write_removal(id));
+    }
+    if let Some(id) = evdevs.promote() {
+        info!("Device {} is now the primary joystick", id);
+        announce_device(evdevs, clients, epoll, id, MessageType::AddDevice);
+    }
+}
+
+/// Sends the record of a device to every client. A device that can't describe
+/// itself is closed rather than letting it take the clients down, the ones
+/// that already got the record are told it is gone.
+fn announce_device(
+    evdevs: &mut EvdevContainer,
+    clients: &mut HashMap<u64, Client>,
+    epoll: &Epoll,
+    id: u64,
+    ty: MessageType,
+) {
+    let Some(e) = hangup_on_error_bcast(clients, epoll, |client| {
+        send_device_record(evdevs, id, ty, client)
+    }) else {
+        return;
+    };
+    warn!("Closing device {}, error: {}", id, e);
+    if let Some(id) = evdevs.close(id, epoll) {
+        hangup_on_error_bcast(clients, epoll, |client| client.write_removal(id));
+    }
+}
+
+fn hangup_on_error<F>(clients: &mut HashMap<u64, Client>, epoll: &Epoll, fd: u64, f: F)
+where
+    F: FnOnce(&mut Client) -> hidpipe::Result<()>,
+{
+    let client = clients.get_mut(&fd).unwrap();
+    match f(client) {
+        Err(e) if is_client_error(&e) => drop_client(clients, epoll, fd, e),
+        Err(e) => warn!("Failed to send to client {}, error: {}", fd, e),
+        Ok(()) => {}
+    }
+}
+
+fn handle_control(
+    cmd: Command,
+    evdevs: &mut EvdevContainer,
+    clients: &mut HashMap<u64, Client>,
+    epoll: &Epoll,
+    ff: &mut ForceFeedback,
+) -> String {
+    match cmd {
+        Command::List => {
+            let mut reply = String::new();
+            for (id, dev) in evdevs.all() {
+                let name = dev.device_name().map(trim_nul).unwrap_or_default();
+                let state = if evdevs.is_enabled(id) {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                let grab = if evdevs.is_ungrabbed(id) {
+                    ",ungrabbed"
+                } else {
+                    ""
+                };
+                reply.push_str(&format!(
+                    "{} {}{} {}\n",
+                    id,
+                    state,
+                    grab,
+                    String::from_utf8_lossy(&name)
+                ));
+            }
+            reply
+        }
+        Command::Clients => {
+            let mut reply = String::new();
+            for (fd, client) in clients.iter() {
+                reply.push_str(&format!(
+                    "{} queued={} dropped={}\n",
+                    fd,
+                    client.queue.len(),
+                    client.dropped
+                ));
+            }
+            reply
+        }
+        Command::Enable(id)
+        | Command::Disable(id)
+        | Command::Watch(id)
+        | Command::Rumble(id, _)
+        | Command::Reset(id)
+        | Command::Unforward(id)
+            if evdevs.get(id).is_none() =>
+        {
+            format!("Unknown device {}\n", id)
+        }
+        Command::Disable(id) => {
+            if evdevs.set_enabled(id, false) {
+                hangup_on_error_bcast(clients, epoll, |client| client.write_removal(id));
+            }
+            "ok\n".to_string()
+        }
+        Command::Enable(id) => {
+            if evdevs.set_enabled(id, true) {
+                announce_device(evdevs, clients, epoll, id, MessageType::AddDevice);
+            }
+            "ok\n".to_string()
+        }
+        Command::Forward(device) => {
+            let Some(device) = DeviceMatch::parse(&device) else {
+                return format!("Invalid device {}\n", device);
+            };
+            match evdevs.force(device, epoll) {
+                Ok(added) => {
+                    // Joysticks put on standby are announced once promoted
+                    for &id in &added {
+                        if evdevs.is_enabled(id) {
+                            announce_device(evdevs, clients, epoll, id, MessageType::AddDevice);
+                        }
+                    }
+                    for id in evdevs.add_pending_sensors(epoll) {
+                        announce_device(evdevs, clients, epoll, id, MessageType::AddDevice);
+                    }
+                    format!("Forwarding {} new devices\n", added.len())
+                }
+                Err(e) => format!("Failed to look for devices: {}\n", e),
+            }
+        }
+        Command::Unforward(id) => {
+            let removed = evdevs.unforward(id, epoll);
+            announce_removal(evdevs, clients, epoll, removed);
+            "ok\n".to_string()
+        }
+        Command::Reset(id) => match (evdevs.abs_infos(id), evdevs.resync(id)) {
+            (Ok(infos), Ok(events)) => {
+                if evdevs.is_enabled(id) {
+                    hangup_on_error_bcast(clients, epoll, |client| {
+                        if !client.has(id) {
+                            return Ok(());
+                        }
+                        // The limits first, the values may only fit the new ones
+                        infos.iter().try_for_each(|info| {
+                            client.write_message_bytes(
+                                MessageType::UpdateAbsInfo,
+                                &info.into_wire().to_bytes(),
+                            )
+                        })?;
+                        client.write_frame(id, &events)
+                    });
+                }
+                "ok\n".to_string()
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                format!("Failed to read the state of device {}: {}\n", id, e)
+            }
+        },
+        Command::Rumble(_, client) if !clients.contains_key(&client) => {
+            format!("Unknown client {}\n", client)
+        }
+        Command::Rumble(id, client) => {
+            ff.set_owner(evdevs, id, client);
+            info!(
+                "Client {} now drives the force feedback of device {}",
+                client, id
+            );
+            "ok\n".to_string()
+        }
+        // Handled by the caller, the connection stays open
+        Command::Watch(id) => format!("Device {} can only be watched on its own connection\n", id),
+    }
+}
+
+/// What to do with the touchpads of controllers.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum TouchpadMode {
+    #[default]
+    Ignore,
+    Forward,
+    Mouse,
+}
+
+#[derive(Default)]
+struct Options {
+    motion: bool,
+    compact_snapshot: bool,
+    lazy: bool,
+    no_instance_tag: bool,
+    no_clamp: bool,
+    deadzone_filter: bool,
+    no_autocenter: bool,
+    joydev_map: bool,
+    grab: bool,
+    skip_ungrabbed: bool,
+    touchpads: TouchpadMode,
+    primary: bool,
+    primary_match: Option<DeviceMatch>,
+    allow: Vec<DeviceMatch>,
+    deny: Vec<DeviceMatch>,
+    skip_phys: Vec<String>,
+    classes: Option<Vec<DeviceClass>>,
+    socket: Option<String>,
+    /// Also accepts clients on this TCP address
+    tcp: Option<String>,
+    /// Also accepts guests on this vsock port
+    vsock_port: Option<u32>,
+    socket_mode: Option<u32>,
+    socket_owner: Option<(u32, Option<u32>)>,
+    devices_from: Option<String>,
+    trace_file: Option<String>,
+    trace_max_size: Option<u64>,
+    /// How often to log what the server forwards
+    stats_interval: Option<Duration>,
+    /// The most reports a second forwarded for each device
+    max_hz: Option<u32>,
+    /// The most clients connected at once
+    max_clients: Option<usize>,
+    command: Option<Command>,
+}
+
+const USAGE: &str = "\
+Usage: hidpipe-server [--motion] [--compact-snapshot] [--lazy] [--no-instance-tag]
+                      [--no-clamp] [--deadzone-filter] [--no-autocenter]
+                      [--joydev-map]
+                      [--grab [--skip-ungrabbed]]
+                      [--touchpads ignore|forward|mouse] [--devices-from <path>]
+                      [--primary [--primary-match <vendor>:<product>|<path>]]
+                      [--allow <vendor>:<product>] [--deny <vendor>:<product>]
+                      [--skip-phys <prefix>] [--devices gamepad,mouse,keyboard]
+                      [--trace-file <path> [--trace-max-size <bytes>]]
+                      [--stats-interval <seconds>] [--max-hz <rate>]
+                      [--max-clients <count>]
+                      [--socket <path>] [--socket-mode <octal>]
+                      [--socket-owner <uid>[:<gid>]] [--tcp <address>:<port>]
+                      [--vsock-port <port>]
+       hidpipe-server --list-devices | --list-clients
+       hidpipe-server --enable-device <id> | --disable-device <id>
+       hidpipe-server --watch <id> | --reset-device <id>
+       hidpipe-server --forward-device <path>|<vendor>:<product>
+       hidpipe-server --unforward-device <id>
+       hidpipe-server --rumble-owner <id> <client>
+       hidpipe-server --help | --version";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        hidpipe::common_flag(&arg, "hidpipe-server", USAGE);
+        match arg.as_str() {
+            "--motion" => options.motion = true,
+            "--compact-snapshot" => options.compact_snapshot = true,
+            "--lazy" => options.lazy = true,
+            "--no-instance-tag" => options.no_instance_tag = true,
+            "--no-clamp" => options.no_clamp = true,
+            "--deadzone-filter" => options.deadzone_filter = true,
+            "--no-autocenter" => options.no_autocenter = true,
+            "--joydev-map" => options.joydev_map = true,
+            "--grab" => options.grab = true,
+            "--skip-ungrabbed" => options.skip_ungrabbed = true,
+            "--touchpads" => {
+                options.touchpads = match args.next().as_deref() {
+                    Some("ignore") => TouchpadMode::Ignore,
+                    Some("forward") => TouchpadMode::Forward,
+                    Some("mouse") => TouchpadMode::Mouse,
+                    _ => usage(),
+                }
+            }
+            "--primary" => options.primary = true,
+            "--primary-match" => {
+                options.primary_match = Some(
+                    args.next()
+                        .and_then(|device| DeviceMatch::parse(&device))
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--allow" => options.allow.push(
+                args.next()
+                    .and_then(|device| DeviceMatch::parse(&device))
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--deny" => options.deny.push(
+                args.next()
+                    .and_then(|device| DeviceMatch::parse(&device))
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--skip-phys" => options
+                .skip_phys
+                .push(args.next().unwrap_or_else(|| usage())),
+            "--devices" => {
+                let mut classes = args
+                    .next()
+                    .and_then(|list| {
+                        list.split(',')
+                            .map(DeviceClass::parse)
+                            .collect::<Option<Vec<_>>>()
+                    })
+                    .unwrap_or_else(|| usage());
+                classes.sort();
+                classes.dedup();
+                options.classes = Some(classes);
+            }
+            "--devices-from" => options.devices_from = Some(args.next().unwrap_or_else(|| usage())),
+            "--trace-file" => options.trace_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--socket" => options.socket = Some(args.next().unwrap_or_else(|| usage())),
+            "--tcp" => options.tcp = Some(args.next().unwrap_or_else(|| usage())),
+            "--vsock-port" => {
+                options.vsock_port = Some(
+                    args.next()
+                        .and_then(|port| port.parse::<u32>().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--socket-mode" => {
+                options.socket_mode = Some(
+                    args.next()
+                        .and_then(|mode| u32::from_str_radix(&mode, 8).ok())
+                        .filter(|mode| *mode <= 0o777)
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--socket-owner" => {
+                let owner = args.next().unwrap_or_else(|| usage());
+                let (uid, gid) = match owner.split_once(':') {
+                    Some((uid, gid)) => (uid, Some(gid)),
+                    None => (owner.as_str(), None),
+                };
+                let uid = uid.parse::<u32>().unwrap_or_else(|_| usage());
+                let gid = gid.map(|gid| gid.parse::<u32>().unwrap_or_else(|_| usage()));
+                options.socket_owner = Some((uid, gid));
+            }
+            "--trace-max-size" => {
+                options.trace_max_size = Some(
+                    args.next()
+                        .and_then(|size| size.parse::<u64>().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--stats-interval" => {
+                options.stats_interval = Some(
+                    args.next()
+                        .and_then(|secs| secs.parse::<u64>().ok())
+                        .filter(|secs| *secs > 0)
+                        .map(Duration::from_secs)
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--max-hz" => {
+                options.max_hz = Some(
+                    args.next()
+                        .and_then(|hz| hz.parse::<u32>().ok())
+                        .filter(|hz| *hz > 0)
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--max-clients" => {
+                options.max_clients = Some(
+                    args.next()
+                        .and_then(|count| count.parse::<usize>().ok())
+                        .filter(|count| *count > 0)
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--list-devices" => options.command = Some(Command::List),
+            "--list-clients" => options.command = Some(Command::Clients),
+            "--forward-device" => {
+                options.command = Some(Command::Forward(args.next().unwrap_or_else(|| usage())))
+            }
+            "--enable-device" | "--disable-device" | "--watch" | "--reset-device"
+            | "--unforward-device" => {
+                let id = args
+                    .next()
+                    .and_then(|id| id.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage());
+                options.command = Some(match arg.as_str() {
+                    "--enable-device" => Command::Enable(id),
+                    "--disable-device" => Command::Disable(id),
+                    "--reset-device" => Command::Reset(id),
+                    "--unforward-device" => Command::Unforward(id),
+                    _ => Command::Watch(id),
+                });
+            }
+            "--rumble-owner" => {
+                let mut next = || {
+                    args.next()
+                        .and_then(|id| id.parse::<u64>().ok())
+                        .unwrap_or_else(|| usage())
+                };
+                let id = next();
+                options.command = Some(Command::Rumble(id, next()));
+            }
+            _ => {
+                eprintln!("Unknown argument {}", arg);
+                usage();
+            }
+        }
+    }
+    options
+}
+
+/// Listens for guests on the vsock port, whatever their CID.
+fn listen_vsock(port: u32) -> Result<OwnedFd> {
+    let listener = socket::socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    socket::bind(
+        listener.as_raw_fd(),
+        &VsockAddr::new(libc::VMADDR_CID_ANY, port),
+    )?;
+    socket::listen(&listener, Backlog::new(16).unwrap())?;
+    Ok(listener)
 }
 
-fn hangup_on_error<F>(clients: &mut HashMap<u64, Client>, epoll: &Epoll, fd: u64, f: F)
-where
-    F: FnOnce(&mut Client) -> Result<()>,
-{
-    let client = clients.get_mut(&fd).unwrap();
-    let res = f(client);
-    if res.is_err() {
-        eprintln!(
-            "Client {} disconnected with error: {:?}",
-            fd,
-            res.unwrap_err()
-        );
-        epoll.delete(&client.socket).unwrap();
-        clients.remove(&fd);
+/// The socket systemd passed us when started through socket activation.
+fn systemd_listener() -> Option<UnixListener> {
+    const SD_LISTEN_FDS_START: i32 = 3;
+    let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    // The variables may have been inherited from whoever started us
+    if pid != process::id() || fds == 0 {
+        return None;
     }
+    if fds > 1 {
+        warn!("systemd passed {} sockets, only using the first one", fds);
+    }
+    // SAFETY: systemd hands the fds from SD_LISTEN_FDS_START on over to us
+    Some(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
 }
 
 fn main() {
+    log::init();
+    let options = parse_args();
+    if let Some(cmd) = &options.command {
+        match control::send(cmd, &mut io::stdout()) {
+            Ok(()) => {}
+            Err(e) => {
+                error!("Unable to reach the running server, error: {:?}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     if getresuid().unwrap().real.is_root() {
-        eprintln!("You are trying to run hidpipe as root. Unless your entire desktop session runs as root, this is most likely not what you want.")
+        warn!("You are trying to run hidpipe as root. Unless your entire desktop session runs as root, this is most likely not what you want.")
     }
+    raise_fd_limit();
+    let mut spare_fd = File::open("/dev/null").ok();
     let udev_socket = MonitorBuilder::new()
         .unwrap()
         .match_subsystem("input")
         .unwrap()
         .listen()
         .unwrap();
-    let mut evdevs = EvdevContainer::new();
+    let instance_tag = if options.no_instance_tag {
+        None
+    } else {
+        Some(random_tag())
+    };
+    let device_list = options.devices_from.as_ref().map(|path| {
+        load_device_list(path).unwrap_or_else(|e| {
+            error!("Failed to read the device list, error: {:?}", e);
+            process::exit(1);
+        })
+    });
+    let mut evdevs = EvdevContainer::new(&options, instance_tag, device_list);
     let mut clients = HashMap::new();
     let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
-    for dir_ent in fs::read_dir("/dev/input/").unwrap() {
-        let dir_ent = dir_ent.unwrap();
+    let mut dir_ents: Vec<_> = fs::read_dir("/dev/input/")
+        .unwrap()
+        .map(|dir_ent| dir_ent.unwrap())
+        .collect();
+    // By node number, event2 before event10, for a stable plug order
+    dir_ents.sort_by_key(|dir_ent| (dir_ent.file_name().len(), dir_ent.file_name()));
+    for dir_ent in dir_ents {
         if dir_ent.file_type().unwrap().is_dir() {
             continue;
         }
         let name = dir_ent.file_name();
-        let res = evdevs.check_and_add(&name, dir_ent.path().as_os_str(), &epoll);
-        match res {
-            Ok(Some(_)) => eprintln!("{} is a joystick", name.to_string_lossy()),
-            Ok(None) => eprintln!("{} is not a joystick", name.to_string_lossy()),
-            Err(e) if e.kind() == ErrorKind::PermissionDenied => eprintln!(
+        match evdevs.check_and_add(&name, dir_ent.path().as_os_str(), &epoll) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => debug!(
                 "Unable to access {}, this is most likely fine",
                 name.to_string_lossy()
             ),
-            Err(e) => eprintln!(
+            Err(e) => warn!(
                 "Unable to determine if {} is a joystick, error: {:?}",
                 name.to_string_lossy(),
                 e
             ),
         }
     }
+    evdevs.add_pending_sensors(&epoll);
     epoll
-        .add(
-            &udev_socket,
-            EpollEvent::new(EpollFlags::EPOLLIN, udev_socket.as_raw_fd() as u64),
-        )
+        .add(&udev_socket, EpollEvent::new(EpollFlags::EPOLLIN, KEY_UDEV))
         .unwrap();
-    let xdg_dir = env::var("XDG_RUNTIME_DIR");
-    if xdg_dir.is_err() {
-        eprintln!(
-            "Unable to get XDG_RUNTIME_DIR, error: {:?}",
-            xdg_dir.unwrap_err()
-        );
-        return;
-    }
-    let sock_path = format!("{}/hidpipe", xdg_dir.unwrap());
-    _ = fs::remove_file(&sock_path);
-    let listen_sock = if env::var("$LISTEN_FDS")
-        .map(|x| x.parse::<u32>().unwrap() > 1)
-        .unwrap_or_default()
+    let sock_path = match options
+        .socket
+        .clone()
+        .or_else(|| env::var("HIDPIPE_SOCKET").ok())
     {
-        unsafe { UnixListener::from_raw_fd(3) }
+        Some(path) => path,
+        None => {
+            let xdg_dir = match env::var("XDG_RUNTIME_DIR") {
+                Ok(xdg_dir) => xdg_dir,
+                Err(e) => {
+                    error!("Unable to get XDG_RUNTIME_DIR, error: {:?}", e);
+                    return;
+                }
+            };
+            format!("{}/hidpipe", xdg_dir)
+        }
+    };
+    // Only the socket we bound ourselves is ours to remove
+    let (listen_sock, bound_path) = if let Some(listener) = systemd_listener() {
+        info!("Listening on the socket passed by systemd");
+        (listener, None)
     } else {
-        UnixListener::bind(sock_path).unwrap()
+        _ = fs::remove_file(&sock_path);
+        let mode = options.socket_mode.unwrap_or(0o600);
+        let listener = bind_private(&sock_path, mode).unwrap();
+        info!("Listening on {}", sock_path);
+        if let Some((uid, gid)) = options.socket_owner {
+            chown(&sock_path, Some(uid), gid).unwrap();
+        }
+        (listener, Some(&sock_path))
     };
     epoll
         .add(
             &listen_sock,
-            EpollEvent::new(EpollFlags::EPOLLIN, listen_sock.as_raw_fd() as u64),
+            EpollEvent::new(EpollFlags::EPOLLIN, KEY_LISTEN),
         )
         .unwrap();
-    let mut seen_effect = HashSet::new();
+    let tcp_sock = options.tcp.as_ref().map(|addr| {
+        let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+            error!("Unable to listen on {}, error: {:?}", addr, e);
+            process::exit(1);
+        });
+        info!("Listening on {} over TCP", listener.local_addr().unwrap());
+        epoll
+            .add(&listener, EpollEvent::new(EpollFlags::EPOLLIN, KEY_TCP))
+            .unwrap();
+        listener
+    });
+    let vsock_sock = options.vsock_port.map(|port| {
+        let listener = listen_vsock(port).unwrap_or_else(|e| {
+            error!("Unable to listen on vsock port {}, error: {:?}", port, e);
+            process::exit(1);
+        });
+        info!("Listening on vsock port {}", port);
+        epoll
+            .add(&listener, EpollEvent::new(EpollFlags::EPOLLIN, KEY_VSOCK))
+            .unwrap();
+        listener
+    });
+    let control_path = control::socket_path().unwrap();
+    _ = fs::remove_file(&control_path);
+    let control_sock = bind_private(&control_path, 0o600).unwrap();
+    epoll
+        .add(
+            &control_sock,
+            EpollEvent::new(EpollFlags::EPOLLIN, KEY_CONTROL),
+        )
+        .unwrap();
+    let signals =
+        signal::pipe(&[libc::SIGUSR1, libc::SIGUSR2, libc::SIGTERM, libc::SIGINT]).unwrap();
+    epoll
+        .add(&signals, EpollEvent::new(EpollFlags::EPOLLIN, KEY_SIGNALS))
+        .unwrap();
+    // Logs every forwarded event, toggled with SIGUSR1 to catch a glitch as it happens
+    let mut dump_events = false;
+    let log_level = log::level();
+    let mut ff = ForceFeedback::default();
+    let mut watchers: Vec<Watcher> = Vec::new();
+    let mut trace = options.trace_file.as_ref().map(|path| {
+        let max_size = options.trace_max_size.unwrap_or(trace::DEFAULT_MAX_SIZE);
+        Trace::open(path, max_size).unwrap()
+    });
+    let mut last_ping = Instant::now();
+    let mut ping_seq = 0;
+    // Events forwarded since the last stats were logged
+    let mut forwarded = 0u64;
+    let mut last_stats = Instant::now();
+    let mut limiter = options.max_hz.map(RateLimiter::new);
 
     loop {
-        let mut evts = [EpollEvent::empty()];
-        match epoll.wait(&mut evts, EpollTimeout::NONE) {
-            Err(Errno::EINTR) | Ok(0) => {
-                continue;
+        if evdevs.lazy && evdevs.active && clients.is_empty() {
+            evdevs.deactivate(&epoll);
+            watchers.clear();
+        }
+        ff.release_gone(&evdevs, &clients);
+        for (fd, client) in clients.iter_mut() {
+            client.update_polling(&epoll, *fd);
+        }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            ping_seq += 1;
+            ping_clients(&mut clients, &epoll, ping_seq);
+            last_ping = Instant::now();
+        }
+        if let Some(interval) = options.stats_interval {
+            let elapsed = last_stats.elapsed();
+            if elapsed >= interval {
+                info!(
+                    "Forwarded {:.1} events/s, {} clients, {} devices",
+                    forwarded as f64 / elapsed.as_secs_f64(),
+                    clients.len(),
+                    evdevs.iter().count()
+                );
+                forwarded = 0;
+                last_stats = Instant::now();
             }
-            Ok(_) => {}
-            e => {
-                e.unwrap();
-            }
-        }
-        let fd = evts[0].data();
-        if fd == udev_socket.as_raw_fd() as u64 {
-            for event in udev_socket.iter() {
-                match event.event_type() {
-                    EventType::Remove => {
-                        if let Some(id) = evdevs.remove(event.sysname(), &epoll) {
-                            hangup_on_error_bcast(&mut clients, &epoll, |client| {
-                                client.write(&MessageType::RemoveDevice)?;
-                                client.write(&RemoveDevice { id })
-                            });
-                        }
-                    }
-                    EventType::Add => {
-                        let name = event.sysname();
-                        let node = event.devnode();
-                        if node.is_none() {
-                            continue;
+        }
+        if let Some(limiter) = &mut limiter {
+            limiter.devices.retain(|id, _| evdevs.get(*id).is_some());
+            for (id, report) in limiter.due(Instant::now()) {
+                hangup_on_error_bcast(&mut clients, &epoll, |client| {
+                    client.write_frame(id, &report)
+                });
+            }
+        }
+        let ping_wait = clients
+            .values()
+            .any(|client| client.pings)
+            .then(|| PING_INTERVAL.saturating_sub(last_ping.elapsed()));
+        let stats_wait = options
+            .stats_interval
+            .map(|interval| interval.saturating_sub(last_stats.elapsed()));
+        let limiter_wait = limiter
+            .as_ref()
+            .and_then(RateLimiter::next_due)
+            .map(|due| due.saturating_duration_since(Instant::now()));
+        let timeout = match ping_wait
+            .into_iter()
+            .chain(stats_wait)
+            .chain(limiter_wait)
+            .min()
+        {
+            Some(wait) => EpollTimeout::try_from(wait).unwrap(),
+            None => EpollTimeout::NONE,
+        };
+        let mut evts = [EpollEvent::empty(); EPOLL_BATCH];
+        // Messages left over from a JSON frame don't wake epoll up
+        let decoded = clients
+            .iter()
+            .find(|(_, client)| !client.decoded.is_empty());
+        let count = if let Some((fd, _)) = decoded {
+            evts[0] = EpollEvent::new(EpollFlags::EPOLLIN, KEY_CLIENT | *fd);
+            1
+        } else {
+            match epoll.wait(&mut evts, timeout) {
+                Err(Errno::EINTR) | Ok(0) => {
+                    continue;
+                }
+                res => res.unwrap(),
+            }
+        };
+        // A client or device dropped by an earlier event can still have some in
+        // the batch, every branch checks that what it is for is still there
+        for evt in &evts[..count] {
+            let kind = evt.data() & KEY_KIND_MASK;
+            let fd = evt.data() & !KEY_KIND_MASK;
+            if kind == KEY_UDEV {
+                for event in udev_socket.iter() {
+                    match event.event_type() {
+                        EventType::Remove => {
+                            let removed = evdevs.remove(event.sysname(), &epoll);
+                            announce_removal(&mut evdevs, &mut clients, &epoll, removed);
+                            watchers.retain(|watcher| evdevs.get(watcher.id).is_some());
                         }
-                        let res = evdevs.check_and_add(name, node.unwrap().as_os_str(), &epoll);
-                        match res {
-                            Err(e) => {
-                                eprintln!(
-                                    "Unable to determine if {} is a joystick, error: {:?}",
-                                    name.to_string_lossy(),
-                                    e
+                        // Controllers switching modes can change what they support
+                        EventType::Change => {
+                            if let Some(id) = evdevs.refresh(event.sysname()) {
+                                announce_device(
+                                    &mut evdevs,
+                                    &mut clients,
+                                    &epoll,
+                                    id,
+                                    MessageType::UpdateDevice,
                                 );
                             }
-                            Ok(None) => {}
-                            Ok(Some(dev)) => {
-                                hangup_on_error_bcast(&mut clients, &epoll, |client| {
-                                    send_add_device(dev, client)
-                                });
+                        }
+                        EventType::Add => {
+                            let Some(devnode) = event.devnode() else {
+                                continue;
+                            };
+                            let (name, node) = resolve_node(event.sysname(), devnode);
+                            match evdevs.check_and_add(&name, node.as_os_str(), &epoll) {
+                                Err(e) => {
+                                    warn!(
+                                        "Unable to determine if {} is a joystick, error: {:?}",
+                                        name.to_string_lossy(),
+                                        e
+                                    );
+                                }
+                                Ok(Some(id)) if evdevs.is_enabled(id) => {
+                                    announce_device(
+                                        &mut evdevs,
+                                        &mut clients,
+                                        &epoll,
+                                        id,
+                                        MessageType::AddDevice,
+                                    );
+                                    // A wheel plugged in while a game runs needs it too
+                                    if options.no_autocenter && !clients.is_empty() {
+                                        if let Some(dev) = evdevs.get(id) {
+                                            disable_autocenter(id, dev);
+                                        }
+                                    }
+                                }
+                                Ok(_) => {}
+                            }
+                            for id in evdevs.add_pending_sensors(&epoll) {
+                                announce_device(
+                                    &mut evdevs,
+                                    &mut clients,
+                                    &epoll,
+                                    id,
+                                    MessageType::AddDevice,
+                                );
                             }
                         }
+                        _ => {}
                     }
-                    _ => {}
-                }
-            }
-        } else if fd == listen_sock.as_raw_fd() as u64 {
-            let res = listen_sock.accept();
-            if res.is_err() {
-                eprintln!(
-                    "Failed to accept a connection, error: {:?}",
-                    res.unwrap_err()
-                );
-                continue;
-            }
-            let stream = res.unwrap().0;
-            stream.set_nonblocking(true).unwrap();
-            let raw = stream.as_raw_fd() as u64;
-            epoll
-                .add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, raw))
-                .unwrap();
-            let client = Client::new(stream);
-            clients.insert(raw, client);
-        } else if let Some(client) = clients.get(&fd) {
-            if client.waiting_for == WaitingFor::Hello {
-                let data =
-                    recv_from_client(&mut clients, &epoll, fd, mem::size_of::<ClientHello>());
-                if data.is_none() {
-                    continue;
-                }
-                hangup_on_error(&mut clients, &epoll, fd, |client| {
-                    client.write(&ServerHello { version: 0 })?;
-                    for dev in evdevs.iter() {
-                        send_add_device(dev, client)?;
-                    }
-                    client.waiting_for = WaitingFor::Header;
-                    Ok(())
-                });
-            } else if client.waiting_for == WaitingFor::Header {
-                let data =
-                    recv_from_client(&mut clients, &epoll, fd, mem::size_of::<MessageType>());
-                if data.is_none() {
-                    continue;
                 }
-                let data = data.unwrap();
-                let msg_type = u32::from_ne_bytes(data.try_into().unwrap());
-                let client = clients.get_mut(&fd).unwrap();
-                if msg_type == MessageType::InputEvent as u32 {
-                    client.waiting_for = WaitingFor::InputEvent;
-                } else if msg_type == MessageType::FFUpload as u32 {
-                    client.waiting_for = WaitingFor::FFUpload;
-                } else if msg_type == MessageType::FFErase as u32 {
-                    client.waiting_for = WaitingFor::FFErase;
+            } else if kind == KEY_LISTEN || kind == KEY_TCP || kind == KEY_VSOCK {
+                let stream: Box<dyn Transport> = if kind == KEY_LISTEN {
+                    let accept = || listen_sock.accept().map(|(stream, _)| stream);
+                    let Some(stream) = accept_or_shed(accept, &mut spare_fd, "connection") else {
+                        continue;
+                    };
+                    stream.set_nonblocking(true).unwrap();
+                    Box::new(stream)
+                } else if kind == KEY_TCP {
+                    let accept = || tcp_sock.as_ref().unwrap().accept();
+                    let Some((stream, addr)) = accept_or_shed(accept, &mut spare_fd, "connection")
+                    else {
+                        continue;
+                    };
+                    stream.set_nonblocking(true).unwrap();
+                    // Messages are small and each one is late already
+                    stream.set_nodelay(true).unwrap();
+                    info!("Connection from {}", addr);
+                    Box::new(stream)
                 } else {
-                    eprintln!("Unknown message {} from client {}", msg_type, fd);
-                    client.socket.shutdown(Shutdown::Both).unwrap();
+                    // UnixListener refuses to accept anything but Unix sockets
+                    let accept = || {
+                        let fd = socket::accept(vsock_sock.as_ref().unwrap().as_raw_fd())?;
+                        // SAFETY: the fd was just accepted and nothing else owns it
+                        Ok(unsafe { UnixStream::from_raw_fd(fd) })
+                    };
+                    let Some(stream) = accept_or_shed(accept, &mut spare_fd, "connection") else {
+                        continue;
+                    };
+                    stream.set_nonblocking(true).unwrap();
+                    Box::new(stream)
+                };
+                if options.max_clients.is_some_and(|max| clients.len() >= max) {
+                    warn!(
+                        "Refusing a connection, {} clients are connected already",
+                        clients.len()
+                    );
                     continue;
                 }
-            } else if client.waiting_for == WaitingFor::InputEvent {
-                let data = recv_from_client(&mut clients, &epoll, fd, mem::size_of::<InputEvent>());
-                if data.is_none() {
-                    continue;
+                let raw = stream.as_raw_fd() as u64;
+                epoll
+                    .add(
+                        &stream,
+                        EpollEvent::new(EpollFlags::EPOLLIN, KEY_CLIENT | raw),
+                    )
+                    .unwrap();
+                let client = Client::new(stream, options.joydev_map);
+                clients.insert(raw, client);
+                info!("Client {} connected", raw);
+            } else if kind == KEY_SIGNALS {
+                for sig in signal::received(&signals) {
+                    if sig == libc::SIGUSR1 {
+                        dump_events = !dump_events;
+                        log::set_level(if dump_events { Level::Trace } else { log_level });
+                        info!(
+                            "Event dump {}",
+                            if dump_events { "enabled" } else { "disabled" }
+                        );
+                    } else if sig == libc::SIGUSR2 {
+                        log_devices(&evdevs);
+                    } else {
+                        info!("Stopping on signal {}", sig);
+                        if let Some(path) = bound_path {
+                            _ = fs::remove_file(path);
+                        }
+                        _ = fs::remove_file(&control_path);
+                        process::exit(0);
+                    }
                 }
-                let data = data.unwrap();
-                let event = unsafe { (data.as_ptr() as *const InputEvent).as_ref().unwrap() };
-                let evdev = evdevs.get(event.id);
-                if evdev.is_none() {
-                    eprintln!("Client {} sent input to unknown device {}", fd, event.id);
+            } else if kind == KEY_CONTROL {
+                let Some(mut stream) = accept_or_shed(
+                    || control_sock.accept().map(|(stream, _)| stream),
+                    &mut spare_fd,
+                    "control connection",
+                ) else {
                     continue;
+                };
+                let reply = match control::read_command(&stream) {
+                    Ok(Some(Command::Watch(id))) if evdevs.get(id).is_some() => {
+                        if let Err(e) = stream.set_nonblocking(true) {
+                            warn!("Failed to set up a watcher, error: {:?}", e);
+                            continue;
+                        }
+                        watchers.push(Watcher {
+                            id,
+                            stream,
+                            last_update: None,
+                            queue: Vec::new(),
+                        });
+                        continue;
+                    }
+                    Ok(Some(cmd)) => {
+                        handle_control(cmd, &mut evdevs, &mut clients, &epoll, &mut ff)
+                    }
+                    Ok(None) => "Unknown command\n".to_string(),
+                    Err(e) => {
+                        warn!("Failed to read a control command, error: {:?}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = stream.write_all(reply.as_bytes()) {
+                    warn!("Failed to reply to a control command, error: {:?}", e);
                 }
-                evdev.unwrap().write(&[event.to_input_event()]).unwrap();
-                clients.get_mut(&fd).unwrap().waiting_for = WaitingFor::Header;
-            } else if client.waiting_for == WaitingFor::FFUpload {
-                let data = recv_from_client(&mut clients, &epoll, fd, mem::size_of::<FFUpload>());
-                if data.is_none() {
-                    continue;
+            } else if kind == KEY_CLIENT && clients.contains_key(&fd) {
+                let events = evt.events();
+                if events.contains(EpollFlags::EPOLLOUT) {
+                    hangup_on_error(&mut clients, &epoll, fd, Client::catch_up);
+                    if !events.contains(EpollFlags::EPOLLIN) {
+                        continue;
+                    }
                 }
-                let mut data = data.unwrap();
-                let upload = unsafe { (data.as_mut_ptr() as *mut FFUpload).as_mut().unwrap() };
-                let evdev = evdevs.get(upload.id);
-                if evdev.is_none() {
-                    eprintln!("Client {} sent input to unknown device {}", fd, upload.id);
+                let Some(client) = clients.get(&fd) else {
                     continue;
-                }
-                if seen_effect.insert((upload.id, upload.effect.id)) {
-                    upload.effect.id = -1;
-                }
-                evdev
-                    .unwrap()
-                    .send_force_feedback(&mut upload.effect)
-                    .unwrap();
-                hangup_on_error(&mut clients, &epoll, fd, |client| {
+                };
+                if client.waiting_for == WaitingFor::Hello {
+                    let data =
+                        recv_from_client(&mut clients, &epoll, fd, mem::size_of::<ClientHello>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let hello = u32::from_le_bytes(data.unwrap()[..4].try_into().unwrap());
+                    let json = cfg!(feature = "json") && hello & HELLO_JSON != 0;
+                    let pings = hello & HELLO_PING != 0;
+                    // Other clients would not know what to do with the tag
+                    let tagged = hello & HELLO_INSTANCE_TAG != 0;
+                    let theirs = hello & HELLO_VERSION_MASK;
+                    if protocol_major(theirs) != protocol_major(PROTOCOL_VERSION) {
+                        hangup_on_error(&mut clients, &epoll, fd, |client| {
+                            // Still answer, so the client can tell why it is dropped
+                            let version = PROTOCOL_VERSION;
+                            client.write(&ServerHello { version }.into_wire())?;
+                            Err(Error::VersionMismatch {
+                                ours: PROTOCOL_VERSION,
+                                theirs,
+                            })
+                        });
+                        continue;
+                    }
+                    if !evdevs.active {
+                        evdevs.activate(&epoll);
+                    }
+                    let mut broken = Vec::new();
+                    hangup_on_error(&mut clients, &epoll, fd, |client| {
+                        let version = if json { HELLO_JSON } else { 0 };
+                        // We answer pings either way, it only matters to the client
+                        let version = version | HELLO_PING | PROTOCOL_VERSION;
+                        client.write(&ServerHello { version }.into_wire())?;
+                        client.json = json;
+                        client.pings = pings;
+                        client.input_frames = !json && theirs >= FRAMES_VERSION;
+                        if let Some(tag) = instance_tag.filter(|_| tagged) {
+                            client.write_message(
+                                MessageType::InstanceTag,
+                                &InstanceTag { tag }.into_wire(),
+                            )?;
+                        }
+                        if options.compact_snapshot && !client.json {
+                            send_snapshot(&evdevs, client)?;
+                        } else {
+                            for (id, _) in evdevs.iter() {
+                                match send_add_device(&evdevs, id, client) {
+                                    Err(e) if !is_client_error(&e) => {
+                                        warn!(
+                                            "Not sending a device to client {}, error: {}",
+                                            fd, e
+                                        );
+                                        broken.push(id);
+                                    }
+                                    res => res?,
+                                }
+                            }
+                        }
+                        client.waiting_for = WaitingFor::Header;
+                        Ok(())
+                    });
+                    // Its events would reach the clients that never heard of it,
+                    // close it like a broadcast would
+                    for id in broken {
+                        warn!("Closing device {}", id);
+                        let removed = evdevs.close(id, &epoll);
+                        announce_removal(&mut evdevs, &mut clients, &epoll, removed);
+                    }
+                    if options.no_autocenter {
+                        for (id, dev) in evdevs.iter() {
+                            disable_autocenter(id, dev);
+                        }
+                    }
+                } else if client.waiting_for == WaitingFor::Header {
+                    let data = recv_from_client(&mut clients, &epoll, fd, HEADER_SIZE);
+                    if data.is_none() {
+                        continue;
+                    }
+                    let (msg_type, len) = parse_header(&data.unwrap().try_into().unwrap());
+                    trace!("Client {} sent message {} of {} bytes", fd, msg_type, len);
+                    let (waiting_for, size) = if msg_type == MessageType::InputEvent as u32 {
+                        (WaitingFor::InputEvent, mem::size_of::<InputEvent>())
+                    } else if msg_type == MessageType::FFUpload as u32 {
+                        (WaitingFor::FFUpload, mem::size_of::<FFUpload>())
+                    } else if msg_type == MessageType::FFErase as u32 {
+                        (WaitingFor::FFErase, mem::size_of::<FFErase>())
+                    } else if msg_type == MessageType::Ping as u32 {
+                        (WaitingFor::Ping, mem::size_of::<Ping>())
+                    } else if msg_type == MessageType::Pong as u32 {
+                        (WaitingFor::Pong, mem::size_of::<Ping>())
+                    } else if msg_type == MessageType::Subscribe as u32 {
+                        (WaitingFor::Subscribe, mem::size_of::<Subscribe>())
+                    } else {
+                        let err = Error::Protocol(format!("unknown message {}", msg_type));
+                        drop_client(&mut clients, &epoll, fd, err);
+                        continue;
+                    };
+                    if let Err(err) = check_len(msg_type, len, size) {
+                        drop_client(&mut clients, &epoll, fd, err);
+                        continue;
+                    }
+                    clients.get_mut(&fd).unwrap().waiting_for = waiting_for;
+                } else if client.waiting_for == WaitingFor::InputEvent {
+                    let data =
+                        recv_from_client(&mut clients, &epoll, fd, mem::size_of::<InputEvent>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let data = data.unwrap();
+                    let mut event =
+                        unsafe { ptr::read_unaligned(data.as_ptr() as *const InputEvent) }
+                            .into_native();
+                    clients.get_mut(&fd).unwrap().waiting_for = WaitingFor::Header;
+                    let Some(evdev) = evdevs.forwarded(event.id) else {
+                        warn!("Client {} sent input to unknown device {}", fd, event.id);
+                        continue;
+                    };
+                    if event.ty == EventKind::ForceFeedback as u16
+                        && ff.owner(event.id).is_some_and(|owner| owner != fd)
+                    {
+                        continue;
+                    }
+                    ff.effects.translate(&mut event);
+                    // Unplugged, udev or the fd may only say so later
+                    if let Err(e) = write_to_source(evdev, &event) {
+                        warn!(
+                            "Failed to write to device {}, error: {:?}, dropping it",
+                            event.id, e
+                        );
+                        let removed = evdevs.close(event.id, &epoll);
+                        announce_removal(&mut evdevs, &mut clients, &epoll, removed);
+                        watchers.retain(|watcher| evdevs.get(watcher.id).is_some());
+                    }
+                } else if client.waiting_for == WaitingFor::FFUpload {
+                    let data =
+                        recv_from_client(&mut clients, &epoll, fd, mem::size_of::<FFUpload>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let data = data.unwrap();
+                    let mut upload =
+                        unsafe { ptr::read_unaligned(data.as_ptr() as *const FFUpload) }
+                            .into_native();
+                    if let Some(evdev) = evdevs.forwarded(upload.id) {
+                        ff.upload(evdev, fd, &mut upload);
+                    } else {
+                        // Answered all the same, the game waits for the upload to end
+                        warn!("Client {} sent input to unknown device {}", fd, upload.id);
+                        upload.retval = -libc::ENODEV;
+                    }
+                    hangup_on_error(&mut clients, &epoll, fd, |client| {
+                        client.waiting_for = WaitingFor::Header;
+                        client.write_message_bytes(
+                            MessageType::FFUpload,
+                            &upload.into_wire().to_bytes(),
+                        )
+                    });
+                } else if client.waiting_for == WaitingFor::FFErase {
+                    let data =
+                        recv_from_client(&mut clients, &epoll, fd, mem::size_of::<FFErase>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let data = data.unwrap();
+                    let erase = unsafe { ptr::read_unaligned(data.as_ptr() as *const FFErase) }
+                        .into_native();
+                    if let Some(evdev) = evdevs.forwarded(erase.id) {
+                        if let Err(e) = ff.erase(evdev, fd, &erase) {
+                            warn!(
+                                "Failed to erase an effect from device {}, error: {:?}, dropping it",
+                                erase.id, e
+                            );
+                            let removed = evdevs.close(erase.id, &epoll);
+                            announce_removal(&mut evdevs, &mut clients, &epoll, removed);
+                            watchers.retain(|watcher| evdevs.get(watcher.id).is_some());
+                        }
+                    } else {
+                        warn!("Client {} sent input to unknown device {}", fd, erase.id);
+                    }
+                    hangup_on_error(&mut clients, &epoll, fd, |client| {
+                        client.waiting_for = WaitingFor::Header;
+                        client.write_message(MessageType::FFErase, &erase.into_wire())
+                    });
+                } else if client.waiting_for == WaitingFor::Ping
+                    || client.waiting_for == WaitingFor::Pong
+                {
+                    let data = recv_from_client(&mut clients, &epoll, fd, mem::size_of::<Ping>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let data = data.unwrap();
+                    let ping =
+                        unsafe { ptr::read_unaligned(data.as_ptr() as *const Ping) }.into_native();
+                    let client = clients.get_mut(&fd).unwrap();
+                    let answer = client.waiting_for == WaitingFor::Ping;
                     client.waiting_for = WaitingFor::Header;
-                    client.write(&MessageType::FFUpload)?;
-                    client.write(upload)
-                });
-            } else if client.waiting_for == WaitingFor::FFErase {
-                let data = recv_from_client(&mut clients, &epoll, fd, mem::size_of::<FFErase>());
-                if data.is_none() {
-                    continue;
+                    if answer {
+                        hangup_on_error(&mut clients, &epoll, fd, |client| {
+                            client.write_message(MessageType::Pong, &ping.into_wire())
+                        });
+                    } else {
+                        client.ping_sent = None;
+                    }
+                } else if client.waiting_for == WaitingFor::Subscribe {
+                    let data =
+                        recv_from_client(&mut clients, &epoll, fd, mem::size_of::<Subscribe>());
+                    if data.is_none() {
+                        continue;
+                    }
+                    let data = data.unwrap();
+                    let subscription =
+                        unsafe { ptr::read_unaligned(data.as_ptr() as *const Subscribe) }
+                            .into_native();
+                    match subscription.count {
+                        0 => debug!("Client {} wants every device", fd),
+                        count => debug!("Client {} wants {} models", fd, count),
+                    }
+                    hangup_on_error(&mut clients, &epoll, fd, |client| {
+                        client.waiting_for = WaitingFor::Header;
+                        client.subscription = Some(subscription);
+                        apply_subscription(&evdevs, client)
+                    });
                 }
-                let mut data = data.unwrap();
-                let erase = unsafe { (data.as_mut_ptr() as *const FFErase).as_ref().unwrap() };
-                let evdev = evdevs.get(erase.id);
-                if evdev.is_none() {
-                    eprintln!("Client {} sent input to unknown device {}", fd, erase.id);
-                    continue;
+            } else if kind == KEY_DEVICE && evdevs.get(fd).is_some() {
+                // Devices are registered under their id rather than their fd
+                let id = fd;
+                let flags = evt.events();
+                let enabled = evdevs.is_enabled(id);
+                let mut evts = [empty_input_event(); DEVICE_BATCH];
+                let mut frame = Vec::new();
+                while let Ok(count) = evdevs.get(id).unwrap().read(&mut evts) {
+                    if count == 0 {
+                        break;
+                    }
+                    for event in &mut evts[..count] {
+                        let raw_value = event.value;
+                        if !options.no_clamp && evdevs.clamp(id, event) {
+                            debug!(
+                                "Device {} reported {} for axis {}, outside of its range",
+                                id, raw_value, event.code
+                            );
+                        }
+                        evdevs.update_state(id, event);
+                        if event.type_ == EventKind::Synchronize as u16
+                            && event.code == SynchronizeKind::Report as u16
+                        {
+                            let state = evdevs.state(id).unwrap();
+                            watchers.retain_mut(|watcher| {
+                                if watcher.id != id
+                                    || watcher
+                                        .last_update
+                                        .is_some_and(|last| last.elapsed() < WATCH_INTERVAL)
+                                {
+                                    return true;
+                                }
+                                watcher.last_update = Some(Instant::now());
+                                watcher.send(&state.describe())
+                            });
+                        }
+                        if !enabled
+                            || event.type_ == EventKind::ForceFeedback as u16
+                            || !evdevs.forwards(id, event.type_)
+                        {
+                            continue;
+                        }
+                        let ev = match evdevs.mouse_mut(id) {
+                            Some(mouse) => match mouse.convert(event) {
+                                Some(ev) => ev,
+                                None => continue,
+                            },
+                            None => *event,
+                        };
+                        if evdevs.in_deadzone(id, &ev) {
+                            continue;
+                        }
+                        let ev = InputEvent::new(id, ev);
+                        trace!(
+                            "Device {} event {} {} {} at {}.{:06}",
+                            id,
+                            ev.ty,
+                            ev.code,
+                            ev.value,
+                            ev.time_sec,
+                            ev.time_usec
+                        );
+                        if let Some(Err(e)) = trace.as_mut().map(|trace| trace.record(&ev)) {
+                            error!("Failed to write the trace, disabling it, error: {:?}", e);
+                            trace = None;
+                        }
+                        frame.push(ev);
+                        forwarded += 1;
+                        let report = ev.ty == EventKind::Synchronize as u16
+                            && ev.code == SynchronizeKind::Report as u16;
+                        if report || frame.len() >= MAX_FRAME_EVENTS {
+                            if limiter.as_mut().is_none_or(|limiter| {
+                                limiter.pass(id, &mut frame, report, Instant::now())
+                            }) {
+                                hangup_on_error_bcast(&mut clients, &epoll, |client| {
+                                    client.write_frame(id, &frame)
+                                });
+                            }
+                            frame.clear();
+                        }
+                    }
                 }
-                let effect_id = erase.effect_id as i16;
-                seen_effect.remove(&(erase.id, effect_id));
-                evdev.unwrap().erase_force_feedback(effect_id).unwrap();
-                hangup_on_error(&mut clients, &epoll, fd, |client| {
-                    client.waiting_for = WaitingFor::Header;
-                    client.write(&MessageType::FFErase)?;
-                    client.write(erase)
-                });
-            }
-        } else if let Some(evdev) = evdevs.get(fd) {
-            let mut evts = [empty_input_event()];
-            while let Ok(count) = evdev.read(&mut evts) {
-                if count == 0 {
-                    break;
+                // What the device had so far, the rest of the report comes later
+                if !frame.is_empty()
+                    && limiter
+                        .as_mut()
+                        .is_none_or(|limiter| limiter.pass(id, &mut frame, false, Instant::now()))
+                {
+                    hangup_on_error_bcast(&mut clients, &epoll, |client| {
+                        client.write_frame(id, &frame)
+                    });
                 }
-                if evts[0].type_ == EventKind::ForceFeedback as u16 {
-                    continue;
+                // Unplugged, udev may only say so later, and the fd would wake us
+                // up until then
+                if flags.intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+                    debug!(
+                        "Device {} hung up, it was probably unplugged, dropping it",
+                        id
+                    );
+                    let removed = evdevs.close(id, &epoll);
+                    announce_removal(&mut evdevs, &mut clients, &epoll, removed);
+                    watchers.retain(|watcher| evdevs.get(watcher.id).is_some());
                 }
-                let ev = InputEvent::new(fd, evts[0]);
-                hangup_on_error_bcast(&mut clients, &epoll, |client| {
-                    client.write(&MessageType::InputEvent)?;
-                    client.write(&ev)
-                });
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input_linux::RelativeAxis;
+
+    fn event(id: u64, ty: EventKind, code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            time_sec: 0,
+            time_usec: 0,
+            id,
+            value,
+            ty: ty as u16,
+            code,
+        }
+    }
+
+    fn fields(ev: &InputEvent) -> (u64, u16, u16, i32) {
+        (ev.id, ev.ty, ev.code, ev.value)
+    }
+
+    /// A client taking frames, and the other end of its socket.
+    fn client_pair() -> (Client, UnixStream) {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        theirs.set_nonblocking(true).unwrap();
+        let mut client = Client::new(Box::new(ours), false);
+        client.waiting_for = WaitingFor::Header;
+        client.input_frames = true;
+        (client, theirs)
+    }
+
+    /// Reads what the socket has.
+    fn read_available(peer: &mut UnixStream, into: &mut Vec<u8>) {
+        let mut buf = [0u8; 4096];
+        loop {
+            match peer.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => into.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    /// The events of the input frames in `data`, one `Vec` per frame.
+    fn parse_frames(mut data: &[u8]) -> Vec<Vec<InputEvent>> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let (ty, len) = parse_header(data[..HEADER_SIZE].try_into().unwrap());
+            assert_eq!(ty, MessageType::InputFrame as u32);
+            let payload = &data[HEADER_SIZE..HEADER_SIZE + len];
+            let events = payload[mem::size_of::<InputFrame>()..]
+                .chunks(mem::size_of::<InputEvent>())
+                .map(|chunk| {
+                    // SAFETY: InputEvent is plain data and the chunk is as large
+                    unsafe { ptr::read_unaligned(chunk.as_ptr() as *const InputEvent) }
+                        .into_native()
+                })
+                .collect();
+            frames.push(events);
+            data = &data[HEADER_SIZE + len..];
+        }
+        frames
+    }
+
+    fn fields_of(frame: &[InputEvent]) -> Vec<(u64, u16, u16, i32)> {
+        frame.iter().map(fields).collect()
+    }
+
+    #[test]
+    fn rate_limiter_lets_keys_through() {
+        let mut limiter = RateLimiter::new(100);
+        let start = Instant::now();
+        let mut motion = vec![event(1, EventKind::Absolute, 0, 1), syn_report(1)];
+        assert!(limiter.pass(1, &mut motion, true, start));
+        // Too early, but a button changed
+        let mut press = vec![
+            event(1, EventKind::Absolute, 0, 2),
+            event(1, EventKind::Key, Key::ButtonSouth as u16, 1),
+            syn_report(1),
+        ];
+        let sent = press.clone();
+        assert!(limiter.pass(1, &mut press, true, start + Duration::from_millis(1)));
+        assert_eq!(fields_of(&press), fields_of(&sent));
+        // Another device has its own pace
+        let mut other = vec![event(2, EventKind::Absolute, 0, 1), syn_report(2)];
+        assert!(limiter.pass(2, &mut other, true, start + Duration::from_millis(2)));
+        assert!(limiter.next_due().is_none());
+    }
+
+    #[test]
+    fn rate_limiter_merges_early_motion() {
+        let mut limiter = RateLimiter::new(100);
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let abs_x = AbsoluteAxis::X as u16;
+        let rel_x = RelativeAxis::X as u16;
+        let report = |x, dx| {
+            vec![
+                event(1, EventKind::Absolute, abs_x, x),
+                event(1, EventKind::Relative, rel_x, dx),
+                syn_report(1),
+            ]
+        };
+        assert!(limiter.pass(1, &mut report(1, 1), true, start));
+        assert!(!limiter.pass(1, &mut report(2, 2), true, start + ms(3)));
+        assert!(!limiter.pass(1, &mut report(3, 3), true, start + ms(6)));
+        assert_eq!(limiter.next_due(), Some(start + ms(10)));
+        // The next report on time carries what was held back in front of it
+        let mut late = report(4, 4);
+        assert!(limiter.pass(1, &mut late, true, start + ms(10)));
+        let syn = fields(&syn_report(1));
+        let abs = EventKind::Absolute as u16;
+        let rel = EventKind::Relative as u16;
+        assert_eq!(
+            fields_of(&late),
+            [
+                (1, abs, abs_x, 3),
+                (1, rel, rel_x, 5),
+                (1, abs, abs_x, 4),
+                (1, rel, rel_x, 4),
+                syn
+            ]
+        );
+        assert!(limiter.next_due().is_none());
+    }
+
+    #[test]
+    fn rate_limiter_flushes_what_is_due() {
+        let mut limiter = RateLimiter::new(100);
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let rel_x = RelativeAxis::X as u16;
+        let mut report = vec![event(1, EventKind::Relative, rel_x, 1), syn_report(1)];
+        assert!(limiter.pass(1, &mut report, true, start));
+        let mut report = vec![event(1, EventKind::Relative, rel_x, 2), syn_report(1)];
+        assert!(!limiter.pass(1, &mut report, true, start + ms(1)));
+        assert!(limiter.due(start + ms(9)).is_empty());
+        let due = limiter.due(start + ms(10));
+        assert_eq!(due.len(), 1);
+        let (id, report) = &due[0];
+        assert_eq!(*id, 1);
+        assert_eq!(
+            fields_of(report),
+            [
+                (1, EventKind::Relative as u16, rel_x, 2),
+                fields(&syn_report(1))
+            ]
+        );
+        assert!(limiter.due(start + ms(100)).is_empty());
+        assert!(limiter.next_due().is_none());
+        // The flush counts as a report sent
+        let mut report = vec![event(1, EventKind::Relative, rel_x, 3), syn_report(1)];
+        assert!(!limiter.pass(1, &mut report, true, start + ms(15)));
+    }
+
+    fn abs_event(code: AbsoluteAxis, value: i32) -> input_event {
+        input_event {
+            type_: EventKind::Absolute as u16,
+            code: code as u16,
+            value,
+            ..empty_input_event()
+        }
+    }
+
+    #[test]
+    fn clamp() {
+        let mut state = DeviceState::default();
+        state.ranges.insert(AbsoluteAxis::X as u16, (-128, 127));
+        let mut ev = abs_event(AbsoluteAxis::X, -200);
+        // Only the first time an axis goes out of range is reported
+        assert!(state.clamp(&mut ev));
+        assert_eq!(ev.value, -128);
+        let mut ev = abs_event(AbsoluteAxis::X, 300);
+        assert!(!state.clamp(&mut ev));
+        assert_eq!(ev.value, 127);
+        let mut ev = abs_event(AbsoluteAxis::X, 5);
+        assert!(!state.clamp(&mut ev));
+        assert_eq!(ev.value, 5);
+        // Axes without a range and other events are left alone
+        let mut ev = abs_event(AbsoluteAxis::Y, 1000);
+        assert!(!state.clamp(&mut ev));
+        assert_eq!(ev.value, 1000);
+        let mut ev = abs_event(AbsoluteAxis::X, 1000);
+        ev.type_ = EventKind::Relative as u16;
+        assert!(!state.clamp(&mut ev));
+        assert_eq!(ev.value, 1000);
+    }
+
+    #[test]
+    fn clamp_above_max() {
+        let mut state = DeviceState::default();
+        state.ranges.insert(AbsoluteAxis::Z as u16, (0, 255));
+        let mut ev = abs_event(AbsoluteAxis::Z, 256);
+        assert!(state.clamp(&mut ev));
+        assert_eq!(ev.value, 255);
+    }
+
+    #[test]
+    fn clamp_degenerate_range() {
+        let range = |minimum, maximum| AbsoluteInfo {
+            value: 0,
+            minimum,
+            maximum,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        let mut state = DeviceState::default();
+        state.set_range(AbsoluteAxis::Z as u16, &range(0, 255));
+        // Uncalibrated, whatever the device reports goes through
+        for (minimum, maximum) in [(0, 0), (10, -10)] {
+            state.set_range(AbsoluteAxis::Z as u16, &range(minimum, maximum));
+            let mut ev = abs_event(AbsoluteAxis::Z, -3);
+            assert!(!state.clamp(&mut ev));
+            assert_eq!(ev.value, -3);
+        }
+    }
+
+    #[test]
+    fn resync_burst_ends_with_a_report() {
+        let mut state = DeviceState::default();
+        state.keys.insert(Key::ButtonSouth as u16);
+        state.abs.insert(AbsoluteAxis::X as u16, -300);
+        state.abs.insert(AbsoluteAxis::Hat0Y as u16, 1);
+        let mut keys = Bitmask::<Key>::default();
+        keys.insert(Key::ButtonSouth);
+        keys.insert(Key::ButtonEast);
+        let burst = state.burst(4, &keys);
+        let key = EventKind::Key as u16;
+        let abs = EventKind::Absolute as u16;
+        assert_eq!(
+            fields_of(&burst),
+            [
+                (4, key, Key::ButtonSouth as u16, 1),
+                (4, key, Key::ButtonEast as u16, 0),
+                (4, abs, AbsoluteAxis::X as u16, -300),
+                (4, abs, AbsoluteAxis::Hat0Y as u16, 1),
+                fields(&syn_report(4)),
+            ]
+        );
+        // Even a device without state commits the report
+        let burst = DeviceState::default().burst(4, &Bitmask::default());
+        assert_eq!(fields_of(&burst), [fields(&syn_report(4))]);
+    }
+
+    #[test]
+    fn broadcast_survives_a_failing_client() {
+        let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+        let mut clients = HashMap::new();
+        let mut peers = Vec::new();
+        for _ in 0..4 {
+            let (client, peer) = client_pair();
+            let fd = client.socket.as_raw_fd() as u64;
+            epoll
+                .add(&client.socket, EpollEvent::new(EpollFlags::EPOLLIN, fd))
+                .unwrap();
+            clients.insert(fd, client);
+            peers.push(peer);
+        }
+        let mut fds: Vec<u64> = clients.keys().copied().collect();
+        fds.sort();
+        // One that didn't say hello yet, and one that fails
+        clients.get_mut(&fds[0]).unwrap().waiting_for = WaitingFor::Hello;
+        let failing = fds[2];
+        let mut handled = Vec::new();
+        let error = hangup_on_error_bcast_with(&mut clients, &epoll, |client, others| {
+            let fd = client.socket.as_raw_fd() as u64;
+            handled.push(fd);
+            // The others are all there, minus the one being handled
+            assert_eq!(others.len(), 3);
+            assert!(!others.contains_key(&fd));
+            if fd == failing {
+                return Err(Error::Unresponsive);
+            }
+            Ok(())
+        });
+        assert!(error.is_none());
+        handled.sort();
+        assert_eq!(handled, fds[1..]);
+        let mut left: Vec<u64> = clients.keys().copied().collect();
+        left.sort();
+        assert_eq!(left, [fds[0], fds[1], fds[3]]);
+
+        // A device failing doesn't drop anyone
+        let error = hangup_on_error_bcast(&mut clients, &epoll, |_| Err(Error::DeviceGone(1)));
+        assert!(matches!(error, Some(Error::DeviceGone(1))));
+        assert_eq!(clients.len(), 3);
+    }
+
+    /// An empty directory of its own for a test.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hidpipe-{}-{}", test, process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolve_node_follows_links() {
+        let dir = temp_dir("resolve-node");
+        let node = dir.join("event7");
+        File::create(&node).unwrap();
+        fs::create_dir(dir.join("by-id")).unwrap();
+        let link = dir.join("by-id/usb-Pad-event-joystick");
+        std::os::unix::fs::symlink("../event7", &link).unwrap();
+
+        let (name, path) = resolve_node(OsStr::new("usb-Pad-event-joystick"), &link);
+        assert_eq!(name, "event7");
+        assert_eq!(path, fs::canonicalize(&node).unwrap());
+        let (name, path) = resolve_node(OsStr::new("event7"), &node);
+        assert_eq!(name, "event7");
+        assert_eq!(path, fs::canonicalize(&node).unwrap());
+        // Gone already, udev's word is all there is
+        let gone = dir.join("event8");
+        let (name, path) = resolve_node(OsStr::new("event8"), &gone);
+        assert_eq!(name, "event8");
+        assert_eq!(path, gone);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A device on `bustype` with `phys`.
+    fn device_on(bustype: u16, phys: &str) -> AddDevice {
+        // SAFETY: AddDevice is made of integers and arrays of them
+        let mut add_dev: AddDevice = unsafe { mem::zeroed() };
+        add_dev.input_id.bustype = bustype;
+        add_dev.phys[..phys.len()].copy_from_slice(phys.as_bytes());
+        add_dev
+    }
+
+    #[test]
+    fn virtual_devices_are_rejected() {
+        let markers = [PHYS_MARKER.to_string(), "vhci-".to_string()];
+        let usb = input_linux::sys::BUS_USB;
+        let tagged = instance_tag_phys(42) + "usb-0000:00:14.0-1/input0";
+        assert!(is_virtual(&markers, &device_on(usb, &tagged)).unwrap());
+        assert!(is_virtual(&markers, &device_on(usb, "vhci-hcd.0-1/input0")).unwrap());
+        assert!(is_virtual(&markers, &device_on(BUS_VIRTUAL, "")).unwrap());
+        assert!(!is_virtual(&markers, &device_on(usb, "usb-0000:00:14.0-1/input0")).unwrap());
+        // Only a prefix counts
+        assert!(!is_virtual(&markers, &device_on(usb, "usb-vhci-1/input0")).unwrap());
+    }
+
+    /// Runs `f` in a child process, so what it does to the whole process, like
+    /// setting the umask, can't reach the tests running meanwhile. Returns
+    /// whether it succeeded.
+    fn in_child(f: impl FnOnce() -> bool) -> bool {
+        // SAFETY: the child only runs `f` and exits, without unwinding into
+        // the test harness
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+            0 => unsafe { libc::_exit(if f() { 0 } else { 1 }) },
+            child => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    #[test]
+    fn sockets_are_private() {
+        let dir = temp_dir("bind-private");
+        for mode in [0o600, 0o660] {
+            let path = dir.join(format!("socket-{:o}", mode));
+            assert!(in_child(|| bind_private(&path, mode).is_ok()));
+            let meta = fs::metadata(&path).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, mode);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn effect_ids() {
+        let mut effects = EffectIds::default();
+        // Both clients' devices picked 0, the host devices picked their own
+        effects.uploaded(1, 0, 3);
+        effects.uploaded(2, 0, 0);
+        effects.uploaded(1, 1, 4);
+        assert_eq!(effects.host(1, 0), Some(3));
+        assert_eq!(effects.host(2, 0), Some(0));
+        assert_eq!(effects.host(2, 1), None);
+        // Uploading again updates the effect in place
+        effects.uploaded(1, 0, 3);
+        assert_eq!(effects.erased(1, 1), Some(4));
+        assert_eq!(effects.erased(1, 1), None);
+        assert_eq!(effects.host(1, 0), Some(3));
+    }
+
+    #[test]
+    fn effect_ids_translate_ff_events() {
+        let gain = ForceFeedbackKind::Gain as u16;
+        let autocenter = ForceFeedbackKind::Autocenter as u16;
+        let mut effects = EffectIds::default();
+        effects.uploaded(1, 0, 5);
+        effects.uploaded(1, gain as i16, 6);
+        effects.uploaded(1, autocenter as i16, 7);
+        let play = |code: u16| {
+            let mut ev = event(1, EventKind::ForceFeedback, code, 1);
+            effects.translate(&mut ev);
+            ev.code
+        };
+        assert_eq!(play(0), 5);
+        // Unknown effects go through as they are, the device rejects them
+        assert_eq!(play(1), 1);
+        assert_eq!(play(gain), gain);
+        assert_eq!(play(autocenter), autocenter);
+        // Other devices and event types are left alone
+        let mut ev = event(2, EventKind::ForceFeedback, 0, 1);
+        effects.translate(&mut ev);
+        assert_eq!(ev.code, 0);
+        let mut ev = event(1, EventKind::Led, 0, 1);
+        effects.translate(&mut ev);
+        assert_eq!(ev.code, 0);
+    }
+
+    #[test]
+    fn effect_ids_go_with_their_device() {
+        let mut effects = EffectIds::default();
+        effects.uploaded(1, 0, 3);
+        effects.uploaded(1, 1, 4);
+        effects.uploaded(2, 0, 0);
+        effects.uploaded(3, 0, 1);
+        let mut removed = effects.remove_device(1);
+        removed.sort();
+        assert_eq!(removed, [3, 4]);
+        assert_eq!(effects.host(1, 0), None);
+        assert_eq!(effects.host(2, 0), Some(0));
+        // Device 3 was unplugged
+        effects.retain_devices(|id| id != 3);
+        assert_eq!(effects.host(3, 0), None);
+        assert_eq!(effects.host(2, 0), Some(0));
+        assert!(effects.remove_device(3).is_empty());
+    }
+
+    #[test]
+    fn slow_client_gets_merged_motion() {
+        let (mut client, mut peer) = client_pair();
+        // Fill the socket, then have more waiting behind it
+        let mut skipped = 0;
+        while let Ok(written) = client.socket.write(&[0u8; 4096]) {
+            skipped += written;
+        }
+        client.queue = vec![0u8; QUEUE_SOFT_LIMIT + 1];
+        skipped += client.queue.len();
+
+        let abs_x = AbsoluteAxis::X as u16;
+        let rel_x = RelativeAxis::X as u16;
+        let slot = AbsoluteAxis::MultitouchSlot as u16;
+        let btn = Key::ButtonSouth as u16;
+        for (x, dx) in [(10, 1), (20, 2), (30, 3)] {
+            let report = [
+                event(1, EventKind::Absolute, abs_x, x),
+                event(1, EventKind::Relative, rel_x, dx),
+                syn_report(1),
+            ];
+            client.write_frame(1, &report).unwrap();
+        }
+        let pressed = [
+            event(1, EventKind::Absolute, slot, 1),
+            event(1, EventKind::Key, btn, 1),
+            syn_report(1),
+        ];
+        client.write_frame(1, &pressed).unwrap();
+        assert_eq!(client.dropped, 4);
+
+        let mut data = Vec::new();
+        while !client.queue.is_empty() || !client.held.is_empty() {
+            read_available(&mut peer, &mut data);
+            client.catch_up().unwrap();
+        }
+        read_available(&mut peer, &mut data);
+
+        let frames = parse_frames(&data[skipped..]);
+        let frames: Vec<Vec<_>> = frames
+            .iter()
+            .map(|frame| frame.iter().map(fields).collect())
+            .collect();
+        let syn = fields(&syn_report(1));
+        let key = EventKind::Key as u16;
+        let abs = EventKind::Absolute as u16;
+        let rel = EventKind::Relative as u16;
+        assert_eq!(
+            frames,
+            [
+                // Reports and buttons still go through, slots too
+                vec![syn],
+                vec![syn],
+                vec![syn],
+                vec![(1, abs, slot, 1), (1, key, btn, 1), syn],
+                // Then the latest position and the sum of the motion
+                vec![(1, abs, abs_x, 30), (1, rel, rel_x, 6), syn],
+            ]
+        );
+    }
+
+    #[test]
+    fn held_motion_goes_before_the_next_report() {
+        let (mut client, mut peer) = client_pair();
+        client.held = vec![event(1, EventKind::Relative, RelativeAxis::Y as u16, 5)];
+        client.input_frames = false;
+        let report = [
+            event(2, EventKind::Key, Key::ButtonSouth as u16, 1),
+            syn_report(2),
+        ];
+        client.write_frame(2, &report).unwrap();
+        let wheel = event(1, EventKind::Relative, RelativeAxis::Wheel as u16, 1);
+        client.write_frame(1, &[wheel, syn_report(1)]).unwrap();
+        assert!(client.held.is_empty());
+
+        let mut data = Vec::new();
+        read_available(&mut peer, &mut data);
+        let size = HEADER_SIZE + mem::size_of::<InputEvent>();
+        let events: Vec<_> = data
+            .chunks(size)
+            .map(|message| {
+                // SAFETY: InputEvent is plain data and the message is as large
+                let ev = unsafe {
+                    ptr::read_unaligned(message[HEADER_SIZE..].as_ptr() as *const InputEvent)
+                };
+                (ev.into_native().id, ev.into_native().code)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (2, Key::ButtonSouth as u16),
+                (2, 0),
+                (1, RelativeAxis::Wheel as u16),
+                (1, RelativeAxis::Y as u16),
+                (1, 0),
+            ]
+        );
+    }
+
+    #[derive(Default)]
+    struct MockSource {
+        leds: Bitmask<LedKind>,
+        written: std::cell::RefCell<Vec<input_event>>,
+    }
+
+    impl Source for MockSource {
+        fn led_bits(&self) -> Result<Bitmask<LedKind>> {
+            Ok(self.leds)
+        }
+        fn write(&self, events: &[input_event]) -> Result<usize> {
+            self.written.borrow_mut().extend_from_slice(events);
+            Ok(events.len())
+        }
+    }
+
+    #[test]
+    fn leds_the_device_has_reach_it() {
+        let mut source = MockSource::default();
+        source.leds.insert(LedKind::NumLock);
+        let sent = [
+            event(1, EventKind::Led, LedKind::NumLock as u16, 1),
+            event(1, EventKind::Led, LedKind::CapsLock as u16, 1),
+            event(1, EventKind::Led, 0x7f, 1),
+            event(1, EventKind::Led, LedKind::NumLock as u16, 0),
+        ];
+        for ev in &sent {
+            write_to_source(&source, ev).unwrap();
+        }
+        let written: Vec<_> = source
+            .written
+            .borrow()
+            .iter()
+            .map(|ev| (ev.type_, ev.code, ev.value))
+            .collect();
+        let led = EventKind::Led as u16;
+        let num_lock = LedKind::NumLock as u16;
+        assert_eq!(written, [(led, num_lock, 1), (led, num_lock, 0)]);
+    }
+
+    #[test]
+    fn other_events_reach_the_device() {
+        let source = MockSource::default();
+        write_to_source(&source, &event(1, EventKind::ForceFeedback, 3, 1)).unwrap();
+        let written = source.written.borrow();
+        assert_eq!(written.len(), 1);
+        assert_eq!(
+            (written[0].type_, written[0].code, written[0].value),
+            (EventKind::ForceFeedback as u16, 3, 1)
+        );
+    }
+}
@@ -0,0 +1,53 @@
+//! Signals are turned into bytes on a pipe, so the event loops can wait for
+//! them with epoll like for everything else.
+
+use libc::c_int;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Write end of the pipe, for the handler.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: c_int) {
+    let byte = signal as u8;
+    // SAFETY: write is async-signal-safe, if the pipe is full the loop has a
+    // wakeup pending anyway
+    unsafe {
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        )
+    };
+}
+
+/// Sends the given signals to the returned pipe. Meant to be called once.
+pub fn pipe(signals: &[c_int]) -> io::Result<File> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for both ends
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    PIPE.store(fds[1], Ordering::Relaxed);
+    let handler = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+    for signal in signals {
+        // SAFETY: the handler only does async-signal-safe things
+        if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // SAFETY: the read end was just created and is owned by nobody else
+    Ok(unsafe { File::from_raw_fd(fds[0]) })
+}
+
+/// Returns the signals received since the last call.
+pub fn received(mut pipe: &File) -> Vec<c_int> {
+    let mut buf = [0u8; 16];
+    let mut signals = Vec::new();
+    while let Ok(len @ 1..) = pipe.read(&mut buf) {
+        signals.extend(buf[..len].iter().map(|signal| *signal as c_int));
+    }
+    signals
+}
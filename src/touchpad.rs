@@ -0,0 +1,93 @@
+//! Controller touchpads can be forwarded as mice, for games that don't know
+//! what to do with a touchpad. The first finger on the pad moves the pointer
+//! and clicking the pad is the left button.
+
+use crate::{AddDevice, Padded};
+use input_linux::sys::input_event;
+use input_linux::{
+    AbsoluteAxis, Bitmask, EventKind, InputProperty, Key, MiscKind, RelativeAxis, SynchronizeKind,
+};
+use std::{mem, ptr};
+
+/// Mouse counts for a swipe across the whole width of the pad.
+const MOUSE_RANGE: i64 = 1000;
+
+/// Turns the record of a touchpad into the one of a mouse with a single button.
+pub fn mouse_record(record: &[u8]) -> Vec<u8> {
+    assert!(record.len() >= mem::size_of::<AddDevice>());
+    // SAFETY: AddDevice is made of integers and arrays of them
+    let mut dev = unsafe { ptr::read_unaligned(record.as_ptr() as *const AddDevice) };
+    let mut evbits = Bitmask::<EventKind>::default();
+    evbits.insert(EventKind::Synchronize);
+    evbits.insert(EventKind::Key);
+    evbits.insert(EventKind::Relative);
+    let mut keybits = Bitmask::<Key>::default();
+    keybits.insert(Key::ButtonLeft);
+    let mut relbits = Bitmask::<RelativeAxis>::default();
+    relbits.insert(RelativeAxis::X);
+    relbits.insert(RelativeAxis::Y);
+    dev.evbits = *evbits.data();
+    dev.keybits = *keybits.data();
+    dev.relbits = *relbits.data();
+    dev.absbits = *Bitmask::<AbsoluteAxis>::default().data();
+    dev.mscbits = *Bitmask::<MiscKind>::default().data();
+    dev.propbits = *Bitmask::<InputProperty>::default().data();
+    dev.to_bytes()
+}
+
+/// Converts the events of a touchpad into the ones of the mouse described by
+/// [`mouse_record`]. Positions are those of the first finger, which touchpads
+/// also report outside of the multitouch slots.
+pub struct Mouse {
+    width: i64,
+    touching: bool,
+    last: [Option<i32>; 2],
+    /// What was left over from scaling down the movement on each axis
+    remainder: [i64; 2],
+}
+
+impl Mouse {
+    /// `width` is the range of the pad's X axis, movements on both axes are
+    /// scaled by it to keep the aspect ratio.
+    pub fn new(width: i32) -> Mouse {
+        Mouse {
+            width: width.max(1) as i64,
+            touching: false,
+            last: [None; 2],
+            remainder: [0; 2],
+        }
+    }
+    pub fn convert(&mut self, ev: &input_event) -> Option<input_event> {
+        let mut out = *ev;
+        if ev.type_ == EventKind::Synchronize as u16 {
+            return (ev.code == SynchronizeKind::Report as u16).then_some(out);
+        }
+        if ev.type_ == EventKind::Key as u16 {
+            if ev.code == Key::ButtonTouch as u16 {
+                // Lifting the finger and putting it down elsewhere doesn't move the pointer
+                self.touching = ev.value != 0;
+                self.last = [None; 2];
+                self.remainder = [0; 2];
+                return None;
+            }
+            return (ev.code == Key::ButtonLeft as u16).then_some(out);
+        }
+        if ev.type_ != EventKind::Absolute as u16 || !self.touching {
+            return None;
+        }
+        let (axis, rel) = if ev.code == AbsoluteAxis::X as u16 {
+            (0, RelativeAxis::X)
+        } else if ev.code == AbsoluteAxis::Y as u16 {
+            (1, RelativeAxis::Y)
+        } else {
+            return None;
+        };
+        let last = self.last[axis].replace(ev.value)?;
+        let moved = (ev.value - last) as i64 * MOUSE_RANGE + self.remainder[axis];
+        self.remainder[axis] = moved % self.width;
+        out.type_ = EventKind::Relative as u16;
+        out.code = rel as u16;
+        out.value = (moved / self.width) as i32;
+        (out.value != 0).then_some(out)
+    }
+}
@@ -0,0 +1,136 @@
+//! Event traces are CSV files with one forwarded event per line, meant to be
+//! attached to bug reports and fed back through `hidpipe-replay`.
+
+use crate::InputEvent;
+use input_linux::{EventKind, SynchronizeKind};
+use std::fs::{self, File};
+use std::io::{BufWriter, Result, Write};
+
+const HEADER: &str = "time_sec,time_usec,id,type,code,value\n";
+
+/// Size of a trace file before it is moved to `<path>.old` and a new one is started.
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+pub struct Trace {
+    path: String,
+    max_size: u64,
+    size: u64,
+    out: BufWriter<File>,
+}
+
+impl Trace {
+    pub fn open(path: &str, max_size: u64) -> Result<Trace> {
+        let file = File::options().append(true).create(true).open(path)?;
+        let size = file.metadata()?.len();
+        let mut trace = Trace {
+            path: path.to_string(),
+            max_size,
+            size,
+            out: BufWriter::new(file),
+        };
+        if size == 0 {
+            trace.write(HEADER)?;
+        }
+        Ok(trace)
+    }
+    fn write(&mut self, line: &str) -> Result<()> {
+        self.out.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+    fn rotate(&mut self) -> Result<()> {
+        self.out.flush()?;
+        fs::rename(&self.path, format!("{}.old", self.path))?;
+        *self = Trace::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+    /// Buffers the event, the file is only written once the device sends a report.
+    pub fn record(&mut self, ev: &InputEvent) -> Result<()> {
+        self.write(&format!(
+            "{},{},{},{},{},{}\n",
+            ev.time_sec, ev.time_usec, ev.id, ev.ty, ev.code, ev.value
+        ))?;
+        if ev.ty == EventKind::Synchronize as u16 && ev.code == SynchronizeKind::Report as u16 {
+            if self.size >= self.max_size {
+                self.rotate()?;
+            } else {
+                self.out.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a line written by [`Trace::record`], returns `None` for the header or garbage.
+pub fn parse_line(line: &str) -> Option<InputEvent> {
+    let fields: Vec<&str> = line.trim().split(',').collect();
+    match fields[..] {
+        [time_sec, time_usec, id, ty, code, value] => Some(InputEvent {
+            time_sec: time_sec.parse().ok()?,
+            time_usec: time_usec.parse().ok()?,
+            id: id.parse().ok()?,
+            ty: ty.parse().ok()?,
+            code: code.parse().ok()?,
+            value: value.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syn_report;
+    use std::process;
+
+    fn fields(ev: &InputEvent) -> (i64, i64, u64, u16, u16, i32) {
+        (ev.time_sec, ev.time_usec, ev.id, ev.ty, ev.code, ev.value)
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert!(parse_line(HEADER).is_none());
+        assert!(parse_line("").is_none());
+        assert!(parse_line("1,2,3,4,5").is_none());
+        assert!(parse_line("1,2,3,4,5,6,7").is_none());
+        assert!(parse_line("1,2,3,x,5,6").is_none());
+        // The type and code don't fit in 16 bits, nor the id in 64
+        assert!(parse_line("1,2,3,65536,5,6").is_none());
+        assert!(parse_line("1,2,-3,4,5,6").is_none());
+    }
+
+    #[test]
+    fn parses_with_surrounding_whitespace() {
+        let ev = parse_line("  10,20,3,3,0,-32768\r\n").unwrap();
+        assert_eq!(fields(&ev), (10, 20, 3, 3, 0, -32768));
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("hidpipe-trace-{}.csv", process::id()));
+        let path = path.to_str().unwrap();
+        _ = fs::remove_file(path);
+        let events = [
+            InputEvent {
+                time_sec: 1700000000,
+                time_usec: 999999,
+                id: 7,
+                ty: EventKind::Absolute as u16,
+                code: 1,
+                value: i32::MIN,
+            },
+            syn_report(7),
+        ];
+        let mut trace = Trace::open(path, DEFAULT_MAX_SIZE).unwrap();
+        for ev in &events {
+            trace.record(ev).unwrap();
+        }
+        let text = fs::read_to_string(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let parsed: Vec<_> = text.lines().filter_map(parse_line).collect();
+        assert_eq!(parsed.len(), events.len());
+        for (parsed, ev) in parsed.iter().zip(&events) {
+            assert_eq!(fields(parsed), fields(ev));
+        }
+    }
+}
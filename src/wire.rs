@@ -0,0 +1,482 @@
+//! Messages travel little endian, whatever the byte order of the peers, so a
+//! big endian host can talk to a little endian guest. The structs are still
+//! sent as they are laid out in memory, fields are just swapped on big endian
+//! machines before sending and after receiving, which costs nothing on the
+//! others.
+//!
+//! Structs with holes between their fields are sent with [`Padded::to_bytes`],
+//! so the holes are zeros rather than whatever was left on the stack.
+
+use crate::{
+    struct_as_bytes, AddDevice, ClientHello, DeviceSnapshot, FFErase, FFUpload, InputEvent,
+    InputFrame, InstanceTag, JoystickMap, Ping, RemoveDevice, ServerHello, Subscribe,
+    UpdateAbsInfo,
+};
+use input_linux::sys::{
+    ff_effect, ff_effect_union, ff_envelope, FF_CONSTANT, FF_EFFECT_MAX, FF_EFFECT_MIN, FF_INERTIA,
+    FF_PERIODIC, FF_RAMP, FF_RUMBLE, FF_SPRING,
+};
+use input_linux::AbsoluteInfo;
+use std::mem;
+
+pub trait Wire: Sized {
+    /// Swaps the bytes of every integer field.
+    fn swap_bytes(self) -> Self;
+
+    /// Turns a struct about to be sent into its wire format.
+    fn into_wire(self) -> Self {
+        if cfg!(target_endian = "big") {
+            self.swap_bytes()
+        } else {
+            self
+        }
+    }
+
+    /// Turns a struct that was received back into the native format.
+    fn into_native(self) -> Self {
+        self.into_wire()
+    }
+}
+
+macro_rules! swap_fields {
+    ($ty:ty { $($field:ident),* }) => {
+        impl Wire for $ty {
+            fn swap_bytes(mut self) -> Self {
+                $(self.$field = self.$field.swap_bytes();)*
+                self
+            }
+        }
+    };
+}
+
+swap_fields!(ClientHello { version });
+swap_fields!(ServerHello { version });
+swap_fields!(RemoveDevice { id });
+swap_fields!(DeviceSnapshot { count, len });
+swap_fields!(InputFrame { id, count });
+swap_fields!(InputEvent {
+    time_sec,
+    time_usec,
+    id,
+    value,
+    ty,
+    code
+});
+swap_fields!(AbsoluteInfo {
+    value,
+    minimum,
+    maximum,
+    fuzz,
+    flat,
+    resolution
+});
+swap_fields!(UpdateAbsInfo { id, axis, info });
+swap_fields!(FFUpload {
+    id,
+    request_id,
+    retval,
+    effect
+});
+swap_fields!(FFErase {
+    id,
+    request_id,
+    effect_id
+});
+swap_fields!(InstanceTag { tag });
+swap_fields!(Ping { seq });
+swap_fields!(ff_envelope {
+    attack_length,
+    attack_level,
+    fade_length,
+    fade_level
+});
+
+/// The union holds the parameters of the type of the effect, which is in
+/// range whichever way its bytes are. The samples of a custom periodic effect
+/// stay behind a pointer, only their length moves.
+impl Wire for ff_effect {
+    fn swap_bytes(mut self) -> Self {
+        let ty = if (FF_EFFECT_MIN..=FF_EFFECT_MAX).contains(&self.type_) {
+            self.type_
+        } else {
+            self.type_.swap_bytes()
+        };
+        self.type_ = self.type_.swap_bytes();
+        self.id = self.id.swap_bytes();
+        self.direction = self.direction.swap_bytes();
+        self.trigger.button = self.trigger.button.swap_bytes();
+        self.trigger.interval = self.trigger.interval.swap_bytes();
+        self.replay.length = self.replay.length.swap_bytes();
+        self.replay.delay = self.replay.delay.swap_bytes();
+        let u = <&mut ff_effect_union>::from(&mut self);
+        match ty {
+            FF_RUMBLE => {
+                let rumble = u.rumble_mut();
+                rumble.strong_magnitude = rumble.strong_magnitude.swap_bytes();
+                rumble.weak_magnitude = rumble.weak_magnitude.swap_bytes();
+            }
+            FF_PERIODIC => {
+                let periodic = u.periodic_mut();
+                periodic.waveform = periodic.waveform.swap_bytes();
+                periodic.period = periodic.period.swap_bytes();
+                periodic.magnitude = periodic.magnitude.swap_bytes();
+                periodic.offset = periodic.offset.swap_bytes();
+                periodic.phase = periodic.phase.swap_bytes();
+                periodic.envelope = periodic.envelope.swap_bytes();
+                periodic.custom_len = periodic.custom_len.swap_bytes();
+            }
+            FF_CONSTANT => {
+                let constant = u.constant_mut();
+                constant.level = constant.level.swap_bytes();
+                constant.envelope = constant.envelope.swap_bytes();
+            }
+            FF_RAMP => {
+                let ramp = u.ramp_mut();
+                ramp.start_level = ramp.start_level.swap_bytes();
+                ramp.end_level = ramp.end_level.swap_bytes();
+                ramp.envelope = ramp.envelope.swap_bytes();
+            }
+            FF_SPRING..=FF_INERTIA => {
+                for condition in u.condition_mut() {
+                    condition.right_saturation = condition.right_saturation.swap_bytes();
+                    condition.left_saturation = condition.left_saturation.swap_bytes();
+                    condition.right_coeff = condition.right_coeff.swap_bytes();
+                    condition.left_coeff = condition.left_coeff.swap_bytes();
+                    condition.deadband = condition.deadband.swap_bytes();
+                    condition.center = condition.center.swap_bytes();
+                }
+            }
+            _ => {}
+        }
+        self
+    }
+}
+
+impl Wire for JoystickMap {
+    fn swap_bytes(mut self) -> Self {
+        self.id = self.id.swap_bytes();
+        for button in &mut self.btnmap {
+            *button = button.swap_bytes();
+        }
+        self
+    }
+}
+
+impl Wire for Subscribe {
+    fn swap_bytes(mut self) -> Self {
+        self.count = self.count.swap_bytes();
+        for id in self.ids.iter_mut().flatten() {
+            *id = id.swap_bytes();
+        }
+        self
+    }
+}
+
+/// The bitmasks are arrays of bytes, only the integers around them move.
+impl Wire for AddDevice {
+    fn swap_bytes(mut self) -> Self {
+        self.id = self.id.swap_bytes();
+        self.input_id.bustype = self.input_id.bustype.swap_bytes();
+        self.input_id.vendor = self.input_id.vendor.swap_bytes();
+        self.input_id.product = self.input_id.product.swap_bytes();
+        self.input_id.version = self.input_id.version.swap_bytes();
+        self.ff_effects = self.ff_effects.swap_bytes();
+        self
+    }
+}
+
+pub trait Padded {
+    /// The bytes of the struct as laid out in memory, with the padding zeroed.
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+macro_rules! copy_fields {
+    ($ty:ty { $($($field:ident).+),* }) => {
+        impl Padded for $ty {
+            fn to_bytes(&self) -> Vec<u8> {
+                let mut bytes = vec![0u8; mem::size_of::<$ty>()];
+                $(
+                    let field = struct_as_bytes(&self.$($field).+);
+                    let at = mem::offset_of!($ty, $($field).+);
+                    bytes[at..at + field.len()].copy_from_slice(field);
+                )*
+                bytes
+            }
+        }
+    };
+}
+
+copy_fields!(AddDevice {
+    id,
+    evbits,
+    keybits,
+    relbits,
+    absbits,
+    mscbits,
+    ledbits,
+    sndbits,
+    swbits,
+    propbits,
+    ffbits,
+    input_id,
+    ff_effects,
+    name,
+    uniq,
+    phys
+});
+copy_fields!(FFUpload {
+    id,
+    request_id,
+    retval,
+    effect.type_,
+    effect.id,
+    effect.direction,
+    effect.trigger,
+    effect.replay,
+    effect.u
+});
+copy_fields!(UpdateAbsInfo { id, axis, info });
+copy_fields!(JoystickMap {
+    id,
+    axes,
+    buttons,
+    axmap,
+    btnmap
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use input_linux::sys::BUS_USB;
+    use input_linux::InputId;
+
+    /// Checks that the swapped bytes of a value, as a peer of the other byte
+    /// order would hold them, decode back to the value, and returns its wire
+    /// bytes.
+    fn round_trip<T: Wire>(make: impl Fn() -> T, bytes: impl Fn(&T) -> Vec<u8>) -> Vec<u8> {
+        let native = bytes(&make());
+        let swapped = make().swap_bytes();
+        assert_ne!(bytes(&swapped), native);
+        assert_eq!(bytes(&swapped.swap_bytes()), native);
+        let wire = make().into_wire();
+        let wire_bytes = bytes(&wire);
+        assert_eq!(bytes(&wire.into_native()), native);
+        wire_bytes
+    }
+
+    fn raw<T>(value: &T) -> Vec<u8> {
+        struct_as_bytes(value).to_vec()
+    }
+
+    #[test]
+    fn input_event() {
+        let wire = round_trip(
+            || InputEvent {
+                time_sec: 0x0102030405060708,
+                time_usec: 0x1112131415161718,
+                id: 0x2122232425262728,
+                value: -2,
+                ty: 0x3132,
+                code: 0x4142,
+            },
+            raw,
+        );
+        let at = mem::offset_of!(InputEvent, id);
+        assert_eq!(wire[at..at + 8], 0x2122232425262728u64.to_le_bytes());
+        let at = mem::offset_of!(InputEvent, value);
+        assert_eq!(wire[at..at + 4], (-2i32).to_le_bytes());
+        let at = mem::offset_of!(InputEvent, code);
+        assert_eq!(wire[at..at + 2], 0x4142u16.to_le_bytes());
+    }
+
+    #[test]
+    fn add_device() {
+        let make = || {
+            let mut add_dev: AddDevice = unsafe { mem::zeroed() };
+            add_dev.id = 0x0102030405060708;
+            add_dev.input_id = InputId {
+                bustype: BUS_USB,
+                vendor: 0x045e,
+                product: 0x028e,
+                version: 0x0110,
+            };
+            add_dev.ff_effects = 16;
+            add_dev.evbits[0] = 0x0b;
+            add_dev.keybits[0x130 / 8] = 1;
+            add_dev.name[..4].copy_from_slice(b"pad\0");
+            add_dev
+        };
+        let wire = round_trip(make, Padded::to_bytes);
+        let at = mem::offset_of!(AddDevice, input_id.vendor);
+        assert_eq!(wire[at..at + 2], 0x045eu16.to_le_bytes());
+        let at = mem::offset_of!(AddDevice, ff_effects);
+        assert_eq!(wire[at..at + 4], 16u32.to_le_bytes());
+        // The bitmasks and strings are bytes, they don't move
+        let at = mem::offset_of!(AddDevice, keybits);
+        assert_eq!(wire[at + 0x130 / 8], 1);
+        let at = mem::offset_of!(AddDevice, name);
+        assert_eq!(&wire[at..at + 4], b"pad\0");
+    }
+
+    /// An `AddDevice` over memory filled with `garbage`, fields included.
+    fn dirty_add_device(garbage: u8) -> AddDevice {
+        let mut add_dev = mem::MaybeUninit::<AddDevice>::uninit();
+        // SAFETY: every byte is written, and AddDevice is made of integers and
+        // arrays of them
+        let mut add_dev = unsafe {
+            add_dev.as_mut_ptr().write_bytes(garbage, 1);
+            add_dev.assume_init()
+        };
+        add_dev.id = 7;
+        add_dev.evbits = [0x0b; 4];
+        add_dev.keybits.fill(0);
+        add_dev.relbits.fill(0);
+        add_dev.absbits = [0x03, 0, 0, 0, 0, 0, 0, 0];
+        add_dev.mscbits.fill(0);
+        add_dev.ledbits.fill(0);
+        add_dev.sndbits.fill(0);
+        add_dev.swbits.fill(0);
+        add_dev.propbits.fill(0);
+        add_dev.ffbits.fill(0);
+        add_dev.input_id = InputId {
+            bustype: BUS_USB,
+            vendor: 1,
+            product: 2,
+            version: 3,
+        };
+        add_dev.ff_effects = 0;
+        add_dev.name.fill(0);
+        add_dev.uniq.fill(0);
+        add_dev.phys.fill(0);
+        add_dev
+    }
+
+    #[test]
+    fn padding_is_zeroed() {
+        let (a, b) = (dirty_add_device(0xaa), dirty_add_device(0x55));
+        assert_eq!(a.to_bytes(), b.to_bytes());
+        assert_eq!(a.to_bytes().len(), mem::size_of::<AddDevice>());
+    }
+
+    #[test]
+    fn remove_device() {
+        let wire = round_trip(
+            || RemoveDevice {
+                id: 0x0102030405060708,
+            },
+            raw,
+        );
+        assert_eq!(wire, 0x0102030405060708u64.to_le_bytes());
+    }
+
+    #[test]
+    fn hellos() {
+        let wire = round_trip(
+            || ClientHello {
+                version: 0x80000402,
+            },
+            raw,
+        );
+        assert_eq!(wire, 0x80000402u32.to_le_bytes());
+        let wire = round_trip(|| ServerHello { version: 0x0402 }, raw);
+        assert_eq!(wire, 0x0402u32.to_le_bytes());
+    }
+
+    #[test]
+    fn ff_upload() {
+        let make = || {
+            // SAFETY: ff_effect is made of integers
+            let mut effect: ff_effect = unsafe { mem::zeroed() };
+            effect.type_ = FF_RUMBLE;
+            effect.id = -1;
+            effect.replay.length = 0x0102;
+            let rumble = <&mut ff_effect_union>::from(&mut effect).rumble_mut();
+            rumble.strong_magnitude = 0x1112;
+            rumble.weak_magnitude = 0x2122;
+            FFUpload {
+                id: 0x0102030405060708,
+                request_id: 3,
+                retval: -5,
+                effect,
+            }
+        };
+        let wire = round_trip(make, Padded::to_bytes);
+        let at = mem::offset_of!(FFUpload, effect.type_);
+        assert_eq!(wire[at..at + 2], FF_RUMBLE.to_le_bytes());
+        let at = mem::offset_of!(FFUpload, effect.replay.length);
+        assert_eq!(wire[at..at + 2], 0x0102u16.to_le_bytes());
+        let at = mem::offset_of!(FFUpload, effect.u);
+        assert_eq!(wire[at..at + 2], 0x1112u16.to_le_bytes());
+        assert_eq!(wire[at + 2..at + 4], 0x2122u16.to_le_bytes());
+        let at = mem::offset_of!(FFUpload, retval);
+        assert_eq!(wire[at..at + 4], (-5i32).to_le_bytes());
+    }
+
+    #[test]
+    fn conditions_swap_both_axes() {
+        let make = || {
+            // SAFETY: ff_effect is made of integers
+            let mut effect: ff_effect = unsafe { mem::zeroed() };
+            effect.type_ = FF_SPRING;
+            let conditions = <&mut ff_effect_union>::from(&mut effect).condition_mut();
+            conditions[0].center = 0x0102;
+            conditions[1].center = 0x1112;
+            effect
+        };
+        let swapped = make().swap_bytes();
+        let conditions = <&ff_effect_union>::from(&swapped).condition();
+        assert_eq!(conditions[0].center, 0x0201);
+        assert_eq!(conditions[1].center, 0x1211);
+        round_trip(make, raw);
+    }
+
+    #[test]
+    fn small_messages() {
+        let wire = round_trip(
+            || FFErase {
+                id: 0x0102030405060708,
+                request_id: 0x11121314,
+                effect_id: 0x21222324,
+            },
+            raw,
+        );
+        assert_eq!(wire[..8], 0x0102030405060708u64.to_le_bytes());
+        assert_eq!(wire[12..], 0x21222324u32.to_le_bytes());
+        let wire = round_trip(
+            || InstanceTag {
+                tag: 0x0102030405060708,
+            },
+            raw,
+        );
+        assert_eq!(wire, 0x0102030405060708u64.to_le_bytes());
+        let wire = round_trip(
+            || Ping {
+                seq: 0x0102030405060708,
+            },
+            raw,
+        );
+        assert_eq!(wire, 0x0102030405060708u64.to_le_bytes());
+    }
+
+    #[test]
+    fn joystick_map() {
+        let make = || {
+            let mut map = JoystickMap {
+                id: 0x0102030405060708,
+                axes: 2,
+                buttons: 2,
+                axmap: [0; crate::joydev::AXMAP_LEN],
+                btnmap: [0; crate::joydev::BTNMAP_LEN],
+            };
+            map.axmap[..2].copy_from_slice(&[0, 1]);
+            map.btnmap[..2].copy_from_slice(&[0x130, 0x131]);
+            map
+        };
+        let wire = round_trip(make, Padded::to_bytes);
+        let at = mem::offset_of!(JoystickMap, axmap);
+        assert_eq!(wire[at..at + 2], [0, 1]);
+        let at = mem::offset_of!(JoystickMap, btnmap);
+        assert_eq!(wire[at..at + 2], 0x130u16.to_le_bytes());
+        assert_eq!(wire[at + 2..at + 4], 0x131u16.to_le_bytes());
+    }
+}